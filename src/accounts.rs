@@ -0,0 +1,161 @@
+use pbkdf2::{Params, Pbkdf2};
+use pbkdf2::password_hash::{PasswordHash as PhcHash, PasswordHasher, PasswordVerifier, SaltString};
+use serde::Deserialize;
+use std::fmt;
+use crate::storage::{Account, Storage, StorageError};
+
+const PASSWORD_ROUNDS: u32 = 100_000;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_NAME_LENGTH: usize = 24;
+
+// Body of `/auth/register` and `/auth/login`. The name is the player id of
+// the account, so it stays the same across sessions and restarts.
+#[derive(Deserialize)]
+pub struct Credentials {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    InvalidName(String),
+    WeakPassword,
+    NameTaken,
+    // Unknown names get the same answer as wrong passwords.
+    InvalidCredentials,
+    Storage(StorageError),
+}
+
+impl AccountError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccountError::InvalidName(_) => "invalid_name",
+            AccountError::WeakPassword => "weak_password",
+            AccountError::NameTaken => "name_taken",
+            AccountError::InvalidCredentials => "invalid_credentials",
+            AccountError::Storage(_) => "storage_failed",
+        }
+    }
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::InvalidName(name) => write!(
+                f,
+                "'{}' is not a valid name: use up to {} letters, digits and underscores, starting with a letter",
+                name, MAX_NAME_LENGTH
+            ),
+            AccountError::WeakPassword => write!(f, "Passwords need at least {} characters", MIN_PASSWORD_LENGTH),
+            AccountError::NameTaken => write!(f, "That name is already taken"),
+            AccountError::InvalidCredentials => write!(f, "Wrong name or password"),
+            AccountError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+impl From<StorageError> for AccountError {
+    fn from(e: StorageError) -> Self {
+        AccountError::Storage(e)
+    }
+}
+
+// A salted PBKDF2-HMAC-SHA256 of a password, stored as a PHC string like
+// `$pbkdf2-sha256$i=100000,l=32$<salt>$<hash>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    pub fn new(password: &str) -> Self {
+        Self::with_rounds(password, PASSWORD_ROUNDS)
+    }
+
+    fn with_rounds(password: &str, rounds: u32) -> Self {
+        let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes()).expect("16 bytes make a valid salt");
+        let params = Params { rounds, output_length: 32 };
+        let hash = Pbkdf2.hash_password_customized(password.as_bytes(), None, None, params, &salt)
+            .expect("PBKDF2 hashes passwords of any length");
+        Self(hash.to_string())
+    }
+
+    pub fn from_stored(stored: String) -> Self {
+        Self(stored)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        PhcHash::new(&self.0).is_ok_and(|hash| Pbkdf2.verify_password(password.as_bytes(), &hash).is_ok())
+    }
+}
+
+// Names cannot look like guests or NPCs, so nobody can claim their ids.
+fn validate_name(name: &str) -> Result<(), AccountError> {
+    let valid = name.len() <= MAX_NAME_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("guest_");
+    if valid { Ok(()) } else { Err(AccountError::InvalidName(name.to_string())) }
+}
+
+pub fn register(storage: &dyn Storage, credentials: &Credentials, now: u64) -> Result<Account, AccountError> {
+    validate_name(&credentials.name)?;
+    if credentials.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AccountError::WeakPassword);
+    }
+    if !storage.create_login(&credentials.name, &PasswordHash::new(&credentials.password))? {
+        return Err(AccountError::NameTaken);
+    }
+    Ok(storage.touch_account(&credentials.name, now)?)
+}
+
+pub fn login(storage: &dyn Storage, credentials: &Credentials, now: u64) -> Result<Account, AccountError> {
+    let hash = storage.load_login(&credentials.name)?.ok_or(AccountError::InvalidCredentials)?;
+    if !hash.verify(&credentials.password) {
+        return Err(AccountError::InvalidCredentials);
+    }
+    Ok(storage.touch_account(&credentials.name, now)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn credentials(name: &str, password: &str) -> Credentials {
+        Credentials { name: name.to_string(), password: password.to_string() }
+    }
+
+    #[test]
+    fn test_password_hashes() {
+        let hash = PasswordHash::with_rounds("correct horse", 10);
+        assert!(hash.as_str().starts_with("$pbkdf2-sha256$i=10,"));
+        assert!(hash.verify("correct horse"));
+        assert!(!hash.verify("correct horse "));
+        assert!(!PasswordHash::from_stored("plain".to_string()).verify("plain"));
+        // Every hash gets its own salt.
+        assert_ne!(PasswordHash::with_rounds("correct horse", 10), hash);
+    }
+
+    #[test]
+    fn test_register_and_login() {
+        let storage = MemoryStorage::new();
+        let account = register(&storage, &credentials("alice", "correct horse"), 100).unwrap();
+        assert_eq!(account.player_id, "alice");
+        assert!(matches!(register(&storage, &credentials("alice", "battery staple"), 110), Err(AccountError::NameTaken)));
+
+        assert_eq!(login(&storage, &credentials("alice", "correct horse"), 200).unwrap().last_seen, 200);
+        assert!(matches!(login(&storage, &credentials("alice", "wrong"), 200), Err(AccountError::InvalidCredentials)));
+        assert!(matches!(login(&storage, &credentials("bob", "correct horse"), 200), Err(AccountError::InvalidCredentials)));
+
+        for name in ["guest_12345678", "npc:dummy", "_alice", "", &"a".repeat(25)] {
+            let err = register(&storage, &credentials(name, "correct horse"), 100).unwrap_err();
+            assert_eq!(err.code(), "invalid_name", "{}", name);
+        }
+        assert!(matches!(register(&storage, &credentials("bob", "short"), 100), Err(AccountError::WeakPassword)));
+    }
+}
@@ -0,0 +1,256 @@
+use actix_web::{FromRequest, HttpRequest, web};
+use actix_web::dev::Payload;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::future::{Ready, ready};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// Shorter secrets make the signatures easy to brute-force.
+pub const MIN_SECRET_LENGTH: usize = 32;
+
+// How session tokens are signed. Without a configured secret the server
+// picks a random one, so sessions end when it restarts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthConfig {
+    pub secret: Option<String>,
+    pub session_ttl: Duration,
+    // Players who may manage every room and the world file.
+    pub admins: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self { secret: None, session_ttl: DEFAULT_SESSION_TTL, admins: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    MissingToken,
+    InvalidToken,
+    Expired,
+    // The token is valid but the player is not an administrator.
+    Forbidden,
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::Expired => "token_expired",
+            AuthError::Forbidden => "forbidden",
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "A session token is required"),
+            AuthError::InvalidToken => write!(f, "The session token is not valid"),
+            AuthError::Expired => write!(f, "The session token has expired"),
+            AuthError::Forbidden => write!(f, "Only administrators may do this"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    exp: u64,
+}
+
+// What `/auth/guest` and `/auth/refresh` hand out.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SessionToken {
+    pub token: String,
+    pub player_id: String,
+    // Seconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+// Issues and checks session tokens of the form `<claims>.<signature>`, both
+// base64url: the claims are JSON naming the player and the expiry, and the
+// signature is an HMAC-SHA256 of the encoded claims.
+pub struct TokenSigner {
+    key: Vec<u8>,
+    ttl: Duration,
+    admins: Vec<String>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: impl AsRef<[u8]>, ttl: Duration) -> Self {
+        Self { key: secret.as_ref().to_vec(), ttl, admins: Vec::new() }
+    }
+
+    pub fn random(ttl: Duration) -> Self {
+        let key = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()].iter().flat_map(|id| *id.as_bytes()).collect();
+        Self { key, ttl, admins: Vec::new() }
+    }
+
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let signer = match &config.secret {
+            Some(secret) => Self::new(secret, config.session_ttl),
+            None => Self::random(config.session_ttl),
+        };
+        signer.with_admins(config.admins.clone())
+    }
+
+    pub fn with_admins(mut self, admins: Vec<String>) -> Self {
+        self.admins = admins;
+        self
+    }
+
+    pub fn is_admin(&self, player_id: &str) -> bool {
+        self.admins.iter().any(|admin| admin == player_id)
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    pub fn issue(&self, player_id: &str) -> SessionToken {
+        self.issue_at(player_id, unix_now())
+    }
+
+    pub fn issue_at(&self, player_id: &str, now: u64) -> SessionToken {
+        let expires_at = now + self.ttl.as_secs();
+        let claims = Claims { sub: player_id.to_string(), exp: expires_at };
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).expect("claims serialize"));
+        let mut mac = self.mac();
+        mac.update(claims.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        SessionToken { token: format!("{}.{}", claims, signature), player_id: player_id.to_string(), expires_at }
+    }
+
+    // Answers the player the token was issued to.
+    pub fn verify(&self, token: &str) -> Result<String, AuthError> {
+        self.verify_at(token, unix_now())
+    }
+
+    pub fn verify_at(&self, token: &str, now: u64) -> Result<String, AuthError> {
+        let (claims, signature) = token.split_once('.').ok_or(AuthError::InvalidToken)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| AuthError::InvalidToken)?;
+        let mut mac = self.mac();
+        mac.update(claims.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| AuthError::InvalidToken)?;
+
+        let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| AuthError::InvalidToken)?;
+        let claims: Claims = serde_json::from_slice(&claims).map_err(|_| AuthError::InvalidToken)?;
+        if claims.exp <= now {
+            return Err(AuthError::Expired);
+        }
+        Ok(claims.sub)
+    }
+}
+
+// Seconds since the Unix epoch.
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+pub fn guest_id() -> String {
+    format!("guest_{}", &uuid::Uuid::new_v4().simple().to_string()[..8])
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// The player a request was made by, taken from `Authorization: Bearer
+// <token>`. Browsers cannot set headers on WebSocket handshakes, so
+// `?token=<token>` works as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthenticatedPlayer(pub String);
+
+fn signer(req: &HttpRequest) -> Result<&web::Data<TokenSigner>, actix_web::Error> {
+    req.app_data::<web::Data<TokenSigner>>()
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Authentication is not configured"))
+}
+
+impl AuthenticatedPlayer {
+    fn from_request(req: &HttpRequest) -> Result<Self, actix_web::Error> {
+        let signer = signer(req)?;
+        let header = req.headers()
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        let token = match header {
+            Some(token) => token,
+            None => web::Query::<TokenQuery>::from_query(req.query_string()).ok()
+                .and_then(|query| query.into_inner().token)
+                .ok_or(AuthError::MissingToken)?,
+        };
+        Ok(Self(signer.verify(token.trim())?))
+    }
+}
+
+impl FromRequest for AuthenticatedPlayer {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(AuthenticatedPlayer::from_request(req))
+    }
+}
+
+// An authenticated player who is also one of the configured administrators.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Admin(pub String);
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(AuthenticatedPlayer::from_request(req).and_then(|AuthenticatedPlayer(player_id)| {
+            if signer(req)?.is_admin(&player_id) {
+                Ok(Admin(player_id))
+            } else {
+                Err(AuthError::Forbidden.into())
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_round_trip() {
+        let signer = TokenSigner::new("a secret that is long enough to sign with", Duration::from_secs(60));
+        let session = signer.issue_at("player1", 1000);
+        assert_eq!(session.expires_at, 1060);
+        assert_eq!(signer.verify_at(&session.token, 1059), Ok("player1".to_string()));
+        assert_eq!(signer.verify_at(&session.token, 1060), Err(AuthError::Expired));
+
+        let other = TokenSigner::new("another secret that is long enough to sign", Duration::from_secs(60));
+        assert_eq!(other.verify_at(&session.token, 1000), Err(AuthError::InvalidToken));
+    }
+
+    #[test]
+    fn test_tampered_tokens_are_rejected() {
+        let signer = TokenSigner::random(Duration::from_secs(60));
+        let session = signer.issue_at("player1", 1000);
+        let (_, signature) = session.token.split_once('.').unwrap();
+
+        let forged = URL_SAFE_NO_PAD.encode(r#"{"sub":"player2","exp":9999}"#);
+        assert_eq!(signer.verify_at(&format!("{}.{}", forged, signature), 1000), Err(AuthError::InvalidToken));
+        for token in ["", "player1", "a.b", &session.token[1..]] {
+            assert_eq!(signer.verify_at(token, 1000), Err(AuthError::InvalidToken), "{}", token);
+        }
+    }
+}
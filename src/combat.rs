@@ -0,0 +1,103 @@
+use serde::Serialize;
+use std::fmt;
+use std::time::Duration;
+
+// Map objects of this kind become NPCs, e.g. training dummies placed on a
+// Tiled object layer. NPC ids carry NPC_PREFIX so they never clash with
+// player ids, which the server hands out.
+pub const NPC_KIND: &str = "npc";
+pub const NPC_PREFIX: &str = "npc:";
+
+pub fn is_npc(id: &str) -> bool {
+    id.starts_with(NPC_PREFIX)
+}
+
+// Every hit does at least one point of damage, however well defended.
+pub fn damage(attack: i32, defense: i32) -> i32 {
+    (attack - defense).max(1)
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttackOutcome {
+    pub target: String,
+    pub damage: i32,
+    // The target's health after the hit.
+    pub health: i32,
+    pub killed: bool,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ShotOutcome {
+    Hit(AttackOutcome),
+    // Something opaque at (x, y) stopped the shot.
+    Miss { x: i32, y: i32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CombatError {
+    UnknownPlayer,
+    UnknownTarget,
+    SelfTarget,
+    Dead,
+    TargetDead,
+    NotAdjacent,
+    OutOfRange { range: u32 },
+    OnCooldown { remaining: Duration },
+}
+
+impl CombatError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CombatError::UnknownPlayer => "unknown_player",
+            CombatError::UnknownTarget => "unknown_target",
+            CombatError::SelfTarget => "self_target",
+            CombatError::Dead => "dead",
+            CombatError::TargetDead => "target_dead",
+            CombatError::NotAdjacent => "not_adjacent",
+            CombatError::OutOfRange { .. } => "out_of_range",
+            CombatError::OnCooldown { .. } => "on_cooldown",
+        }
+    }
+}
+
+impl fmt::Display for CombatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CombatError::UnknownPlayer => write!(f, "Player not found"),
+            CombatError::UnknownTarget => write!(f, "Target not found"),
+            CombatError::SelfTarget => write!(f, "Characters cannot attack themselves"),
+            CombatError::Dead => write!(f, "Dead characters cannot attack"),
+            CombatError::TargetDead => write!(f, "The target is already dead"),
+            CombatError::NotAdjacent => write!(f, "The target is not next to the attacker"),
+            CombatError::OutOfRange { range } => write!(f, "The target is more than {} tiles away", range),
+            CombatError::OnCooldown { remaining } => {
+                write!(f, "Attacking again is possible in {} ms", remaining.as_millis())
+            }
+        }
+    }
+}
+
+impl std::error::Error for CombatError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_damage_is_at_least_one() {
+        assert_eq!(damage(10, 3), 7);
+        assert_eq!(damage(3, 10), 1);
+        assert!(is_npc("npc:dummy"));
+        assert!(!is_npc("guest_1234"));
+    }
+
+    #[test]
+    fn test_shot_outcomes_are_tagged() {
+        let miss = serde_json::to_value(ShotOutcome::Miss { x: 2, y: 3 }).unwrap();
+        assert_eq!(miss, serde_json::json!({"result": "miss", "x": 2, "y": 3}));
+        let outcome = AttackOutcome { target: "npc:dummy".to_string(), damage: 6, health: 94, killed: false };
+        let hit = serde_json::to_value(ShotOutcome::Hit(outcome)).unwrap();
+        assert_eq!((hit["result"].as_str(), hit["target"].as_str()), (Some("hit"), Some("npc:dummy")));
+    }
+}
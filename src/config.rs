@@ -0,0 +1,420 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::auth::{AuthConfig, MIN_SECRET_LENGTH};
+use crate::direction::MovementMode;
+use crate::dungeon::{DungeonConfig, Generator};
+use crate::game::GameRules;
+use crate::rng::Rng;
+
+// Server settings read from the environment at startup, e.g.
+// `GAME_MAP=maps/arena.txt cargo run`. Maps may be ASCII, Tiled JSON or TMX.
+// Alternatively `GAME_GENERATOR=caves GAME_SEED=42 GAME_MAP_SIZE=40x30`
+// generates a fresh map; the seed defaults to a random one.
+// `GAME_SPAWN_POLICY` is one of round_robin (default), random or farthest,
+// `GAME_COLLISION` is block (default) or bump, and `GAME_MOVE_COOLDOWN_MS`
+// limits how often a character may move. `GAME_MOVEMENT` (four_way or
+// eight_way) overrides the map's movement mode and `GAME_CORNER_CUTTING`
+// (never, one_wall or always) governs diagonal steps past walls.
+// `GAME_TICK_RATE` sets how many simulation ticks run per second and
+// `GAME_RECONNECT_GRACE_MS` how long a disconnected player's character waits
+// for them to come back. `GAME_HEARTBEAT_MS` and `GAME_CLIENT_TIMEOUT_MS`
+// control how often sockets are pinged and when silent ones are closed.
+// `GAME_AUTH_SECRET` signs session tokens (at least 32 bytes; a random one
+// is used if unset) and `GAME_SESSION_TTL_SECS` sets how long they last.
+// Accounts and characters are kept in the SQLite file `GAME_DATABASE`
+// (unset or empty to keep nothing) and saved every `GAME_SAVE_INTERVAL_MS`;
+// it needs `GAME_AUTH_SECRET`, as random keys lose every session on
+// restart. `GAME_ADMINS` lists, comma-separated, the accounts who may close
+// any room and save the world on request; it needs `GAME_DATABASE`, as
+// without accounts anybody could take an admin's name. The whole world is
+// written to `GAME_WORLD_FILE` (default `world.json`; empty to disable) on
+// shutdown and restored from it on startup. Combat uses `GAME_ATTACK` and
+// `GAME_DEFENSE`, with `GAME_ATTACK_COOLDOWN_MS` between attacks and
+// `GAME_RESPAWN_DELAY_MS` before the dead come back. Shots do
+// `GAME_RANGED_ATTACK` damage up to `GAME_RANGED_RANGE` tiles away, every
+// `GAME_RANGED_COOLDOWN_MS`.
+// `GAME_LEVELS` replaces the built-in level curve with a JSON file like
+// `data/levels.json`, and `GAME_ITEMS` the item catalog with one like
+// `data/items.json`. Characters carry up to `GAME_INVENTORY_CAPACITY` items.
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub map: MapSource,
+    pub movement: Option<MovementMode>,
+    pub rules: GameRules,
+    pub auth: AuthConfig,
+    pub database: Option<PathBuf>,
+    pub world_file: Option<PathBuf>,
+    pub levels: Option<PathBuf>,
+    pub items: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum MapSource {
+    #[default]
+    Default,
+    File(PathBuf),
+    Generated(DungeonConfig),
+}
+
+const DEFAULT_GENERATED_SIZE: (usize, usize) = (40, 30);
+const MAX_TICK_RATE: u32 = 1000;
+const DEFAULT_WORLD_FILE: &str = "world.json";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub variable: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.variable, self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ServerConfig {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let map = match (var("GAME_MAP"), var("GAME_GENERATOR")) {
+            (Some(_), Some(_)) => {
+                return Err(ConfigError {
+                    variable: "GAME_GENERATOR",
+                    message: "cannot be combined with GAME_MAP".to_string(),
+                });
+            }
+            (Some(path), None) => MapSource::File(PathBuf::from(path)),
+            (None, Some(generator)) => MapSource::Generated(dungeon_config(&var, &generator)?),
+            (None, None) => MapSource::Default,
+        };
+
+        let mut rules = GameRules::default();
+        if let Some(policy) = var("GAME_SPAWN_POLICY") {
+            rules.spawn_policy = policy.parse()
+                .map_err(|message| ConfigError { variable: "GAME_SPAWN_POLICY", message })?;
+        }
+
+        if let Some(mode) = var("GAME_COLLISION") {
+            rules.collision_mode = mode.parse()
+                .map_err(|message| ConfigError { variable: "GAME_COLLISION", message })?;
+        }
+
+        if let Some(cooldown) = var("GAME_MOVE_COOLDOWN_MS") {
+            rules.move_cooldown = parse_millis("GAME_MOVE_COOLDOWN_MS", &cooldown)?;
+        }
+
+        if let Some(grace) = var("GAME_RECONNECT_GRACE_MS") {
+            rules.reconnect_grace = parse_millis("GAME_RECONNECT_GRACE_MS", &grace)?;
+        }
+
+        if let Some(interval) = var("GAME_HEARTBEAT_MS") {
+            rules.heartbeat_interval = parse_millis("GAME_HEARTBEAT_MS", &interval)?;
+            if rules.heartbeat_interval.is_zero() {
+                return Err(ConfigError { variable: "GAME_HEARTBEAT_MS", message: "must be positive".to_string() });
+            }
+        }
+
+        if let Some(timeout) = var("GAME_CLIENT_TIMEOUT_MS") {
+            rules.client_timeout = parse_millis("GAME_CLIENT_TIMEOUT_MS", &timeout)?;
+        }
+        if rules.client_timeout <= rules.heartbeat_interval {
+            return Err(ConfigError {
+                variable: "GAME_CLIENT_TIMEOUT_MS",
+                message: "must be longer than the heartbeat interval".to_string(),
+            });
+        }
+
+        if let Some(interval) = var("GAME_SAVE_INTERVAL_MS") {
+            rules.save_interval = parse_millis("GAME_SAVE_INTERVAL_MS", &interval)?;
+        }
+
+        let stats = [
+            ("GAME_ATTACK", &mut rules.attack),
+            ("GAME_DEFENSE", &mut rules.defense),
+            ("GAME_RANGED_ATTACK", &mut rules.ranged_attack),
+        ];
+        for (variable, stat) in stats {
+            if let Some(value) = var(variable) {
+                *stat = value.parse().ok().filter(|value| *value >= 0).ok_or_else(|| ConfigError {
+                    variable,
+                    message: format!("'{}' is not a non-negative integer", value),
+                })?;
+            }
+        }
+
+        if let Some(cooldown) = var("GAME_ATTACK_COOLDOWN_MS") {
+            rules.attack_cooldown = parse_millis("GAME_ATTACK_COOLDOWN_MS", &cooldown)?;
+        }
+
+        if let Some(range) = var("GAME_RANGED_RANGE") {
+            rules.ranged_range = range.parse().map_err(|_| ConfigError {
+                variable: "GAME_RANGED_RANGE",
+                message: format!("'{}' is not a non-negative integer", range),
+            })?;
+        }
+
+        if let Some(cooldown) = var("GAME_RANGED_COOLDOWN_MS") {
+            rules.ranged_cooldown = parse_millis("GAME_RANGED_COOLDOWN_MS", &cooldown)?;
+        }
+
+        if let Some(delay) = var("GAME_RESPAWN_DELAY_MS") {
+            rules.respawn_delay = parse_millis("GAME_RESPAWN_DELAY_MS", &delay)?;
+        }
+
+        if let Some(rule) = var("GAME_CORNER_CUTTING") {
+            rules.corner_cutting = rule.parse()
+                .map_err(|message| ConfigError { variable: "GAME_CORNER_CUTTING", message })?;
+        }
+
+        if let Some(rate) = var("GAME_TICK_RATE") {
+            rules.tick_rate = rate.parse().ok()
+                .filter(|rate| (1..=MAX_TICK_RATE).contains(rate))
+                .ok_or_else(|| ConfigError {
+                    variable: "GAME_TICK_RATE",
+                    message: format!("'{}' is not a tick rate between 1 and {} Hz", rate, MAX_TICK_RATE),
+                })?;
+        }
+
+        if let Some(capacity) = var("GAME_INVENTORY_CAPACITY") {
+            rules.inventory_capacity = capacity.parse().map_err(|_| ConfigError {
+                variable: "GAME_INVENTORY_CAPACITY",
+                message: format!("'{}' is not a non-negative integer", capacity),
+            })?;
+        }
+
+        let movement = var("GAME_MOVEMENT").map(|mode| mode.parse())
+            .transpose()
+            .map_err(|message| ConfigError { variable: "GAME_MOVEMENT", message })?;
+
+        let mut auth = AuthConfig::default();
+        if let Some(secret) = var("GAME_AUTH_SECRET") {
+            if secret.len() < MIN_SECRET_LENGTH {
+                return Err(ConfigError {
+                    variable: "GAME_AUTH_SECRET",
+                    message: format!("must be at least {} bytes long", MIN_SECRET_LENGTH),
+                });
+            }
+            auth.secret = Some(secret);
+        }
+
+        if let Some(ttl) = var("GAME_SESSION_TTL_SECS") {
+            auth.session_ttl = ttl.parse().ok()
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| ConfigError {
+                    variable: "GAME_SESSION_TTL_SECS",
+                    message: format!("'{}' is not a positive number of seconds", ttl),
+                })?;
+        }
+
+        let database = var("GAME_DATABASE").filter(|path| !path.is_empty()).map(PathBuf::from);
+        if database.is_some() && auth.secret.is_none() {
+            return Err(ConfigError {
+                variable: "GAME_DATABASE",
+                message: "needs GAME_AUTH_SECRET so that stored players can sign in again".to_string(),
+            });
+        }
+
+        if let Some(admins) = var("GAME_ADMINS") {
+            auth.admins = admins.split(',').map(str::trim).filter(|admin| !admin.is_empty()).map(str::to_string).collect();
+            if !auth.admins.is_empty() && database.is_none() {
+                return Err(ConfigError {
+                    variable: "GAME_ADMINS",
+                    message: "needs GAME_DATABASE so that admin names belong to registered accounts".to_string(),
+                });
+            }
+        }
+
+        let world_file = optional_path(var("GAME_WORLD_FILE"), DEFAULT_WORLD_FILE);
+
+        let levels = var("GAME_LEVELS").filter(|path| !path.is_empty()).map(PathBuf::from);
+        let items = var("GAME_ITEMS").filter(|path| !path.is_empty()).map(PathBuf::from);
+
+        Ok(Self { map, movement, rules, auth, database, world_file, levels, items })
+    }
+}
+
+// An empty value turns the feature off.
+fn optional_path(value: Option<String>, default: &str) -> Option<PathBuf> {
+    match value {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(default)),
+    }
+}
+
+fn parse_millis(variable: &'static str, value: &str) -> Result<Duration, ConfigError> {
+    value.parse().map(Duration::from_millis).map_err(|_| ConfigError {
+        variable,
+        message: format!("'{}' is not a number of milliseconds", value),
+    })
+}
+
+fn dungeon_config(var: &impl Fn(&str) -> Option<String>, generator: &str) -> Result<DungeonConfig, ConfigError> {
+    let generator: Generator = generator.parse()
+        .map_err(|message| ConfigError { variable: "GAME_GENERATOR", message })?;
+
+    let seed = match var("GAME_SEED") {
+        Some(seed) => seed.parse().map_err(|_| ConfigError {
+            variable: "GAME_SEED",
+            message: format!("'{}' is not an unsigned integer", seed),
+        })?,
+        None => Rng::from_entropy().next_u64(),
+    };
+
+    let (width, height) = match var("GAME_MAP_SIZE") {
+        Some(size) => size.split_once('x')
+            .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+            .ok_or_else(|| ConfigError {
+                variable: "GAME_MAP_SIZE",
+                message: format!("'{}' is not in WIDTHxHEIGHT form", size),
+            })?,
+        None => DEFAULT_GENERATED_SIZE,
+    };
+
+    Ok(DungeonConfig::new(generator, width, height, seed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direction::CornerCutting;
+    use crate::game::CollisionMode;
+    use crate::spawn::SpawnPolicy;
+    use std::collections::HashMap;
+
+    fn config(vars: &[(&str, &str)]) -> Result<ServerConfig, ConfigError> {
+        let vars: HashMap<String, String> = vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        ServerConfig::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_config_map_sources() {
+        assert_eq!(config(&[]).unwrap().map, MapSource::Default);
+        assert_eq!(
+            config(&[("GAME_MAP", "maps/arena.txt")]).unwrap().map,
+            MapSource::File(PathBuf::from("maps/arena.txt"))
+        );
+        assert_eq!(
+            config(&[("GAME_GENERATOR", "bsp"), ("GAME_SEED", "7"), ("GAME_MAP_SIZE", "20x16")]).unwrap().map,
+            MapSource::Generated(DungeonConfig::new(Generator::Bsp, 20, 16, 7))
+        );
+    }
+
+    #[test]
+    fn test_config_rejects_invalid_values() {
+        assert_eq!(config(&[("GAME_GENERATOR", "maze")]).unwrap_err().variable, "GAME_GENERATOR");
+        assert_eq!(config(&[("GAME_GENERATOR", "caves"), ("GAME_SEED", "-1")]).unwrap_err().variable, "GAME_SEED");
+        assert_eq!(config(&[("GAME_GENERATOR", "caves"), ("GAME_MAP_SIZE", "40")]).unwrap_err().variable, "GAME_MAP_SIZE");
+        assert!(config(&[("GAME_MAP", "a.txt"), ("GAME_GENERATOR", "caves")]).is_err());
+        assert_eq!(config(&[("GAME_SPAWN_POLICY", "closest")]).unwrap_err().variable, "GAME_SPAWN_POLICY");
+    }
+
+    #[test]
+    fn test_config_spawn_policy() {
+        assert_eq!(config(&[]).unwrap().rules.spawn_policy, SpawnPolicy::RoundRobin);
+        assert_eq!(config(&[("GAME_SPAWN_POLICY", "farthest")]).unwrap().rules.spawn_policy, SpawnPolicy::FarthestFromEnemies);
+    }
+
+    #[test]
+    fn test_config_collision_mode() {
+        assert_eq!(config(&[]).unwrap().rules.collision_mode, CollisionMode::Block);
+        assert_eq!(config(&[("GAME_COLLISION", "bump")]).unwrap().rules.collision_mode, CollisionMode::Bump);
+        assert_eq!(config(&[("GAME_COLLISION", "pass")]).unwrap_err().variable, "GAME_COLLISION");
+    }
+
+    #[test]
+    fn test_config_movement() {
+        assert_eq!(config(&[]).unwrap().movement, None);
+        assert_eq!(config(&[("GAME_MOVEMENT", "8")]).unwrap().movement, Some(MovementMode::EightWay));
+        assert_eq!(config(&[("GAME_MOVEMENT", "hex")]).unwrap_err().variable, "GAME_MOVEMENT");
+        assert_eq!(
+            config(&[("GAME_CORNER_CUTTING", "always")]).unwrap().rules.corner_cutting,
+            CornerCutting::Always
+        );
+    }
+
+    #[test]
+    fn test_config_move_cooldown() {
+        assert_eq!(config(&[]).unwrap().rules.move_cooldown, Duration::ZERO);
+        assert_eq!(config(&[("GAME_MOVE_COOLDOWN_MS", "250")]).unwrap().rules.move_cooldown, Duration::from_millis(250));
+        assert_eq!(config(&[("GAME_MOVE_COOLDOWN_MS", "soon")]).unwrap_err().variable, "GAME_MOVE_COOLDOWN_MS");
+        assert_eq!(config(&[("GAME_RECONNECT_GRACE_MS", "0")]).unwrap().rules.reconnect_grace, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_config_tick_rate() {
+        assert_eq!(config(&[]).unwrap().rules.tick_rate, 20);
+        assert_eq!(config(&[("GAME_TICK_RATE", "60")]).unwrap().rules.tick_rate, 60);
+        assert_eq!(config(&[("GAME_TICK_RATE", "0")]).unwrap_err().variable, "GAME_TICK_RATE");
+        assert_eq!(config(&[("GAME_TICK_RATE", "fast")]).unwrap_err().variable, "GAME_TICK_RATE");
+    }
+
+    #[test]
+    fn test_config_heartbeat() {
+        let rules = config(&[("GAME_HEARTBEAT_MS", "1000"), ("GAME_CLIENT_TIMEOUT_MS", "3000")]).unwrap().rules;
+        assert_eq!(rules.heartbeat_interval, Duration::from_secs(1));
+        assert_eq!(rules.client_timeout, Duration::from_secs(3));
+        assert_eq!(config(&[("GAME_HEARTBEAT_MS", "0")]).unwrap_err().variable, "GAME_HEARTBEAT_MS");
+        assert_eq!(config(&[("GAME_CLIENT_TIMEOUT_MS", "1000")]).unwrap_err().variable, "GAME_CLIENT_TIMEOUT_MS");
+    }
+
+    #[test]
+    fn test_config_auth() {
+        assert_eq!(config(&[]).unwrap().auth, AuthConfig::default());
+        let secret = "s".repeat(MIN_SECRET_LENGTH);
+        let auth = config(&[("GAME_AUTH_SECRET", &secret), ("GAME_SESSION_TTL_SECS", "600")]).unwrap().auth;
+        assert_eq!(auth.secret, Some(secret.clone()));
+        assert_eq!(auth.session_ttl, Duration::from_secs(600));
+
+        let admins = config(&[("GAME_ADMINS", "alice, bob,"), ("GAME_DATABASE", "game.db"), ("GAME_AUTH_SECRET", &secret)])
+            .unwrap().auth.admins;
+        assert_eq!(admins, vec!["alice".to_string(), "bob".to_string()]);
+        assert_eq!(config(&[("GAME_ADMINS", "alice")]).unwrap_err().variable, "GAME_ADMINS");
+        assert!(config(&[("GAME_ADMINS", "")]).unwrap().auth.admins.is_empty());
+
+        assert_eq!(config(&[("GAME_AUTH_SECRET", "hunter2")]).unwrap_err().variable, "GAME_AUTH_SECRET");
+        assert_eq!(config(&[("GAME_SESSION_TTL_SECS", "0")]).unwrap_err().variable, "GAME_SESSION_TTL_SECS");
+    }
+
+    #[test]
+    fn test_config_database() {
+        assert_eq!(config(&[]).unwrap().database, None);
+        let secret = "s".repeat(MIN_SECRET_LENGTH);
+        let database = config(&[("GAME_DATABASE", "/var/lib/game.db"), ("GAME_AUTH_SECRET", &secret)]).unwrap().database;
+        assert_eq!(database, Some(PathBuf::from("/var/lib/game.db")));
+        assert_eq!(config(&[("GAME_DATABASE", "/var/lib/game.db")]).unwrap_err().variable, "GAME_DATABASE");
+        assert_eq!(config(&[("GAME_DATABASE", "")]).unwrap().database, None);
+        assert_eq!(config(&[("GAME_SAVE_INTERVAL_MS", "5000")]).unwrap().rules.save_interval, Duration::from_secs(5));
+        assert_eq!(config(&[("GAME_SAVE_INTERVAL_MS", "soon")]).unwrap_err().variable, "GAME_SAVE_INTERVAL_MS");
+        assert_eq!(config(&[]).unwrap().world_file, Some(PathBuf::from("world.json")));
+        assert_eq!(config(&[("GAME_WORLD_FILE", "")]).unwrap().world_file, None);
+    }
+
+    #[test]
+    fn test_config_data_files() {
+        assert_eq!(config(&[]).unwrap().levels, None);
+        assert_eq!(config(&[("GAME_LEVELS", "data/levels.json")]).unwrap().levels, Some(PathBuf::from("data/levels.json")));
+        assert_eq!(config(&[("GAME_ITEMS", "data/items.json")]).unwrap().items, Some(PathBuf::from("data/items.json")));
+        assert_eq!(config(&[("GAME_INVENTORY_CAPACITY", "4")]).unwrap().rules.inventory_capacity, 4);
+        assert_eq!(config(&[("GAME_INVENTORY_CAPACITY", "lots")]).unwrap_err().variable, "GAME_INVENTORY_CAPACITY");
+    }
+
+    #[test]
+    fn test_config_combat() {
+        let rules = config(&[("GAME_ATTACK", "15"), ("GAME_DEFENSE", "5"), ("GAME_RESPAWN_DELAY_MS", "0")]).unwrap().rules;
+        assert_eq!((rules.attack, rules.defense, rules.respawn_delay), (15, 5, Duration::ZERO));
+        assert_eq!(config(&[("GAME_DEFENSE", "-1")]).unwrap_err().variable, "GAME_DEFENSE");
+        assert_eq!(config(&[("GAME_ATTACK_COOLDOWN_MS", "fast")]).unwrap_err().variable, "GAME_ATTACK_COOLDOWN_MS");
+
+        let rules = config(&[("GAME_RANGED_ATTACK", "12"), ("GAME_RANGED_RANGE", "3"), ("GAME_RANGED_COOLDOWN_MS", "250")]).unwrap().rules;
+        assert_eq!((rules.ranged_attack, rules.ranged_range), (12, 3));
+        assert_eq!(rules.ranged_cooldown, Duration::from_millis(250));
+        assert_eq!(config(&[("GAME_RANGED_RANGE", "-2")]).unwrap_err().variable, "GAME_RANGED_RANGE");
+    }
+}
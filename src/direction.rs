@@ -0,0 +1,147 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+    UpLeft,
+    UpRight,
+    DownLeft,
+    DownRight,
+}
+
+impl Direction {
+    pub const CARDINAL: [Direction; 4] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+    pub const ALL: [Direction; 8] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+        Direction::UpLeft,
+        Direction::UpRight,
+        Direction::DownLeft,
+        Direction::DownRight,
+    ];
+
+    pub fn delta(self) -> (i32, i32) {
+        match self {
+            Direction::Up => (0, -1),
+            Direction::Down => (0, 1),
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+            Direction::UpLeft => (-1, -1),
+            Direction::UpRight => (1, -1),
+            Direction::DownLeft => (-1, 1),
+            Direction::DownRight => (1, 1),
+        }
+    }
+
+    pub fn is_diagonal(self) -> bool {
+        let (dx, dy) = self.delta();
+        dx != 0 && dy != 0
+    }
+}
+
+impl FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_ascii_lowercase()))
+            .map_err(|_| format!("unknown direction '{}'", s))
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MovementMode {
+    #[default]
+    FourWay,
+    EightWay,
+}
+
+impl MovementMode {
+    pub fn allows(self, direction: Direction) -> bool {
+        self == MovementMode::EightWay || !direction.is_diagonal()
+    }
+}
+
+impl FromStr for MovementMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "4" | "four_way" | "four-way" => Ok(MovementMode::FourWay),
+            "8" | "eight_way" | "eight-way" => Ok(MovementMode::EightWay),
+            _ => Err(format!("unknown movement mode '{}', expected four_way or eight_way", s)),
+        }
+    }
+}
+
+// Whether a diagonal step may squeeze past the walls on either side of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CornerCutting {
+    // Both orthogonal neighbours must be walkable.
+    #[default]
+    Never,
+    // At least one orthogonal neighbour must be walkable.
+    AroundOneWall,
+    Always,
+}
+
+impl CornerCutting {
+    pub fn allows(self, horizontal_open: bool, vertical_open: bool) -> bool {
+        match self {
+            CornerCutting::Never => horizontal_open && vertical_open,
+            CornerCutting::AroundOneWall => horizontal_open || vertical_open,
+            CornerCutting::Always => true,
+        }
+    }
+}
+
+impl FromStr for CornerCutting {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(CornerCutting::Never),
+            "one_wall" | "around_one_wall" => Ok(CornerCutting::AroundOneWall),
+            "always" => Ok(CornerCutting::Always),
+            _ => Err(format!("unknown corner cutting rule '{}', expected never, one_wall or always", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_direction_serde_names() {
+        assert_eq!(serde_json::to_string(&Direction::UpLeft).unwrap(), "\"up_left\"");
+        assert_eq!(serde_json::from_str::<Direction>("\"down\"").unwrap(), Direction::Down);
+        assert_eq!("Down_Right".parse::<Direction>(), Ok(Direction::DownRight));
+        assert!("diagonal".parse::<Direction>().is_err());
+    }
+
+    #[test]
+    fn test_movement_mode_allows() {
+        for direction in Direction::ALL {
+            assert!(MovementMode::EightWay.allows(direction));
+            assert_eq!(MovementMode::FourWay.allows(direction), Direction::CARDINAL.contains(&direction));
+        }
+    }
+
+    #[test]
+    fn test_corner_cutting_rules() {
+        assert!(CornerCutting::Never.allows(true, true));
+        assert!(!CornerCutting::Never.allows(true, false));
+        assert!(CornerCutting::AroundOneWall.allows(false, true));
+        assert!(!CornerCutting::AroundOneWall.allows(false, false));
+        assert!(CornerCutting::Always.allows(false, false));
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use crate::game::Map;
+use crate::rng::Rng;
+use crate::tile::Tile;
+
+// Seeded procedural map generation. Every generator carves floor out of a
+// solid block of walls; afterwards only the largest connected floor region is
+// kept so every walkable tile is reachable, and spawn points are spread
+// across it. The same config always yields an identical map.
+
+pub const MIN_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Generator {
+    RoomsAndCorridors,
+    Caves,
+    Bsp,
+}
+
+impl FromStr for Generator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rooms" | "rooms_and_corridors" => Ok(Generator::RoomsAndCorridors),
+            "caves" | "cellular" => Ok(Generator::Caves),
+            "bsp" => Ok(Generator::Bsp),
+            _ => Err(format!("unknown generator '{}', expected rooms, caves or bsp", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DungeonConfig {
+    pub generator: Generator,
+    pub width: usize,
+    pub height: usize,
+    pub seed: u64,
+    pub spawn_points: usize,
+}
+
+impl DungeonConfig {
+    pub fn new(generator: Generator, width: usize, height: usize, seed: u64) -> Self {
+        Self { generator, width, height, seed, spawn_points: 4 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerateError {
+    TooSmall { width: usize, height: usize },
+    NoFloor,
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GenerateError::TooSmall { width, height } => {
+                write!(f, "{}x{} is too small, maps must be at least {}x{}", width, height, MIN_SIZE, MIN_SIZE)
+            }
+            GenerateError::NoFloor => write!(f, "generator produced no walkable tiles"),
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Rect {
+    fn center(&self) -> (i32, i32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+
+    // Overlap check with a one tile gap so rooms never merge.
+    fn intersects(&self, other: &Rect) -> bool {
+        self.x <= other.x + other.width
+            && other.x <= self.x + self.width
+            && self.y <= other.y + other.height
+            && other.y <= self.y + self.height
+    }
+}
+
+struct Grid {
+    width: usize,
+    height: usize,
+    tiles: Vec<Vec<Tile>>,
+}
+
+impl Grid {
+    fn solid(width: usize, height: usize) -> Self {
+        Self { width, height, tiles: vec![vec![Tile::Wall; width]; height] }
+    }
+
+    fn is_interior(&self, x: i32, y: i32) -> bool {
+        x > 0 && y > 0 && x < self.width as i32 - 1 && y < self.height as i32 - 1
+    }
+
+    fn set(&mut self, x: i32, y: i32, tile: Tile) {
+        if self.is_interior(x, y) {
+            self.tiles[y as usize][x as usize] = tile;
+        }
+    }
+
+    fn is_floor(&self, x: i32, y: i32) -> bool {
+        x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height
+            && self.tiles[y as usize][x as usize].is_walkable()
+    }
+
+    fn carve_room(&mut self, room: &Rect) {
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                self.set(x, y, Tile::Floor);
+            }
+        }
+    }
+
+    fn carve_corridor(&mut self, from: (i32, i32), to: (i32, i32), horizontal_first: bool) {
+        let corner = if horizontal_first { (to.0, from.1) } else { (from.0, to.1) };
+        for (a, b) in [(from, corner), (corner, to)] {
+            for x in a.0.min(b.0)..=a.0.max(b.0) {
+                for y in a.1.min(b.1)..=a.1.max(b.1) {
+                    self.set(x, y, Tile::Floor);
+                }
+            }
+        }
+    }
+}
+
+pub fn generate(config: &DungeonConfig) -> Result<Map, GenerateError> {
+    if config.width < MIN_SIZE || config.height < MIN_SIZE {
+        return Err(GenerateError::TooSmall { width: config.width, height: config.height });
+    }
+
+    let mut rng = Rng::new(config.seed);
+    let mut grid = Grid::solid(config.width, config.height);
+    match config.generator {
+        Generator::RoomsAndCorridors => rooms_and_corridors(&mut grid, &mut rng),
+        Generator::Caves => caves(&mut grid, &mut rng),
+        Generator::Bsp => bsp(&mut grid, &mut rng),
+    }
+
+    keep_largest_region(&mut grid)?;
+    place_spawn_points(&mut grid, &mut rng, config.spawn_points);
+    Ok(Map::new(grid.width, grid.height, grid.tiles))
+}
+
+fn rooms_and_corridors(grid: &mut Grid, rng: &mut Rng) {
+    let max_room = (grid.width.min(grid.height) as i32 / 3).max(3);
+    let attempts = (grid.width * grid.height / 16).max(8);
+    let mut rooms: Vec<Rect> = Vec::new();
+
+    for _ in 0..attempts {
+        let width = rng.range(3, max_room + 1);
+        let height = rng.range(3, max_room + 1);
+        let room = Rect {
+            x: rng.range(1, (grid.width as i32 - width).max(2)),
+            y: rng.range(1, (grid.height as i32 - height).max(2)),
+            width,
+            height,
+        };
+        if rooms.iter().any(|other| other.intersects(&room)) {
+            continue;
+        }
+        grid.carve_room(&room);
+        if let Some(previous) = rooms.last() {
+            let horizontal_first = rng.chance(50);
+            grid.carve_corridor(previous.center(), room.center(), horizontal_first);
+        }
+        rooms.push(room);
+    }
+}
+
+fn caves(grid: &mut Grid, rng: &mut Rng) {
+    const WALL_PERCENT: u32 = 45;
+    const SMOOTHING_PASSES: usize = 5;
+
+    for y in 1..grid.height as i32 - 1 {
+        for x in 1..grid.width as i32 - 1 {
+            let tile = if rng.chance(WALL_PERCENT) { Tile::Wall } else { Tile::Floor };
+            grid.set(x, y, tile);
+        }
+    }
+
+    for _ in 0..SMOOTHING_PASSES {
+        let previous = grid.tiles.clone();
+        for y in 1..grid.height as i32 - 1 {
+            for x in 1..grid.width as i32 - 1 {
+                let walls = (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                    .filter(|&(dx, dy)| previous[(y + dy) as usize][(x + dx) as usize] == Tile::Wall)
+                    .count();
+                grid.set(x, y, if walls >= 5 { Tile::Wall } else { Tile::Floor });
+            }
+        }
+    }
+}
+
+fn bsp(grid: &mut Grid, rng: &mut Rng) {
+    const MIN_LEAF: i32 = 6;
+
+    fn split(grid: &mut Grid, rng: &mut Rng, area: Rect) -> (i32, i32) {
+        let can_split_x = area.width >= MIN_LEAF * 2;
+        let can_split_y = area.height >= MIN_LEAF * 2;
+        let split_x = match (can_split_x, can_split_y) {
+            (false, false) => {
+                let width = rng.range(3, (area.width - 1).max(4));
+                let height = rng.range(3, (area.height - 1).max(4));
+                let room = Rect {
+                    x: area.x + rng.range(0, (area.width - width).max(1)),
+                    y: area.y + rng.range(0, (area.height - height).max(1)),
+                    width,
+                    height,
+                };
+                grid.carve_room(&room);
+                return room.center();
+            }
+            (true, false) => true,
+            (false, true) => false,
+            (true, true) => area.width > area.height || (area.width == area.height && rng.chance(50)),
+        };
+
+        let (first, second) = if split_x {
+            let at = rng.range(MIN_LEAF, area.width - MIN_LEAF + 1);
+            (
+                Rect { width: at, ..area },
+                Rect { x: area.x + at, width: area.width - at, ..area },
+            )
+        } else {
+            let at = rng.range(MIN_LEAF, area.height - MIN_LEAF + 1);
+            (
+                Rect { height: at, ..area },
+                Rect { y: area.y + at, height: area.height - at, ..area },
+            )
+        };
+
+        let a = split(grid, rng, first);
+        let b = split(grid, rng, second);
+        grid.carve_corridor(a, b, split_x);
+        if rng.chance(50) { a } else { b }
+    }
+
+    let area = Rect { x: 1, y: 1, width: grid.width as i32 - 2, height: grid.height as i32 - 2 };
+    split(grid, rng, area);
+}
+
+// Floods every floor region and walls off all but the largest one.
+fn keep_largest_region(grid: &mut Grid) -> Result<(), GenerateError> {
+    let mut region = vec![vec![usize::MAX; grid.width]; grid.height];
+    let mut sizes: Vec<usize> = Vec::new();
+
+    for start_y in 0..grid.height as i32 {
+        for start_x in 0..grid.width as i32 {
+            if !grid.is_floor(start_x, start_y) || region[start_y as usize][start_x as usize] != usize::MAX {
+                continue;
+            }
+            let id = sizes.len();
+            let mut size = 0;
+            let mut queue = VecDeque::from([(start_x, start_y)]);
+            region[start_y as usize][start_x as usize] = id;
+            while let Some((x, y)) = queue.pop_front() {
+                size += 1;
+                for (nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+                    if grid.is_floor(nx, ny) && region[ny as usize][nx as usize] == usize::MAX {
+                        region[ny as usize][nx as usize] = id;
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+            sizes.push(size);
+        }
+    }
+
+    // Ties go to the first region found so the result stays deterministic.
+    let largest = sizes.iter().enumerate()
+        .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(&a.0)))
+        .map(|(id, _)| id)
+        .ok_or(GenerateError::NoFloor)?;
+
+    for (y, row) in grid.tiles.iter_mut().enumerate() {
+        for (x, tile) in row.iter_mut().enumerate() {
+            if tile.is_walkable() && region[y][x] != largest {
+                *tile = Tile::Wall;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Picks a random first spawn, then repeatedly the floor tile farthest from
+// all spawns chosen so far.
+fn place_spawn_points(grid: &mut Grid, rng: &mut Rng, count: usize) {
+    let floor: Vec<(i32, i32)> = (0..grid.height as i32)
+        .flat_map(|y| (0..grid.width as i32).map(move |x| (x, y)))
+        .filter(|&(x, y)| grid.is_floor(x, y))
+        .collect();
+    let mut spawns: Vec<(i32, i32)> = Vec::new();
+
+    for _ in 0..count.min(floor.len()) {
+        let next = if spawns.is_empty() {
+            floor[rng.index(floor.len())]
+        } else {
+            *floor.iter()
+                .filter(|tile| !spawns.contains(tile))
+                .max_by_key(|&&(x, y)| {
+                    spawns.iter().map(|&(sx, sy)| (sx - x).abs() + (sy - y).abs()).min().unwrap_or(0)
+                })
+                .expect("count is capped by the number of floor tiles")
+        };
+        spawns.push(next);
+    }
+
+    for (x, y) in spawns {
+        grid.tiles[y as usize][x as usize] = Tile::Spawn;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GENERATORS: [Generator; 3] = [Generator::RoomsAndCorridors, Generator::Caves, Generator::Bsp];
+
+    fn walkable_tiles(map: &Map) -> Vec<(i32, i32)> {
+        (0..map.height as i32)
+            .flat_map(|y| (0..map.width as i32).map(move |x| (x, y)))
+            .filter(|&(x, y)| map.is_walkable(x, y))
+            .collect()
+    }
+
+    fn reachable_from(map: &Map, start: (i32, i32)) -> usize {
+        let mut seen = vec![vec![false; map.width]; map.height];
+        let mut queue = VecDeque::from([start]);
+        seen[start.1 as usize][start.0 as usize] = true;
+        let mut count = 0;
+        while let Some((x, y)) = queue.pop_front() {
+            count += 1;
+            for (nx, ny) in [(x + 1, y), (x - 1, y), (x, y + 1), (x, y - 1)] {
+                if map.is_walkable(nx, ny) && !seen[ny as usize][nx as usize] {
+                    seen[ny as usize][nx as usize] = true;
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_generate_same_seed_is_byte_identical() {
+        for generator in GENERATORS {
+            let config = DungeonConfig::new(generator, 40, 30, 1234);
+            let a = serde_json::to_string(&generate(&config).unwrap()).unwrap();
+            let b = serde_json::to_string(&generate(&config).unwrap()).unwrap();
+            assert_eq!(a, b, "{:?} is not deterministic", generator);
+
+            let other = DungeonConfig { seed: 4321, ..config };
+            assert_ne!(a, serde_json::to_string(&generate(&other).unwrap()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_generate_floor_is_connected_and_enclosed() {
+        for generator in GENERATORS {
+            for seed in 0..10 {
+                let map = generate(&DungeonConfig::new(generator, 32, 24, seed)).unwrap();
+                assert_eq!((map.width, map.height), (32, 24));
+
+                let floor = walkable_tiles(&map);
+                assert!(!floor.is_empty());
+                assert_eq!(reachable_from(&map, floor[0]), floor.len(), "{:?} seed {} is disconnected", generator, seed);
+
+                for x in 0..map.width {
+                    assert_eq!(map.tiles[0][x], Tile::Wall);
+                    assert_eq!(map.tiles[map.height - 1][x], Tile::Wall);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_places_spawn_points() {
+        for generator in GENERATORS {
+            let map = generate(&DungeonConfig::new(generator, 30, 30, 99)).unwrap();
+            let spawns = map.tiles.iter().flatten().filter(|&&tile| tile == Tile::Spawn).count();
+            assert_eq!(spawns, 4);
+        }
+    }
+
+    #[test]
+    fn test_generate_rejects_tiny_maps() {
+        let config = DungeonConfig::new(Generator::Caves, 4, 20, 1);
+        assert_eq!(generate(&config).unwrap_err(), GenerateError::TooSmall { width: 4, height: 20 });
+    }
+
+    #[test]
+    fn test_generator_from_str() {
+        assert_eq!("caves".parse::<Generator>(), Ok(Generator::Caves));
+        assert_eq!("BSP".parse::<Generator>(), Ok(Generator::Bsp));
+        assert!("maze".parse::<Generator>().is_err());
+    }
+}
@@ -1,18 +1,46 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use actix::prelude::*;
-use actix_web_actors::ws;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::auth::unix_now;
+use crate::combat::{AttackOutcome, CombatError, NPC_KIND, NPC_PREFIX, ShotOutcome, damage, is_npc};
+use crate::direction::{CornerCutting, Direction, MovementMode};
+use crate::items::{
+    EquipmentSlot, GroundItems, ITEM_KIND, InventoryError, InventoryView, ItemAction, ItemCatalog, ItemEffect,
+};
+use crate::protocol::{GameEvent, ProtocolError, ServerMessage, StateChange};
+use crate::rng::Rng;
+use crate::save::{GameSave, SaveError};
+use crate::spawn::{SpawnError, SpawnPolicy, SpawnSelector};
+use crate::stats::{LevelCurve, Stats};
+use crate::storage::Storage;
+use crate::tile::Tile;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct Character {
     pub x: i32,
     pub y: i32,
     pub health: i32,
+    #[serde(default)]
+    pub stats: Stats,
+    // Item ids, in the order they were picked up.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inventory: Vec<String>,
+    // Equipped items are not in the inventory and take no room there.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub equipment: BTreeMap<EquipmentSlot, String>,
 }
 
 impl Character {
     pub fn new(x: i32, y: i32, health: i32) -> Self {
-        Self { x, y, health }
+        Self { x, y, health, stats: Stats::default(), inventory: Vec::new(), equipment: BTreeMap::new() }
+    }
+
+    pub fn with_stats(x: i32, y: i32, stats: Stats) -> Self {
+        Self { x, y, health: stats.max_health, stats, inventory: Vec::new(), equipment: BTreeMap::new() }
     }
 
     pub fn move_to(&mut self, x: i32, y: i32) {
@@ -21,92 +49,1123 @@ impl Character {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 pub struct Map {
     pub width: usize,
     pub height: usize,
-    pub tiles: Vec<Vec<String>>,
+    pub tiles: Vec<Vec<Tile>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub objects: Vec<MapObject>,
+    #[serde(default)]
+    pub movement: MovementMode,
+}
+
+// Non-tile entities placed by a level designer, e.g. from a Tiled object layer.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct MapObject {
+    pub kind: String,
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub properties: BTreeMap<String, String>,
 }
 
 impl Map {
-    pub fn new(width: usize, height: usize, tiles: Vec<Vec<String>>) -> Self {
-        Self { width, height, tiles }
+    pub fn new(width: usize, height: usize, tiles: Vec<Vec<Tile>>) -> Self {
+        Self { width, height, tiles, objects: Vec::new(), movement: MovementMode::default() }
     }
 
     pub fn is_valid_position(&self, x: i32, y: i32) -> bool {
         x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32
     }
 
+    pub fn tile(&self, x: i32, y: i32) -> Option<Tile> {
+        if self.is_valid_position(x, y) {
+            Some(self.tiles[y as usize][x as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn set_tile(&mut self, x: i32, y: i32, tile: Tile) -> bool {
+        if !self.is_valid_position(x, y) {
+            return false;
+        }
+        self.tiles[y as usize][x as usize] = tile;
+        true
+    }
+
     pub fn is_walkable(&self, x: i32, y: i32) -> bool {
-        self.is_valid_position(x, y) && self.tiles[y as usize][x as usize] != "wall"
+        self.tile(x, y).is_some_and(Tile::is_walkable)
     }
+
+    // The tiles a straight line from `from` to `to` crosses, both ends
+    // included, as drawn by Bresenham's algorithm.
+    pub fn line(from: (i32, i32), to: (i32, i32)) -> Vec<(i32, i32)> {
+        let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+        let (step_x, step_y) = ((to.0 - from.0).signum(), (to.1 - from.1).signum());
+        let (mut x, mut y) = from;
+        let mut error = dx + dy;
+        let mut tiles = vec![from];
+        while (x, y) != to {
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+            tiles.push((x, y));
+        }
+        tiles
+    }
+
+    // Whether nothing opaque stands between the two tiles. The ends do not
+    // count, so characters in doorways can still see and be seen.
+    pub fn line_of_sight(&self, from: (i32, i32), to: (i32, i32)) -> bool {
+        if !self.is_valid_position(from.0, from.1) || !self.is_valid_position(to.0, to.1) {
+            return false;
+        }
+        let line = Self::line(from, to);
+        line.iter()
+            .skip(1)
+            .take(line.len().saturating_sub(2))
+            .all(|&(x, y)| self.tile(x, y).is_some_and(|tile| !tile.is_opaque()))
+    }
+
+    pub fn spawn_points(&self) -> Vec<(i32, i32)> {
+        self.positions_where(|tile| tile == Tile::Spawn)
+    }
+
+    pub fn walkable_positions(&self) -> Vec<(i32, i32)> {
+        self.positions_where(Tile::is_walkable)
+    }
+
+    fn positions_where(&self, predicate: impl Fn(Tile) -> bool) -> Vec<(i32, i32)> {
+        self.tiles.iter().enumerate()
+            .flat_map(|(y, row)| row.iter().enumerate().map(move |(x, &tile)| (x as i32, y as i32, tile)))
+            .filter(|&(_, _, tile)| predicate(tile))
+            .map(|(x, y, _)| (x, y))
+            .collect()
+    }
+}
+
+// What happens when a character walks into a tile another character stands on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CollisionMode {
+    #[default]
+    Block,
+    Bump,
+}
+
+impl std::str::FromStr for CollisionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(CollisionMode::Block),
+            "bump" => Ok(CollisionMode::Bump),
+            _ => Err(format!("unknown collision mode '{}', expected block or bump", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GameRules {
+    pub spawn_policy: SpawnPolicy,
+    pub starting_health: i32,
+    pub collision_mode: CollisionMode,
+    pub move_cooldown: Duration,
+    pub corner_cutting: CornerCutting,
+    // Simulation ticks per second.
+    pub tick_rate: u32,
+    // How long a disconnected player's character is kept for a reconnect.
+    pub reconnect_grace: Duration,
+    // Sockets are pinged every heartbeat interval and closed when nothing
+    // arrived from them for the client timeout.
+    pub heartbeat_interval: Duration,
+    pub client_timeout: Duration,
+    // How often characters are written to storage while they play.
+    pub save_interval: Duration,
+    // Level 1 stats. A hit does the attacker's attack minus the target's
+    // defense in damage, and at least one point.
+    pub attack: i32,
+    pub defense: i32,
+    pub attack_cooldown: Duration,
+    // Shots reach `ranged_range` tiles in any direction and have their own
+    // cooldown.
+    pub ranged_attack: i32,
+    pub ranged_range: u32,
+    pub ranged_cooldown: Duration,
+    // How long dead characters wait before they come back at a spawn point.
+    pub respawn_delay: Duration,
+    // How experience turns into levels and stats.
+    pub levels: LevelCurve,
+    pub items: ItemCatalog,
+    pub inventory_capacity: usize,
+}
+
+impl Default for GameRules {
+    fn default() -> Self {
+        let base = Stats::default();
+        Self {
+            spawn_policy: SpawnPolicy::default(),
+            starting_health: base.max_health,
+            collision_mode: CollisionMode::default(),
+            move_cooldown: Duration::ZERO,
+            corner_cutting: CornerCutting::default(),
+            tick_rate: 20,
+            reconnect_grace: Duration::from_secs(30),
+            heartbeat_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(15),
+            save_interval: Duration::from_secs(30),
+            attack: base.attack,
+            defense: base.defense,
+            attack_cooldown: Duration::from_millis(500),
+            ranged_attack: 8,
+            ranged_range: 6,
+            ranged_cooldown: Duration::from_secs(1),
+            respawn_delay: Duration::from_secs(5),
+            levels: LevelCurve::default(),
+            items: ItemCatalog::default(),
+            inventory_capacity: 10,
+        }
+    }
+}
+
+impl GameRules {
+    // What new characters start out with.
+    pub fn base_stats(&self) -> Stats {
+        Stats {
+            max_health: self.starting_health,
+            attack: self.attack,
+            defense: self.defense,
+            ..Stats::default()
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum MoveOutcome {
+    Moved,
+    // The mover stayed put and interacted with the character in the way.
+    Bumped { target: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoveError {
+    UnknownPlayer,
+    UnknownDirection,
+    // The move request itself is malformed, e.g. has no direction.
+    InvalidRequest(String),
+    DirectionNotAllowed { direction: Direction },
+    OutOfBounds,
+    BlockedByWall { tile: Tile },
+    CornerBlocked,
+    BlockedByPlayer { by: String },
+    OnCooldown { remaining: Duration },
+    Dead,
+}
+
+impl MoveError {
+    // Stable machine-readable code sent to clients alongside the message.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MoveError::UnknownPlayer => "unknown_player",
+            MoveError::UnknownDirection => "unknown_direction",
+            MoveError::InvalidRequest(_) => "invalid_request",
+            MoveError::DirectionNotAllowed { .. } => "direction_not_allowed",
+            MoveError::OutOfBounds => "out_of_bounds",
+            MoveError::BlockedByWall { .. } => "blocked_by_wall",
+            MoveError::CornerBlocked => "corner_blocked",
+            MoveError::BlockedByPlayer { .. } => "blocked_by_player",
+            MoveError::OnCooldown { .. } => "on_cooldown",
+            MoveError::Dead => "dead",
+        }
+    }
+}
+
+impl fmt::Display for MoveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MoveError::UnknownPlayer => write!(f, "Player not found"),
+            MoveError::UnknownDirection => write!(f, "Unknown direction"),
+            MoveError::InvalidRequest(message) => write!(f, "Invalid request: {}", message),
+            MoveError::DirectionNotAllowed { direction } => {
+                write!(f, "Moving {:?} is not allowed on this map", direction)
+            }
+            MoveError::OutOfBounds => write!(f, "Target tile is outside the map"),
+            MoveError::BlockedByWall { tile } => write!(f, "Target tile is not walkable: {}", tile),
+            MoveError::CornerBlocked => write!(f, "Cannot cut the corner around a wall"),
+            MoveError::BlockedByPlayer { by } => write!(f, "Tile occupied by {}", by),
+            MoveError::OnCooldown { remaining } => {
+                write!(f, "Moving again is possible in {} ms", remaining.as_millis())
+            }
+            MoveError::Dead => write!(f, "Dead characters cannot move"),
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+// How many recent deltas are kept for clients that ask to resync.
+pub const DELTA_HISTORY: usize = 64;
+// How many inputs a player may have waiting for the next ticks.
+pub const MAX_QUEUED_INPUTS: usize = 8;
+
+// Something a player asked for over the socket, applied on a later tick.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayerInput {
+    Move(Direction),
+    Attack { target: String },
+    Shoot { target: String },
+    Item(ItemAction),
+}
+
+#[derive(Clone)]
+struct QueuedInput {
+    player_id: String,
+    input: PlayerInput,
+    // Where to send the ack once the input has been applied.
+    reply: Option<(Recipient<ServerMessage>, Option<u64>)>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub open: usize,
+    pub opened: u64,
+    pub closed: u64,
+    // Closed by the server because the client stopped answering heartbeats.
+    pub reaped: u64,
 }
 
 #[derive(Clone)]
 pub struct GameState {
     pub players: HashMap<String, Character>,
     pub map: Map,
-    pub clients: Vec<actix::Addr<GameWebSocket>>,
+    pub clients: Vec<Recipient<ServerMessage>>,
+    pub rules: GameRules,
+    occupancy: HashMap<(i32, i32), String>,
+    last_moved: HashMap<String, Instant>,
+    last_attacked: HashMap<String, Instant>,
+    last_shot: HashMap<String, Instant>,
+    // Dead characters and when they died, until they respawn.
+    dead: HashMap<String, Instant>,
+    // Where each NPC was placed on the map; it respawns there.
+    npc_homes: HashMap<String, (i32, i32)>,
+    // Items lying on the map, by tile.
+    ground_items: BTreeMap<(i32, i32), Vec<String>>,
+    // Open sockets per player, and when the last one of a player closed.
+    sessions: HashMap<String, usize>,
+    disconnected: HashMap<String, Instant>,
+    spawner: SpawnSelector,
+    // Bumped by every tick that changed something; snapshots and deltas carry it.
+    pub version: u64,
+    pub ticks: u64,
+    connections: ConnectionStats,
+    history: VecDeque<(u64, Vec<StateChange>)>,
+    pending: Vec<StateChange>,
+    inputs: VecDeque<QueuedInput>,
+    // Where characters are loaded from and saved to, and the room they
+    // are stored under.
+    storage: Option<(Arc<dyn Storage>, String)>,
 }
 
 impl GameState {
     pub fn new(map: Map) -> Self {
-        Self { players: HashMap::new(), map, clients: Vec::new() }
+        Self::with_rules(map, GameRules::default())
+    }
+
+    pub fn with_rules(map: Map, rules: GameRules) -> Self {
+        let spawner = SpawnSelector::new(rules.spawn_policy, Rng::from_entropy());
+        let mut game = Self {
+            players: HashMap::new(),
+            map,
+            clients: Vec::new(),
+            rules,
+            occupancy: HashMap::new(),
+            last_moved: HashMap::new(),
+            last_attacked: HashMap::new(),
+            last_shot: HashMap::new(),
+            dead: HashMap::new(),
+            npc_homes: HashMap::new(),
+            ground_items: BTreeMap::new(),
+            sessions: HashMap::new(),
+            disconnected: HashMap::new(),
+            spawner,
+            version: 0,
+            ticks: 0,
+            connections: ConnectionStats::default(),
+            history: VecDeque::new(),
+            pending: Vec::new(),
+            inputs: VecDeque::new(),
+            storage: None,
+        };
+        game.spawn_npcs();
+        game.place_items();
+        game
+    }
+
+    // Places an NPC on every free `npc` object of the map.
+    fn spawn_npcs(&mut self) {
+        let npcs: Vec<(String, i32, i32)> = self.map.objects.iter()
+            .enumerate()
+            .filter(|(_, object)| object.kind == NPC_KIND)
+            .map(|(index, object)| {
+                let name = if object.name.is_empty() { index.to_string() } else { object.name.clone() };
+                (format!("{}{}", NPC_PREFIX, name), object.x, object.y)
+            })
+            .collect();
+        for (npc_id, x, y) in npcs {
+            if self.players.contains_key(&npc_id) || self.occupancy.contains_key(&(x, y)) || !self.map.is_walkable(x, y) {
+                continue;
+            }
+            self.occupancy.insert((x, y), npc_id.clone());
+            self.npc_homes.insert(npc_id.clone(), (x, y));
+            self.players.insert(npc_id, Character::with_stats(x, y, self.rules.base_stats()));
+        }
+    }
+
+    // Puts the item of every `item` object of the map on the ground.
+    fn place_items(&mut self) {
+        for object in self.map.objects.iter().filter(|object| object.kind == ITEM_KIND) {
+            if self.rules.items.get(&object.name).is_none() {
+                log::warn!("Skipping unknown item {} at ({}, {})", object.name, object.x, object.y);
+                continue;
+            }
+            if self.map.is_walkable(object.x, object.y) {
+                self.ground_items.entry((object.x, object.y)).or_default().push(object.name.clone());
+            }
+        }
+    }
+
+    // Players in the game, not counting NPCs.
+    pub fn player_count(&self) -> usize {
+        self.players.keys().filter(|id| !is_npc(id)).count()
+    }
+
+    pub fn to_save(&self) -> GameSave {
+        GameSave {
+            map: self.map.clone(),
+            players: self.players.iter().map(|(id, character)| (id.clone(), character.clone())).collect(),
+            items: self.ground_items(),
+            ticks: self.ticks,
+            version: self.version,
+        }
+    }
+
+    // Restored players are treated as disconnected, so they get the usual
+    // grace period to come back.
+    pub fn restore(save: GameSave, rules: GameRules) -> Result<Self, SaveError> {
+        let mut game = Self::with_rules(save.map, rules);
+        let now = Instant::now();
+        // Saved NPCs replace the ones placed from the map.
+        let placed = std::mem::take(&mut game.players);
+        game.occupancy.clear();
+        for (player_id, character) in save.players {
+            if !game.map.is_valid_position(character.x, character.y) {
+                return Err(SaveError::Invalid(format!("{} is outside the map", player_id)));
+            }
+            if let Some(other) = game.occupancy.insert((character.x, character.y), player_id.clone()) {
+                return Err(SaveError::Invalid(format!("{} and {} share a tile", other, player_id)));
+            }
+            if character.health <= 0 {
+                game.dead.insert(player_id.clone(), now);
+            }
+            if !is_npc(&player_id) {
+                game.disconnected.insert(player_id.clone(), now);
+            }
+            game.players.insert(player_id, character);
+        }
+        for (npc_id, npc) in placed {
+            if !game.players.contains_key(&npc_id) && !game.occupancy.contains_key(&(npc.x, npc.y)) {
+                game.occupancy.insert((npc.x, npc.y), npc_id.clone());
+                game.players.insert(npc_id, npc);
+            }
+        }
+        // Saved items replace the ones placed from the map as well.
+        game.ground_items.clear();
+        for ground in save.items {
+            if !game.map.is_valid_position(ground.x, ground.y) {
+                return Err(SaveError::Invalid(format!("items at ({}, {}) are outside the map", ground.x, ground.y)));
+            }
+            game.ground_items.entry((ground.x, ground.y)).or_default().extend(ground.items);
+        }
+        game.ticks = save.ticks;
+        game.version = save.version;
+        Ok(game)
+    }
+
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>, room: &str) {
+        self.storage = Some((storage, room.to_string()));
+    }
+
+    pub fn has_storage(&self) -> bool {
+        self.storage.is_some()
+    }
+
+    // A saved character of the player, if storage has one. Storage errors
+    // are reported and treated like a new player so the game keeps running.
+    fn load_character(&self, player_id: &str) -> Option<Character> {
+        let (storage, room) = self.storage.as_ref()?;
+        let loaded = storage.touch_account(player_id, unix_now())
+            .and_then(|_| storage.load_character(room, player_id));
+        loaded.unwrap_or_else(|e| {
+            log::error!("Could not load {} in room {}: {}", player_id, room, e);
+            None
+        })
+    }
+
+    fn save_characters(&self, player_ids: &[&str]) {
+        let Some((storage, room)) = &self.storage else {
+            return;
+        };
+        let characters: Vec<(&str, &Character)> = player_ids.iter()
+            .filter(|player_id| !is_npc(player_id))
+            .filter_map(|player_id| self.players.get(*player_id).map(|character| (*player_id, character)))
+            .collect();
+        if characters.is_empty() {
+            return;
+        }
+        if let Err(e) = storage.save_characters(room, &characters) {
+            log::error!("Could not save {} characters in room {}: {}", characters.len(), room, e);
+        }
+    }
+
+    pub fn save_all(&self) {
+        let player_ids: Vec<&str> = self.players.keys().map(String::as_str).collect();
+        self.save_characters(&player_ids);
     }
 
-    pub fn add_player(&mut self, player_id: String) {
+    pub fn add_player(&mut self, player_id: String) -> Result<&Character, SpawnError> {
         if !self.players.contains_key(&player_id) {
-            let character = Character::new(0, 0, 100);
-            self.players.insert(player_id, character);
-            self.notify_clients();
+            let stored = self.load_character(&player_id);
+            // Returning players come back where they left unless the spot is taken.
+            let position = stored.as_ref()
+                .map(|character| (character.x, character.y))
+                .filter(|&(x, y)| self.map.is_walkable(x, y) && !self.occupancy.contains_key(&(x, y)));
+            let (x, y) = match position {
+                Some(position) => position,
+                None => {
+                    let occupied: Vec<(i32, i32)> = self.occupancy.keys().copied().collect();
+                    self.spawner.choose(&self.map, &occupied)?
+                }
+            };
+            self.occupancy.insert((x, y), player_id.clone());
+            let mut character = stored.unwrap_or_else(|| Character::with_stats(x, y, self.rules.base_stats()));
+            character.move_to(x, y);
+            if character.health <= 0 {
+                character.health = self.effective_stats(&character).max_health;
+            }
+            self.players.insert(player_id.clone(), character.clone());
+            self.record(StateChange::PlayerJoined { player_id: player_id.clone(), character });
         }
+        Ok(&self.players[&player_id])
     }
 
-    pub fn move_character(&mut self, player_id: &str, direction: &str) -> bool {
-        if let Some(character) = self.players.get_mut(player_id) {
-            let mut new_x = character.x;
-            let mut new_y = character.y;
+    pub fn remove_player(&mut self, player_id: &str) -> Option<Character> {
+        self.save_characters(&[player_id]);
+        let character = self.players.remove(player_id)?;
+        self.occupancy.remove(&(character.x, character.y));
+        self.last_moved.remove(player_id);
+        self.last_attacked.remove(player_id);
+        self.last_shot.remove(player_id);
+        self.dead.remove(player_id);
+        self.npc_homes.remove(player_id);
+        self.disconnected.remove(player_id);
+        self.inputs.retain(|queued| queued.player_id != player_id);
+        self.record(StateChange::PlayerLeft { player_id: player_id.to_string() });
+        Some(character)
+    }
 
-            match direction {
-                "up" => new_y -= 1,
-                "down" => new_y += 1,
-                "left" => new_x -= 1,
-                "right" => new_x += 1,
-                _ => return false,
+    pub fn connect_player(&mut self, player_id: &str) {
+        *self.sessions.entry(player_id.to_string()).or_default() += 1;
+        if self.disconnected.remove(player_id).is_some() {
+            self.broadcast(GameEvent::PlayerReturned { player_id: player_id.to_string() });
+        }
+    }
+
+    // Called when a player's socket closes. Once their last socket is gone the
+    // character stays reserved for the reconnect grace period.
+    pub fn disconnect_player(&mut self, player_id: &str) {
+        let Some(count) = self.sessions.get_mut(player_id) else {
+            return;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return;
+        }
+        self.sessions.remove(player_id);
+        self.inputs.retain(|queued| queued.player_id != player_id);
+        self.save_characters(&[player_id]);
+        if self.players.contains_key(player_id) {
+            self.disconnected.insert(player_id.to_string(), Instant::now());
+            self.broadcast(GameEvent::PlayerLeft { player_id: player_id.to_string() });
+        }
+    }
+
+    pub fn is_connected(&self, player_id: &str) -> bool {
+        self.sessions.contains_key(player_id)
+    }
+
+    fn remove_expired_players(&mut self, now: Instant) {
+        let mut expired: Vec<String> = self.disconnected.iter()
+            .filter(|(_, since)| now.duration_since(**since) >= self.rules.reconnect_grace)
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        expired.sort();
+        for player_id in expired {
+            self.remove_player(&player_id);
+        }
+    }
+
+    pub fn set_tile(&mut self, x: i32, y: i32, tile: Tile) -> bool {
+        if self.map.tile(x, y) == Some(tile) || !self.map.set_tile(x, y, tile) {
+            return false;
+        }
+        self.record(StateChange::TileChanged { x, y, tile });
+        true
+    }
+
+    pub fn occupant_at(&self, x: i32, y: i32) -> Option<&str> {
+        self.occupancy.get(&(x, y)).map(String::as_str)
+    }
+
+    pub fn move_character(&mut self, player_id: &str, direction: Direction) -> Result<MoveOutcome, MoveError> {
+        let character = self.players.get(player_id).ok_or(MoveError::UnknownPlayer)?;
+        if character.health <= 0 {
+            return Err(MoveError::Dead);
+        }
+        let now = Instant::now();
+        // Slow ground, like lava, takes longer to leave.
+        let cost = self.map.tile(character.x, character.y).map_or(1, Tile::movement_cost);
+        let cooldown = self.rules.move_cooldown * cost * 100 / self.effective_stats(character).speed.max(1) as u32;
+        if let Some(last_moved) = self.last_moved.get(player_id) {
+            let elapsed = now.duration_since(*last_moved);
+            if elapsed < cooldown {
+                return Err(MoveError::OnCooldown { remaining: cooldown - elapsed });
             }
+        }
+
+        if !self.map.movement.allows(direction) {
+            return Err(MoveError::DirectionNotAllowed { direction });
+        }
+
+        let (from_x, from_y) = (character.x, character.y);
+        let (dx, dy) = direction.delta();
+        let (new_x, new_y) = (from_x + dx, from_y + dy);
 
-            if self.map.is_walkable(new_x, new_y) {
-                character.move_to(new_x, new_y);
-                self.notify_clients();
-                true
-            } else {
-                false
+        let tile = self.map.tile(new_x, new_y).ok_or(MoveError::OutOfBounds)?;
+        if !tile.is_walkable() {
+            return Err(MoveError::BlockedByWall { tile });
+        }
+        if direction.is_diagonal() {
+            let horizontal_open = self.map.is_walkable(new_x, from_y);
+            let vertical_open = self.map.is_walkable(from_x, new_y);
+            if !self.rules.corner_cutting.allows(horizontal_open, vertical_open) {
+                return Err(MoveError::CornerBlocked);
             }
-        } else {
-            false
+        }
+
+        if let Some(occupant) = self.occupant_at(new_x, new_y) {
+            let occupant = occupant.to_string();
+            return match self.rules.collision_mode {
+                CollisionMode::Block => Err(MoveError::BlockedByPlayer { by: occupant }),
+                CollisionMode::Bump => Ok(MoveOutcome::Bumped { target: occupant }),
+            };
+        }
+
+        let character = self.players.get_mut(player_id).ok_or(MoveError::UnknownPlayer)?;
+        character.move_to(new_x, new_y);
+        character.health = (character.health - tile.damage_per_step()).max(0);
+        self.occupancy.remove(&(from_x, from_y));
+        self.occupancy.insert((new_x, new_y), player_id.to_string());
+        self.last_moved.insert(player_id.to_string(), now);
+        let health = character.health;
+        self.record(StateChange::PlayerMoved { player_id: player_id.to_string(), x: new_x, y: new_y, health });
+        if health == 0 {
+            self.kill(player_id, None);
+        }
+        Ok(MoveOutcome::Moved)
+    }
+
+    fn is_adjacent(&self, from: (i32, i32), to: (i32, i32)) -> bool {
+        let delta = (to.0 - from.0, to.1 - from.1);
+        Direction::ALL.iter().any(|&direction| direction.delta() == delta && self.map.movement.allows(direction))
+    }
+
+    // Hits a character next to the attacker; diagonal neighbours only count
+    // on maps that allow diagonal movement.
+    pub fn attack(&mut self, attacker_id: &str, target_id: &str) -> Result<AttackOutcome, CombatError> {
+        let (attacker, target) = self.combatants(attacker_id, target_id)?;
+        let (from, to) = ((attacker.x, attacker.y), (target.x, target.y));
+        let power = self.effective_stats(attacker).attack;
+        if !self.is_adjacent(from, to) {
+            return Err(CombatError::NotAdjacent);
+        }
+        let now = Instant::now();
+        check_cooldown(self.last_attacked.get(attacker_id), self.rules.attack_cooldown, now)?;
+        self.last_attacked.insert(attacker_id.to_string(), now);
+        Ok(self.hit(attacker_id, target_id, power))
+    }
+
+    // Shoots along the line to the target. The shot hits the first living
+    // character on its way, who need not be the target, and misses when
+    // something opaque is in the way.
+    pub fn shoot(&mut self, attacker_id: &str, target_id: &str) -> Result<ShotOutcome, CombatError> {
+        let (attacker, target) = self.combatants(attacker_id, target_id)?;
+        let (from, to) = ((attacker.x, attacker.y), (target.x, target.y));
+        // Attack gained from levels and equipment makes shots stronger as well.
+        let power = self.rules.ranged_attack + self.effective_stats(attacker).attack - self.rules.attack;
+        let distance = (to.0 - from.0).abs().max((to.1 - from.1).abs());
+        if distance as u32 > self.rules.ranged_range {
+            return Err(CombatError::OutOfRange { range: self.rules.ranged_range });
+        }
+        let now = Instant::now();
+        check_cooldown(self.last_shot.get(attacker_id), self.rules.ranged_cooldown, now)?;
+        self.last_shot.insert(attacker_id.to_string(), now);
+
+        for (x, y) in Map::line(from, to).into_iter().skip(1) {
+            let struck = self.occupant_at(x, y)
+                .filter(|occupant| !self.dead.contains_key(*occupant))
+                .map(str::to_string);
+            if let Some(struck) = struck {
+                return Ok(ShotOutcome::Hit(self.hit(attacker_id, &struck, power)));
+            }
+            if self.map.tile(x, y).is_none_or(Tile::is_opaque) {
+                self.broadcast(GameEvent::Missed { attacker: attacker_id.to_string(), x, y });
+                return Ok(ShotOutcome::Miss { x, y });
+            }
+        }
+        // The target should stand at the end of the line; if occupancy says
+        // otherwise the shot simply lands there.
+        self.broadcast(GameEvent::Missed { attacker: attacker_id.to_string(), x: to.0, y: to.1 });
+        Ok(ShotOutcome::Miss { x: to.0, y: to.1 })
+    }
+
+    // A living attacker and a living target other than the attacker.
+    fn combatants(&self, attacker_id: &str, target_id: &str) -> Result<(&Character, &Character), CombatError> {
+        let attacker = self.players.get(attacker_id).ok_or(CombatError::UnknownPlayer)?;
+        if attacker.health <= 0 {
+            return Err(CombatError::Dead);
+        }
+        if attacker_id == target_id {
+            return Err(CombatError::SelfTarget);
+        }
+        let target = self.players.get(target_id).ok_or(CombatError::UnknownTarget)?;
+        if target.health <= 0 {
+            return Err(CombatError::TargetDead);
+        }
+        Ok((attacker, target))
+    }
+
+    // Lands a hit the attacker already got to deal. Killing a character is
+    // worth experience.
+    fn hit(&mut self, attacker_id: &str, target_id: &str, power: i32) -> AttackOutcome {
+        let defense = self.effective_stats(&self.players[target_id]).defense;
+        let target = self.players.get_mut(target_id).expect("target was checked");
+        let amount = damage(power, defense);
+        target.health = (target.health - amount).max(0);
+        let level = target.stats.level;
+        let health = target.health;
+        self.record(StateChange::HealthChanged { player_id: target_id.to_string(), health });
+        self.broadcast(GameEvent::Damaged {
+            attacker: attacker_id.to_string(),
+            target: target_id.to_string(),
+            damage: amount,
+            health,
+        });
+        if health == 0 {
+            self.kill(target_id, Some(attacker_id));
+            self.grant_experience(attacker_id, self.rules.levels.kill_experience * u64::from(level));
+        }
+        AttackOutcome { target: target_id.to_string(), damage: amount, health, killed: health == 0 }
+    }
+
+    // Experience from kills, or from anything else worth a reward. Answers
+    // the character's level, or None for an unknown character.
+    pub fn grant_experience(&mut self, player_id: &str, amount: u64) -> Option<u32> {
+        let character = self.players.get_mut(player_id)?;
+        character.stats.experience += amount;
+        let max_health = character.stats.max_health;
+        let gained = self.rules.levels.level_up(&mut character.stats);
+        // New levels come with the extra health, not with a full heal.
+        if character.health > 0 {
+            character.health += character.stats.max_health - max_health;
+        }
+        let (stats, health, level) = (character.stats.clone(), character.health, character.stats.level);
+        self.record(StateChange::StatsChanged { player_id: player_id.to_string(), stats });
+        if gained > 0 {
+            self.record(StateChange::HealthChanged { player_id: player_id.to_string(), health });
+            self.broadcast(GameEvent::LevelUp { player_id: player_id.to_string(), level });
+        }
+        Some(level)
+    }
+
+    fn kill(&mut self, player_id: &str, by: Option<&str>) {
+        self.dead.insert(player_id.to_string(), Instant::now());
+        self.inputs.retain(|queued| queued.player_id != player_id);
+        self.broadcast(GameEvent::Died { player_id: player_id.to_string(), by: by.map(str::to_string) });
+    }
+
+    // Brings back characters that have been dead for the respawn delay, at
+    // a spawn point, or at their home for NPCs.
+    fn respawn_dead(&mut self, now: Instant) {
+        let mut due: Vec<String> = self.dead.iter()
+            .filter(|(_, since)| now.duration_since(**since) >= self.rules.respawn_delay)
+            .map(|(player_id, _)| player_id.clone())
+            .collect();
+        due.sort();
+        for player_id in due {
+            let Some(character) = self.players.get(&player_id) else {
+                continue;
+            };
+            let body = (character.x, character.y);
+            self.occupancy.remove(&body);
+            let home = self.npc_homes.get(&player_id).copied().filter(|home| !self.occupancy.contains_key(home));
+            let position = match home {
+                Some(home) => home,
+                None => {
+                    let occupied: Vec<(i32, i32)> = self.occupancy.keys().copied().collect();
+                    match self.spawner.choose(&self.map, &occupied) {
+                        Ok(position) => position,
+                        // Try again next tick, once a spawn point frees up.
+                        Err(_) => {
+                            self.occupancy.insert(body, player_id);
+                            continue;
+                        }
+                    }
+                }
+            };
+            self.dead.remove(&player_id);
+            self.occupancy.insert(position, player_id.clone());
+            let max_health = self.effective_stats(&self.players[&player_id]).max_health;
+            let character = self.players.get_mut(&player_id).expect("character exists");
+            character.move_to(position.0, position.1);
+            character.health = max_health;
+            let health = character.health;
+            self.record(StateChange::PlayerMoved { player_id, x: position.0, y: position.1, health });
         }
     }
 
+    pub fn items_at(&self, x: i32, y: i32) -> &[String] {
+        self.ground_items.get(&(x, y)).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn ground_items(&self) -> Vec<GroundItems> {
+        self.ground_items.iter()
+            .map(|(&(x, y), items)| GroundItems { x, y, items: items.clone() })
+            .collect()
+    }
+
+    pub fn inventory(&self, player_id: &str) -> Option<InventoryView> {
+        let character = self.players.get(player_id)?;
+        Some(InventoryView {
+            capacity: self.rules.inventory_capacity,
+            items: character.inventory.iter().map(|id| self.rules.items.describe(id)).collect(),
+            equipment: character.equipment.iter().map(|(&slot, id)| (slot, self.rules.items.describe(id))).collect(),
+            stats: self.effective_stats(character),
+        })
+    }
+
+    // The stats combat and movement go by: the character's own plus what
+    // the equipment adds.
+    pub fn effective_stats(&self, character: &Character) -> Stats {
+        self.rules.items.equipped_stats(&character.stats, &character.equipment)
+    }
+
+    pub fn apply_item_action(&mut self, player_id: &str, action: ItemAction) -> Result<(), InventoryError> {
+        match action {
+            ItemAction::PickUp(item) => self.pick_up(player_id, item.as_deref()).map(|_| ()),
+            ItemAction::Drop(item) => self.drop_item(player_id, &item),
+            ItemAction::Use(item) => self.use_item(player_id, &item),
+            ItemAction::Equip(item) => self.equip(player_id, &item),
+            ItemAction::Unequip(item) => self.unequip(player_id, &item),
+        }
+    }
+
+    fn living_character(&self, player_id: &str) -> Result<&Character, InventoryError> {
+        let character = self.players.get(player_id).ok_or(InventoryError::UnknownPlayer)?;
+        if character.health <= 0 {
+            return Err(InventoryError::Dead);
+        }
+        Ok(character)
+    }
+
+    // Takes the named item, or the one dropped last, from the character's
+    // tile and answers its id.
+    pub fn pick_up(&mut self, player_id: &str, item: Option<&str>) -> Result<String, InventoryError> {
+        let character = self.living_character(player_id)?;
+        let position = (character.x, character.y);
+        if character.inventory.len() >= self.rules.inventory_capacity {
+            return Err(InventoryError::InventoryFull { capacity: self.rules.inventory_capacity });
+        }
+        let here = self.items_at(position.0, position.1);
+        let index = match item {
+            Some(item) => here.iter().rposition(|id| id == item),
+            None => here.len().checked_sub(1),
+        }.ok_or(InventoryError::NothingHere)?;
+
+        let ground = self.ground_items.get_mut(&position).expect("items lie here");
+        let item = ground.remove(index);
+        if ground.is_empty() {
+            self.ground_items.remove(&position);
+        }
+        let character = self.players.get_mut(player_id).expect("character exists");
+        character.inventory.push(item.clone());
+        let inventory = character.inventory.clone();
+        self.record_items_at(position);
+        self.record(StateChange::InventoryChanged { player_id: player_id.to_string(), inventory });
+        Ok(item)
+    }
+
+    pub fn drop_item(&mut self, player_id: &str, item: &str) -> Result<(), InventoryError> {
+        let character = self.living_character(player_id)?;
+        let position = (character.x, character.y);
+        let index = character.inventory.iter().rposition(|id| id == item).ok_or(InventoryError::NotCarried)?;
+        let character = self.players.get_mut(player_id).expect("character exists");
+        let item = character.inventory.remove(index);
+        let inventory = character.inventory.clone();
+        self.ground_items.entry(position).or_default().push(item);
+        self.record_items_at(position);
+        self.record(StateChange::InventoryChanged { player_id: player_id.to_string(), inventory });
+        Ok(())
+    }
+
+    // Applies the item's effect and uses it up.
+    pub fn use_item(&mut self, player_id: &str, item: &str) -> Result<(), InventoryError> {
+        let character = self.living_character(player_id)?;
+        let index = character.inventory.iter().rposition(|id| id == item).ok_or(InventoryError::NotCarried)?;
+        let definition = self.rules.items.get(item).ok_or(InventoryError::UnknownItem)?;
+        let effect = definition.effect.clone().ok_or(InventoryError::NotUsable)?;
+        let max_health = self.effective_stats(character).max_health;
+        let character = self.players.get_mut(player_id).expect("character exists");
+        match effect {
+            ItemEffect::Heal(amount) => {
+                if character.health >= max_health {
+                    return Err(InventoryError::FullHealth);
+                }
+                character.health = (character.health + amount).min(max_health);
+                let health = character.health;
+                self.record(StateChange::HealthChanged { player_id: player_id.to_string(), health });
+            }
+        }
+        let character = self.players.get_mut(player_id).expect("character exists");
+        character.inventory.remove(index);
+        let inventory = character.inventory.clone();
+        self.record(StateChange::InventoryChanged { player_id: player_id.to_string(), inventory });
+        Ok(())
+    }
+
+    pub fn equip(&mut self, player_id: &str, item: &str) -> Result<(), InventoryError> {
+        let character = self.living_character(player_id)?;
+        let index = character.inventory.iter().rposition(|id| id == item).ok_or(InventoryError::NotCarried)?;
+        let definition = self.rules.items.get(item).ok_or(InventoryError::UnknownItem)?;
+        let slot = definition.slot.ok_or(InventoryError::NotEquippable)?;
+        let character = self.players.get_mut(player_id).expect("character exists");
+        let item = character.inventory.remove(index);
+        // Whatever was in the slot takes the item's place in the inventory.
+        if let Some(previous) = character.equipment.insert(slot, item) {
+            character.inventory.insert(index, previous);
+        }
+        self.record_equipment(player_id);
+        Ok(())
+    }
+
+    pub fn unequip(&mut self, player_id: &str, item: &str) -> Result<(), InventoryError> {
+        let character = self.living_character(player_id)?;
+        let slot = character.equipment.iter()
+            .find(|(_, id)| *id == item)
+            .map(|(&slot, _)| slot)
+            .ok_or(InventoryError::NotEquipped)?;
+        if character.inventory.len() >= self.rules.inventory_capacity {
+            return Err(InventoryError::InventoryFull { capacity: self.rules.inventory_capacity });
+        }
+        let character = self.players.get_mut(player_id).expect("character exists");
+        let item = character.equipment.remove(&slot).expect("the item is equipped");
+        character.inventory.push(item);
+        self.record_equipment(player_id);
+        Ok(())
+    }
+
+    // Publishes an equipment change, with the inventory it came out of or
+    // went into. Health is capped when the maximum drops.
+    fn record_equipment(&mut self, player_id: &str) {
+        let character = &self.players[player_id];
+        let max_health = self.effective_stats(character).max_health;
+        let character = self.players.get_mut(player_id).expect("character exists");
+        let (inventory, equipment) = (character.inventory.clone(), character.equipment.clone());
+        let capped = character.health > max_health;
+        if capped {
+            character.health = max_health;
+        }
+        let health = character.health;
+        self.record(StateChange::InventoryChanged { player_id: player_id.to_string(), inventory });
+        self.record(StateChange::EquipmentChanged { player_id: player_id.to_string(), equipment });
+        if capped {
+            self.record(StateChange::HealthChanged { player_id: player_id.to_string(), health });
+        }
+    }
+
+    fn record_items_at(&mut self, (x, y): (i32, i32)) {
+        let items = self.items_at(x, y).to_vec();
+        self.record(StateChange::ItemsChanged { x, y, items });
+    }
+
     pub fn get_character(&self, player_id: &str) -> Option<&Character> {
         self.players.get(player_id)
     }
 
-    pub fn add_client(&mut self, addr: actix::Addr<GameWebSocket>) {
-        self.clients.push(addr);
+    pub fn add_client(&mut self, client: Recipient<ServerMessage>) {
+        self.clients.push(client);
+        self.connections.opened += 1;
+    }
+
+    pub fn remove_client(&mut self, client: &Recipient<ServerMessage>) {
+        let before = self.clients.len();
+        self.clients.retain(|existing| existing != client);
+        self.connections.closed += (before - self.clients.len()) as u64;
+    }
+
+    pub fn record_reaped_connection(&mut self) {
+        self.connections.reaped += 1;
+    }
+
+    pub fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats { open: self.clients.len(), ..self.connections }
+    }
+
+    pub fn snapshot(&self) -> ServerMessage {
+        ServerMessage::Snapshot {
+            version: self.version,
+            players: self.players.clone(),
+            map: self.map.clone(),
+            items: self.ground_items(),
+        }
+    }
+
+    // Deltas a client at version `since` has missed, or None once they have
+    // fallen out of the history and only a snapshot will do.
+    pub fn deltas_since(&self, since: u64) -> Option<Vec<ServerMessage>> {
+        if since > self.version {
+            return None;
+        }
+        let oldest = self.history.front().map_or(self.version + 1, |(version, _)| *version);
+        if since + 1 < oldest {
+            return None;
+        }
+        Some(self.history.iter()
+            .filter(|(version, _)| *version > since)
+            .map(|(version, changes)| ServerMessage::Delta { version: *version, changes: changes.clone() })
+            .collect())
+    }
+
+    pub fn broadcast(&self, event: GameEvent) {
+        for client in &self.clients {
+            client.do_send(ServerMessage::Event(event.clone()));
+        }
+    }
+
+    pub fn queue_input(
+        &mut self,
+        player_id: &str,
+        input: PlayerInput,
+        reply: Option<(Recipient<ServerMessage>, Option<u64>)>,
+    ) -> Result<(), ProtocolError> {
+        let queued = self.inputs.iter().filter(|queued| queued.player_id == player_id).count();
+        if queued >= MAX_QUEUED_INPUTS {
+            return Err(ProtocolError::new(
+                "input_queue_full",
+                format!("At most {} inputs may be waiting at once", MAX_QUEUED_INPUTS),
+            ));
+        }
+        self.inputs.push_back(QueuedInput { player_id: player_id.to_string(), input, reply });
+        Ok(())
+    }
+
+    // Advances the simulation by one step: applies queued inputs, then sends
+    // everything that changed since the last tick as a single delta.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+        for queued in self.next_inputs() {
+            let result = match queued.input {
+                PlayerInput::Move(direction) => self.move_character(&queued.player_id, direction)
+                    .map(|_| ())
+                    .map_err(ProtocolError::from),
+                PlayerInput::Attack { target } => self.attack(&queued.player_id, &target)
+                    .map(|_| ())
+                    .map_err(ProtocolError::from),
+                PlayerInput::Shoot { target } => self.shoot(&queued.player_id, &target)
+                    .map(|_| ())
+                    .map_err(ProtocolError::from),
+                PlayerInput::Item(action) => self.apply_item_action(&queued.player_id, action)
+                    .map_err(ProtocolError::from),
+            };
+            if let Some((recipient, seq)) = queued.reply {
+                let error = result.err();
+                recipient.do_send(ServerMessage::Ack { seq, ok: error.is_none(), error });
+            }
+        }
+        let now = Instant::now();
+        self.remove_expired_players(now);
+        self.respawn_dead(now);
+        self.flush();
     }
 
-    pub fn remove_client(&mut self, addr: &actix::Addr<GameWebSocket>) {
-        self.clients.retain(|client| client != addr);
+    // At most one input per player and tick, in player id order rotated by
+    // the tick number so that nobody always gets to move first.
+    fn next_inputs(&mut self) -> Vec<QueuedInput> {
+        let mut players: Vec<String> = self.inputs.iter().map(|queued| queued.player_id.clone()).collect();
+        players.sort();
+        players.dedup();
+        if !players.is_empty() {
+            let first = (self.ticks % players.len() as u64) as usize;
+            players.rotate_left(first);
+        }
+        players.iter()
+            .filter_map(|player_id| {
+                let index = self.inputs.iter().position(|queued| queued.player_id == *player_id)?;
+                self.inputs.remove(index)
+            })
+            .collect()
     }
 
-    pub fn notify_clients(&self) {
+    // Changes are collected until the end of the tick. Every change carries
+    // absolute values, so a client may safely apply one it already saw in a
+    // snapshot.
+    fn record(&mut self, change: StateChange) {
+        self.pending.push(change);
+    }
+
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let changes = std::mem::take(&mut self.pending);
+        self.version += 1;
+        if self.history.len() == DELTA_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((self.version, changes.clone()));
+        let delta = ServerMessage::Delta { version: self.version, changes };
         for client in &self.clients {
-            let _ = client.do_send(UpdateGameState {
-                players: self.players.clone(),
-                map: self.map.clone(),
-            });
+            client.do_send(delta.clone());
+        }
+    }
+}
+
+fn check_cooldown(last: Option<&Instant>, cooldown: Duration, now: Instant) -> Result<(), CombatError> {
+    if let Some(last) = last {
+        let elapsed = now.duration_since(*last);
+        if elapsed < cooldown {
+            return Err(CombatError::OnCooldown { remaining: cooldown - elapsed });
         }
     }
+    Ok(())
 }
 
 pub fn create_default_map() -> Map {
@@ -125,16 +1184,29 @@ pub fn create_default_map() -> Map {
 
     let tiles = wall_pattern.iter().map(|row| {
         row.iter().map(|&cell| {
-            if cell == 1 { "wall".to_string() } else { "empty".to_string() }
+            if cell == 1 { Tile::Wall } else { Tile::Floor }
         }).collect()
     }).collect();
 
-    Map::new(10, 10, tiles)
+    let mut map = Map::new(10, 10, tiles);
+    let items = [
+        ("health_potion", 4, 2),
+        ("health_potion", 9, 9),
+        ("rusty_key", 0, 9),
+        ("short_sword", 2, 0),
+        ("leather_armor", 7, 4),
+    ];
+    for (item, x, y) in items {
+        map.objects.push(MapObject { kind: ITEM_KIND.to_string(), name: item.to_string(), x, y, properties: BTreeMap::new() });
+    }
+    map
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::accounts::PasswordHash;
+    use crate::storage::{Account, MemoryStorage, StorageError};
 
     #[test]
     fn test_character_new() {
@@ -156,8 +1228,8 @@ mod tests {
     #[test]
     fn test_map_new() {
         let tiles = vec![
-            vec!["empty".to_string(), "wall".to_string()],
-            vec!["wall".to_string(), "empty".to_string()],
+            vec![Tile::Floor, Tile::Wall],
+            vec![Tile::Wall, Tile::Floor],
         ];
         let map = Map::new(2, 2, tiles.clone());
         assert_eq!(map.width, 2);
@@ -168,8 +1240,8 @@ mod tests {
     #[test]
     fn test_map_is_valid_position() {
         let tiles = vec![
-            vec!["empty".to_string(), "empty".to_string()],
-            vec!["empty".to_string(), "empty".to_string()],
+            vec![Tile::Floor, Tile::Floor],
+            vec![Tile::Floor, Tile::Floor],
         ];
         let map = Map::new(2, 2, tiles);
 
@@ -184,8 +1256,8 @@ mod tests {
     #[test]
     fn test_map_is_walkable() {
         let tiles = vec![
-            vec!["empty".to_string(), "wall".to_string()],
-            vec!["wall".to_string(), "empty".to_string()],
+            vec![Tile::Floor, Tile::Wall],
+            vec![Tile::Wall, Tile::Floor],
         ];
         let map = Map::new(2, 2, tiles);
 
@@ -199,7 +1271,7 @@ mod tests {
 
     #[test]
     fn test_game_state_new() {
-        let tiles = vec![vec!["empty".to_string()]];
+        let tiles = vec![vec![Tile::Floor]];
         let map = Map::new(1, 1, tiles.clone());
         let game_state = GameState::new(map.clone());
 
@@ -212,21 +1284,21 @@ mod tests {
     #[test]
     fn test_game_state_move_character_valid() {
         let tiles = vec![
-            vec!["empty".to_string(), "empty".to_string()],
-            vec!["empty".to_string(), "empty".to_string()],
+            vec![Tile::Floor, Tile::Floor],
+            vec![Tile::Floor, Tile::Floor],
         ];
         let map = Map::new(2, 2, tiles);
         let mut game_state = GameState::new(map);
         let player_id = "player1".to_string();
-        game_state.add_player(player_id.clone());
+        game_state.add_player(player_id.clone()).unwrap();
 
-        assert!(game_state.move_character(&player_id, "right"));
+        assert_eq!(game_state.move_character(&player_id, Direction::Right), Ok(MoveOutcome::Moved));
         if let Some(char) = game_state.players.get(&player_id) {
             assert_eq!(char.x, 1);
             assert_eq!(char.y, 0);
         }
 
-        assert!(game_state.move_character(&player_id, "down"));
+        assert_eq!(game_state.move_character(&player_id, Direction::Down), Ok(MoveOutcome::Moved));
         if let Some(char) = game_state.players.get(&player_id) {
             assert_eq!(char.x, 1);
             assert_eq!(char.y, 1);
@@ -236,113 +1308,747 @@ mod tests {
     #[test]
     fn test_game_state_move_character_invalid() {
         let tiles = vec![
-            vec!["wall".to_string(), "wall".to_string()],
-            vec!["empty".to_string(), "empty".to_string()],
+            vec![Tile::Wall, Tile::Wall],
+            vec![Tile::Floor, Tile::Wall],
         ];
         let map = Map::new(2, 2, tiles);
         let mut game_state = GameState::new(map);
         let player_id = "player1".to_string();
-        game_state.add_player(player_id.clone());
+        game_state.add_player(player_id.clone()).unwrap();
+
+        // The only walkable tile is (0, 1), so that is where the player spawns
+        if let Some(char) = game_state.players.get(&player_id) {
+            assert_eq!(char.x, 0);
+            assert_eq!(char.y, 1);
+        }
 
         // Try to move into wall - should fail
-        assert!(!game_state.move_character(&player_id, "right"));
+        assert_eq!(game_state.move_character(&player_id, Direction::Right), Err(MoveError::BlockedByWall { tile: Tile::Wall }));
         if let Some(char) = game_state.players.get(&player_id) {
             assert_eq!(char.x, 0);
-            assert_eq!(char.y, 0);
+            assert_eq!(char.y, 1);
         }
 
-        // Try invalid direction - should fail
-        assert!(!game_state.move_character(&player_id, "diagonal"));
+        // Try a diagonal on a four-way map - should fail
+        assert_eq!(
+            game_state.move_character(&player_id, Direction::UpRight),
+            Err(MoveError::DirectionNotAllowed { direction: Direction::UpRight })
+        );
         if let Some(char) = game_state.players.get(&player_id) {
             assert_eq!(char.x, 0);
-            assert_eq!(char.y, 0);
+            assert_eq!(char.y, 1);
         }
 
         // Try to move out of bounds - should fail
-        assert!(!game_state.move_character(&player_id, "left"));
+        assert_eq!(game_state.move_character(&player_id, Direction::Left), Err(MoveError::OutOfBounds));
         if let Some(char) = game_state.players.get(&player_id) {
             assert_eq!(char.x, 0);
-            assert_eq!(char.y, 0);
+            assert_eq!(char.y, 1);
         }
     }
 
     #[test]
-    fn test_create_default_map() {
-        let map = create_default_map();
-        assert_eq!(map.width, 10);
-        assert_eq!(map.height, 10);
-        assert_eq!(map.tiles.len(), 10);
-        assert_eq!(map.tiles[0].len(), 10);
+    fn test_game_state_move_character_into_occupied_tile() {
+        let tiles = vec![vec![Tile::Spawn, Tile::Floor, Tile::Spawn]];
+        let mut game_state = GameState::new(Map::new(3, 1, tiles));
+        game_state.add_player("player1".to_string()).unwrap();
+        game_state.add_player("player2".to_string()).unwrap();
 
-        // Check some known wall positions
-        assert_eq!(map.tiles[0][3], "wall");
-        assert_eq!(map.tiles[1][1], "wall");
-        assert_eq!(map.tiles[0][0], "empty");
+        assert_eq!(game_state.move_character("player1", Direction::Right), Ok(MoveOutcome::Moved));
+        assert_eq!(game_state.occupant_at(1, 0), Some("player1"));
+        assert_eq!(game_state.occupant_at(0, 0), None);
+
+        assert_eq!(
+            game_state.move_character("player2", Direction::Left),
+            Err(MoveError::BlockedByPlayer { by: "player1".to_string() })
+        );
+        assert_eq!(game_state.get_character("player2").unwrap().x, 2);
+        assert_eq!(game_state.move_character("nobody", Direction::Left), Err(MoveError::UnknownPlayer));
     }
-}
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct UpdateGameState {
-    pub players: HashMap<String, Character>,
-    pub map: Map,
-}
+    #[test]
+    fn test_game_state_move_character_diagonally() {
+        let tiles = vec![
+            vec![Tile::Floor, Tile::Floor, Tile::Wall],
+            vec![Tile::Floor, Tile::Floor, Tile::Floor],
+            vec![Tile::Floor, Tile::Floor, Tile::Floor],
+        ];
+        let mut map = Map::new(3, 3, tiles);
+        map.movement = MovementMode::EightWay;
+        let mut game_state = GameState::new(map);
+        game_state.add_player("player1".to_string()).unwrap();
 
-pub struct GameWebSocket {
-    pub game_state: std::sync::Arc<std::sync::Mutex<GameState>>,
-    pub player_id: Option<String>,
-}
+        assert_eq!(game_state.move_character("player1", Direction::DownRight), Ok(MoveOutcome::Moved));
+        let character = game_state.get_character("player1").unwrap();
+        assert_eq!((character.x, character.y), (1, 1));
+
+        assert_eq!(game_state.move_character("player1", Direction::DownLeft), Ok(MoveOutcome::Moved));
+        assert_eq!(game_state.move_character("player1", Direction::Right), Ok(MoveOutcome::Moved));
+        assert_eq!(game_state.move_character("player1", Direction::Right), Ok(MoveOutcome::Moved));
+        assert_eq!(game_state.move_character("player1", Direction::UpRight), Err(MoveError::OutOfBounds));
+    }
+
+    #[test]
+    fn test_game_state_diagonal_corner_cutting() {
+        // Moving from (0, 1) to (1, 0) passes the wall at (0, 0).
+        let tiles = vec![
+            vec![Tile::Wall, Tile::Floor],
+            vec![Tile::Spawn, Tile::Floor],
+        ];
+        let mut map = Map::new(2, 2, tiles);
+        map.movement = MovementMode::EightWay;
+
+        let mut strict = GameState::new(map.clone());
+        strict.add_player("player1".to_string()).unwrap();
+        assert_eq!(strict.move_character("player1", Direction::UpRight), Err(MoveError::CornerBlocked));
 
-impl Actor for GameWebSocket {
-    type Context = ws::WebsocketContext<Self>;
+        let rules = GameRules { corner_cutting: CornerCutting::AroundOneWall, ..GameRules::default() };
+        let mut lenient = GameState::with_rules(map, rules);
+        lenient.add_player("player1".to_string()).unwrap();
+        assert_eq!(lenient.move_character("player1", Direction::UpRight), Ok(MoveOutcome::Moved));
+    }
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        if let Ok(mut game_state) = self.game_state.lock() {
-            game_state.add_client(addr);
+    #[test]
+    fn test_game_state_move_character_on_cooldown() {
+        let tiles = vec![vec![Tile::Floor, Tile::Floor, Tile::Floor]];
+        let rules = GameRules { move_cooldown: Duration::from_secs(60), ..GameRules::default() };
+        let mut game_state = GameState::with_rules(Map::new(3, 1, tiles), rules);
+        game_state.add_player("player1".to_string()).unwrap();
+
+        assert_eq!(game_state.move_character("player1", Direction::Right), Ok(MoveOutcome::Moved));
+        match game_state.move_character("player1", Direction::Right) {
+            Err(MoveError::OnCooldown { remaining }) => assert!(remaining <= Duration::from_secs(60)),
+            other => panic!("expected cooldown, got {:?}", other),
         }
+        assert_eq!(game_state.get_character("player1").unwrap().x, 1);
     }
 
-    fn stopped(&mut self, ctx: &mut Self::Context) {
-        let addr = ctx.address();
-        if let Ok(mut game_state) = self.game_state.lock() {
-            game_state.remove_client(&addr);
+    #[test]
+    fn test_game_state_dead_characters_cannot_move() {
+        let tiles = vec![vec![Tile::Floor, Tile::Floor]];
+        let mut game_state = GameState::new(Map::new(2, 1, tiles));
+        game_state.add_player("player1".to_string()).unwrap();
+        game_state.players.get_mut("player1").unwrap().health = 0;
+
+        assert_eq!(game_state.move_character("player1", Direction::Right), Err(MoveError::Dead));
+    }
+
+    #[test]
+    fn test_game_state_bump_collision_mode() {
+        let tiles = vec![vec![Tile::Spawn, Tile::Spawn]];
+        let rules = GameRules { collision_mode: CollisionMode::Bump, ..GameRules::default() };
+        let mut game_state = GameState::with_rules(Map::new(2, 1, tiles), rules);
+        game_state.add_player("player1".to_string()).unwrap();
+        game_state.add_player("player2".to_string()).unwrap();
+
+        assert_eq!(
+            game_state.move_character("player1", Direction::Right),
+            Ok(MoveOutcome::Bumped { target: "player2".to_string() })
+        );
+        assert_eq!(game_state.get_character("player1").unwrap().x, 0);
+        assert_eq!(game_state.occupant_at(1, 0), Some("player2"));
+    }
+
+    #[test]
+    fn test_game_state_add_player_uses_free_spawn_points() {
+        let tiles = vec![
+            vec![Tile::Wall, Tile::Spawn, Tile::Floor],
+            vec![Tile::Floor, Tile::Floor, Tile::Spawn],
+        ];
+        let mut game_state = GameState::new(Map::new(3, 2, tiles));
+
+        let first = game_state.add_player("player1".to_string()).unwrap();
+        assert_eq!((first.x, first.y), (1, 0));
+        let second = game_state.add_player("player2".to_string()).unwrap();
+        assert_eq!((second.x, second.y), (2, 1));
+
+        // Re-adding an existing player keeps their character
+        let again = game_state.add_player("player1".to_string()).unwrap();
+        assert_eq!((again.x, again.y), (1, 0));
+
+        assert_eq!(game_state.add_player("player3".to_string()).unwrap_err(), SpawnError::NoFreeSpawnPoint);
+        assert!(game_state.get_character("player3").is_none());
+    }
+
+    #[test]
+    fn test_game_state_with_rules_sets_starting_health() {
+        let rules = GameRules { starting_health: 40, ..GameRules::default() };
+        let mut game_state = GameState::with_rules(Map::new(1, 1, vec![vec![Tile::Floor]]), rules);
+        assert_eq!(game_state.add_player("player1".to_string()).unwrap().health, 40);
+    }
+
+    #[test]
+    fn test_map_is_walkable_uses_tile_properties() {
+        let tiles = vec![vec![Tile::Floor, Tile::Water, Tile::Door, Tile::Lava, Tile::Spawn]];
+        let map = Map::new(5, 1, tiles);
+
+        assert!(map.is_walkable(0, 0));
+        assert!(!map.is_walkable(1, 0)); // water
+        assert!(map.is_walkable(2, 0)); // door
+        assert!(map.is_walkable(3, 0)); // lava hurts but can be crossed
+        assert!(map.is_walkable(4, 0));
+        assert_eq!(map.tile(5, 0), None);
+    }
+
+    #[test]
+    fn test_game_state_move_character_onto_lava_deals_damage() {
+        let tiles = vec![vec![Tile::Floor, Tile::Lava]];
+        let mut game_state = GameState::new(Map::new(2, 1, tiles));
+        let player_id = "player1".to_string();
+        game_state.add_player(player_id.clone()).unwrap();
+
+        assert_eq!(game_state.move_character(&player_id, Direction::Right), Ok(MoveOutcome::Moved));
+        let character = game_state.get_character(&player_id).unwrap();
+        assert_eq!(character.x, 1);
+        assert_eq!(character.health, 100 - Tile::Lava.damage_per_step());
+    }
+
+    #[test]
+    fn test_slow_tiles_lengthen_the_move_cooldown() {
+        let tiles = vec![vec![Tile::Floor, Tile::Lava, Tile::Floor]];
+        let rules = GameRules { move_cooldown: Duration::from_secs(60), ..GameRules::default() };
+        let mut game_state = GameState::with_rules(Map::new(3, 1, tiles), rules);
+        game_state.add_player("player1".to_string()).unwrap();
+        game_state.move_character("player1", Direction::Right).unwrap();
+        match game_state.move_character("player1", Direction::Right) {
+            Err(MoveError::OnCooldown { remaining }) => assert!(remaining > Duration::from_secs(60)),
+            other => panic!("expected a cooldown, got {:?}", other),
         }
     }
-}
 
-impl Handler<UpdateGameState> for GameWebSocket {
-    type Result = ();
+    #[test]
+    fn test_tick_applies_one_input_per_player_in_rotating_order() {
+        // Both players want the middle tile; who gets it alternates by tick.
+        let map = Map::new(3, 1, vec![vec![Tile::Spawn, Tile::Floor, Tile::Spawn]]);
+        let mut game = GameState::new(map);
+        game.add_player("a".to_string()).unwrap();
+        game.add_player("b".to_string()).unwrap();
+
+        game.queue_input("a", PlayerInput::Move(Direction::Right), None).unwrap();
+        game.queue_input("a", PlayerInput::Move(Direction::Left), None).unwrap();
+        game.queue_input("b", PlayerInput::Move(Direction::Left), None).unwrap();
+        game.tick();
+        // Tick 1 starts with "b".
+        assert_eq!(game.occupant_at(1, 0), Some("b"));
+        assert_eq!(game.get_character("a").unwrap().x, 0);
 
-    fn handle(&mut self, msg: UpdateGameState, ctx: &mut Self::Context) {
-        let data = serde_json::to_string(&serde_json::json!({
-            "players": msg.players,
-            "map": msg.map
-        })).unwrap();
-        ctx.text(data);
+        game.tick();
+        assert_eq!(game.get_character("a").unwrap().x, 0);
+        assert_eq!(game.occupant_at(1, 0), Some("b"));
     }
-}
 
-impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GameWebSocket {
-    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match msg {
-            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
-            Ok(ws::Message::Text(text)) => {
-                if self.player_id.is_none() {
-                    // First message should contain player ID
-                    if let Ok(data) = serde_json::from_str::<serde_json::Value>(&text) {
-                        if let Some(player_id) = data.get("playerId").and_then(|v| v.as_str()) {
-                            self.player_id = Some(player_id.to_string());
-                            if let Ok(mut game_state) = self.game_state.lock() {
-                                game_state.add_player(player_id.to_string());
-                            }
-                        }
-                    }
-                }
-                println!("Received: {}", text);
-            }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
-            _ => (),
+    #[test]
+    fn test_each_tick_publishes_one_delta() {
+        let mut game = GameState::new(Map::new(3, 1, vec![vec![Tile::Floor; 3]]));
+        assert_eq!(game.version, 0);
+        game.add_player("player1".to_string()).unwrap();
+        game.add_player("player1".to_string()).unwrap();
+        game.tick();
+        assert_eq!(game.version, 1);
+        game.tick();
+        assert_eq!(game.version, 1);
+
+        game.move_character("player1", Direction::Right).unwrap();
+        assert!(game.move_character("player1", Direction::Up).is_err());
+        assert!(game.set_tile(2, 0, Tile::Lava));
+        assert!(!game.set_tile(2, 0, Tile::Lava));
+        assert!(!game.set_tile(5, 0, Tile::Wall));
+        game.tick();
+        assert_eq!(game.remove_player("player1").map(|c| c.x), Some(1));
+        assert_eq!(game.occupant_at(1, 0), None);
+        game.tick();
+        assert_eq!(game.version, 3);
+
+        let deltas = game.deltas_since(1).unwrap();
+        assert_eq!(deltas.len(), 2);
+        assert!(matches!(
+            &deltas[0],
+            ServerMessage::Delta { version: 2, changes } if changes[..] == [
+                StateChange::PlayerMoved { player_id: "player1".to_string(), x: 1, y: 0, health: 100 },
+                StateChange::TileChanged { x: 2, y: 0, tile: Tile::Lava },
+            ]
+        ));
+        assert!(matches!(
+            &deltas[1],
+            ServerMessage::Delta { version: 3, changes } if changes[..] == [
+                StateChange::PlayerLeft { player_id: "player1".to_string() },
+            ]
+        ));
+        assert!(game.deltas_since(3).unwrap().is_empty());
+        assert!(game.deltas_since(4).is_none());
+    }
+
+    #[test]
+    fn test_disconnected_players_are_removed_after_the_grace_period() {
+        let map = Map::new(3, 1, vec![vec![Tile::Floor; 3]]);
+        let mut game = GameState::with_rules(map, GameRules { reconnect_grace: Duration::ZERO, ..GameRules::default() });
+        game.add_player("player1".to_string()).unwrap();
+        game.connect_player("player1");
+        game.connect_player("player1");
+
+        // A second socket of the same player keeps them connected.
+        game.disconnect_player("player1");
+        assert!(game.is_connected("player1"));
+        game.tick();
+        assert!(game.get_character("player1").is_some());
+
+        game.queue_input("player1", PlayerInput::Move(Direction::Right), None).unwrap();
+        game.disconnect_player("player1");
+        assert!(!game.is_connected("player1"));
+        game.tick();
+        assert!(game.get_character("player1").is_none());
+        assert_eq!(game.occupant_at(0, 0), None);
+        let left = StateChange::PlayerLeft { player_id: "player1".to_string() };
+        assert!(matches!(
+            game.deltas_since(game.version - 1).unwrap()[..],
+            [ServerMessage::Delta { ref changes, .. }] if changes[..] == [left]
+        ));
+    }
+
+    #[test]
+    fn test_players_reconnecting_within_the_grace_period_keep_their_character() {
+        let mut game = GameState::new(Map::new(3, 1, vec![vec![Tile::Floor; 3]]));
+        game.add_player("player1".to_string()).unwrap();
+        game.connect_player("player1");
+        game.move_character("player1", Direction::Right).unwrap();
+
+        game.disconnect_player("player1");
+        game.tick();
+        assert_eq!(game.occupant_at(1, 0), Some("player1"));
+
+        game.connect_player("player1");
+        game.rules.reconnect_grace = Duration::ZERO;
+        game.tick();
+        assert_eq!(game.get_character("player1").map(|c| c.x), Some(1));
+    }
+
+    #[test]
+    fn test_connection_stats() {
+        let mut game = GameState::new(Map::new(1, 1, vec![vec![Tile::Floor]]));
+        game.record_reaped_connection();
+        assert_eq!(game.connection_stats(), ConnectionStats { open: 0, opened: 0, closed: 0, reaped: 1 });
+    }
+
+    #[test]
+    fn test_old_deltas_require_a_snapshot() {
+        let mut game = GameState::new(Map::new(2, 1, vec![vec![Tile::Floor; 2]]));
+        for i in 0..DELTA_HISTORY + 1 {
+            game.set_tile(1, 0, if i % 2 == 0 { Tile::Water } else { Tile::Floor });
+            game.tick();
         }
+        assert!(game.deltas_since(0).is_none());
+        assert_eq!(game.deltas_since(1).unwrap().len(), DELTA_HISTORY);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_games_survive_a_save_and_restore() {
+        let mut game = GameState::new(Map::new(3, 1, vec![vec![Tile::Floor; 3]]));
+        game.add_player("player1".to_string()).unwrap();
+        game.move_character("player1", Direction::Right).unwrap();
+        game.set_tile(2, 0, Tile::Water);
+        game.tick();
+
+        let save = serde_json::to_string(&game.to_save()).unwrap();
+        let restored = GameState::restore(serde_json::from_str(&save).unwrap(), GameRules::default()).unwrap();
+        assert_eq!(restored.players, game.players);
+        assert_eq!(restored.map.tile(2, 0), Some(Tile::Water));
+        assert_eq!((restored.ticks, restored.version), (1, 1));
+        assert_eq!(restored.occupant_at(1, 0), Some("player1"));
+        assert!(!restored.is_connected("player1"));
+
+        let mut clash = game.to_save();
+        clash.players.insert("player2".to_string(), Character::new(1, 0, 100));
+        assert!(matches!(GameState::restore(clash, GameRules::default()), Err(SaveError::Invalid(_))));
+    }
+
+    fn arena() -> GameState {
+        let mut map = Map::new(4, 1, vec![vec![Tile::Floor, Tile::Spawn, Tile::Floor, Tile::Floor]]);
+        map.objects.push(MapObject {
+            kind: NPC_KIND.to_string(),
+            name: "dummy".to_string(),
+            x: 2,
+            y: 0,
+            properties: BTreeMap::new(),
+        });
+        let rules = GameRules { attack_cooldown: Duration::ZERO, respawn_delay: Duration::ZERO, ..GameRules::default() };
+        GameState::with_rules(map, rules)
+    }
+
+    #[test]
+    fn test_melee_attacks() {
+        let mut game = arena();
+        assert_eq!(game.get_character("npc:dummy"), Some(&Character::new(2, 0, 100)));
+        assert_eq!(game.player_count(), 0);
+        game.add_player("player1".to_string()).unwrap();
+        assert_eq!(game.player_count(), 1);
+
+        let outcome = game.attack("player1", "npc:dummy").unwrap();
+        assert_eq!(outcome, AttackOutcome { target: "npc:dummy".to_string(), damage: 8, health: 92, killed: false });
+        assert_eq!(game.attack("player1", "player1"), Err(CombatError::SelfTarget));
+        assert_eq!(game.attack("player1", "ghost"), Err(CombatError::UnknownTarget));
+        assert_eq!(game.attack("ghost", "npc:dummy"), Err(CombatError::UnknownPlayer));
+
+        game.move_character("player1", Direction::Left).unwrap();
+        assert_eq!(game.attack("player1", "npc:dummy"), Err(CombatError::NotAdjacent));
+
+        game.move_character("player1", Direction::Right).unwrap();
+        game.attack("player1", "npc:dummy").unwrap();
+        game.rules.attack_cooldown = Duration::from_secs(60);
+        assert!(matches!(game.attack("player1", "npc:dummy"), Err(CombatError::OnCooldown { .. })));
+    }
+
+    #[test]
+    fn test_killed_characters_respawn() {
+        let mut game = arena();
+        game.add_player("player1".to_string()).unwrap();
+        game.players.get_mut("npc:dummy").unwrap().health = 5;
+
+        let outcome = game.attack("player1", "npc:dummy").unwrap();
+        assert!(outcome.killed);
+        assert_eq!(game.attack("player1", "npc:dummy"), Err(CombatError::TargetDead));
+        game.players.get_mut("player1").unwrap().health = 0;
+        game.kill("player1", None);
+        assert_eq!(game.attack("player1", "npc:dummy"), Err(CombatError::Dead));
+
+        game.tick();
+        assert_eq!(game.get_character("npc:dummy"), Some(&Character::new(2, 0, 100)));
+        assert_eq!(game.get_character("player1").unwrap().health, 100);
+        let changes = match game.deltas_since(0).unwrap().pop() {
+            Some(ServerMessage::Delta { changes, .. }) => changes,
+            other => panic!("expected a delta, got {:?}", other.map(|_| ())),
+        };
+        assert!(changes.contains(&StateChange::HealthChanged { player_id: "npc:dummy".to_string(), health: 0 }));
+    }
+
+    #[test]
+    fn test_kills_are_worth_experience() {
+        let mut game = arena();
+        game.add_player("player1".to_string()).unwrap();
+        game.players.get_mut("npc:dummy").unwrap().health = 5;
+        game.attack("player1", "npc:dummy").unwrap();
+        assert_eq!(game.get_character("player1").unwrap().stats.experience, 20);
+
+        assert_eq!(game.grant_experience("player1", 30), Some(2));
+        assert_eq!(game.grant_experience("ghost", 30), None);
+        let character = game.get_character("player1").unwrap();
+        assert_eq!((character.health, character.stats.max_health, character.stats.attack), (110, 110, 12));
+
+        game.tick();
+        assert_eq!(game.attack("player1", "npc:dummy").unwrap().damage, 10);
+        let changes = match game.deltas_since(0).unwrap().pop() {
+            Some(ServerMessage::Delta { changes, .. }) => changes,
+            other => panic!("expected a delta, got {:?}", other.map(|_| ())),
+        };
+        let stats = game.get_character("player1").unwrap().stats.clone();
+        assert!(changes.contains(&StateChange::StatsChanged { player_id: "player1".to_string(), stats }));
+    }
+
+    #[test]
+    fn test_speed_shortens_the_move_cooldown() {
+        let mut game = arena();
+        game.rules.move_cooldown = Duration::from_secs(60);
+        game.add_player("player1".to_string()).unwrap();
+        game.players.get_mut("player1").unwrap().stats.speed = 200;
+        game.move_character("player1", Direction::Left).unwrap();
+        match game.move_character("player1", Direction::Right) {
+            Err(MoveError::OnCooldown { remaining }) => assert!(remaining <= Duration::from_secs(30)),
+            other => panic!("expected a cooldown, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_attacks_are_queued_for_the_tick() {
+        let mut game = arena();
+        game.add_player("player1".to_string()).unwrap();
+        let attack = PlayerInput::Attack { target: "npc:dummy".to_string() };
+        game.queue_input("player1", attack, None).unwrap();
+        assert_eq!(game.get_character("npc:dummy").unwrap().health, 100);
+        game.tick();
+        assert_eq!(game.get_character("npc:dummy").unwrap().health, 92);
+    }
+
+    fn range() -> GameState {
+        let mut tiles = vec![vec![Tile::Floor; 8]; 2];
+        tiles[0][2] = Tile::Wall;
+        tiles[1][0] = Tile::Spawn;
+        let mut map = Map::new(8, 2, tiles);
+        for (name, x, y) in [("hidden", 4, 0), ("front", 2, 1), ("back", 5, 1), ("far", 7, 1)] {
+            map.objects.push(MapObject { kind: NPC_KIND.to_string(), name: name.to_string(), x, y, properties: BTreeMap::new() });
+        }
+        let rules = GameRules { ranged_cooldown: Duration::ZERO, ..GameRules::default() };
+        GameState::with_rules(map, rules)
+    }
+
+    #[test]
+    fn test_map_line_of_sight() {
+        assert_eq!(Map::line((0, 0), (3, 0)), vec![(0, 0), (1, 0), (2, 0), (3, 0)]);
+        assert_eq!(Map::line((0, 1), (4, 0)), vec![(0, 1), (1, 1), (2, 0), (3, 0), (4, 0)]);
+        assert_eq!(Map::line((2, 2), (2, 2)), vec![(2, 2)]);
+
+        let mut map = range().map;
+        assert!(map.line_of_sight((0, 1), (7, 1)));
+        assert!(!map.line_of_sight((0, 0), (4, 0)));
+        assert!(!map.line_of_sight((0, 1), (4, 0)));
+        assert!(map.line_of_sight((0, 0), (2, 0)));
+        assert!(!map.line_of_sight((0, 0), (8, 0)));
+
+        // Doors block the view through them, not into them.
+        map.set_tile(2, 0, Tile::Door);
+        assert!(map.line_of_sight((0, 0), (2, 0)));
+        assert!(!map.line_of_sight((0, 0), (3, 0)));
+    }
+
+    #[test]
+    fn test_ranged_attacks() {
+        let mut game = range();
+        game.add_player("player1".to_string()).unwrap();
+        assert_eq!(game.get_character("player1"), Some(&Character::new(0, 1, 100)));
+
+        assert_eq!(game.shoot("player1", "hidden"), Err(CombatError::UnknownTarget));
+        assert_eq!(game.shoot("player1", "npc:hidden"), Ok(ShotOutcome::Miss { x: 2, y: 0 }));
+        assert_eq!(game.shoot("player1", "npc:far"), Err(CombatError::OutOfRange { range: 6 }));
+
+        // The character in front takes the shot meant for the one behind.
+        let outcome = game.shoot("player1", "npc:back").unwrap();
+        let expected = AttackOutcome { target: "npc:front".to_string(), damage: 6, health: 94, killed: false };
+        assert_eq!(outcome, ShotOutcome::Hit(expected));
+
+        game.shoot("player1", "npc:front").unwrap();
+        game.rules.ranged_cooldown = Duration::from_secs(60);
+        assert!(matches!(game.shoot("player1", "npc:front"), Err(CombatError::OnCooldown { .. })));
+        // Melee has a cooldown of its own.
+        game.move_character("player1", Direction::Right).unwrap();
+        assert!(game.attack("player1", "npc:front").is_ok());
+    }
+
+    #[test]
+    fn test_shots_pass_over_the_dead() {
+        let mut game = range();
+        game.add_player("player1".to_string()).unwrap();
+        game.players.get_mut("npc:front").unwrap().health = 0;
+        game.kill("npc:front", None);
+        let outcome = game.shoot("player1", "npc:back").unwrap();
+        assert!(matches!(outcome, ShotOutcome::Hit(AttackOutcome { ref target, .. }) if target == "npc:back"));
+
+        game.queue_input("player1", PlayerInput::Shoot { target: "npc:back".to_string() }, None).unwrap();
+        game.tick();
+        assert_eq!(game.get_character("npc:back").unwrap().health, 88);
+    }
+
+    #[test]
+    fn test_shots_at_stale_positions_miss() {
+        let mut game = range();
+        game.add_player("player1".to_string()).unwrap();
+        let back = game.get_character("npc:back").unwrap();
+        let (x, y) = (back.x, back.y);
+        game.occupancy.remove(&(x, y));
+        game.players.get_mut("npc:front").unwrap().health = 0;
+        game.kill("npc:front", None);
+        assert_eq!(game.shoot("player1", "npc:back"), Ok(ShotOutcome::Miss { x, y }));
+    }
+
+    fn storeroom() -> GameState {
+        let mut map = Map::new(3, 1, vec![vec![Tile::Spawn, Tile::Floor, Tile::Floor]]);
+        for (name, x) in [("health_potion", 0), ("rusty_key", 0), ("cursed_idol", 1)] {
+            map.objects.push(MapObject { kind: ITEM_KIND.to_string(), name: name.to_string(), x, y: 0, properties: BTreeMap::new() });
+        }
+        GameState::new(map)
+    }
+
+    #[test]
+    fn test_items_are_picked_up_used_and_dropped() {
+        let mut game = storeroom();
+        game.add_player("player1".to_string()).unwrap();
+        assert_eq!(game.items_at(0, 0), ["health_potion".to_string(), "rusty_key".to_string()]);
+        assert!(game.items_at(1, 0).is_empty());
+
+        assert_eq!(game.pick_up("player1", None), Ok("rusty_key".to_string()));
+        assert_eq!(game.pick_up("player1", Some("rusty_key")), Err(InventoryError::NothingHere));
+        assert_eq!(game.pick_up("player1", Some("health_potion")), Ok("health_potion".to_string()));
+        assert_eq!(game.pick_up("player1", None), Err(InventoryError::NothingHere));
+        assert!(game.ground_items().is_empty());
+
+        assert_eq!(game.use_item("player1", "rusty_key"), Err(InventoryError::NotUsable));
+        assert_eq!(game.use_item("player1", "health_potion"), Err(InventoryError::FullHealth));
+        game.players.get_mut("player1").unwrap().health = 50;
+        game.use_item("player1", "health_potion").unwrap();
+        assert_eq!(game.get_character("player1").unwrap().health, 80);
+        assert_eq!(game.use_item("player1", "health_potion"), Err(InventoryError::NotCarried));
+
+        game.move_character("player1", Direction::Right).unwrap();
+        game.drop_item("player1", "rusty_key").unwrap();
+        assert_eq!(game.items_at(1, 0), ["rusty_key".to_string()]);
+        assert!(game.inventory("player1").unwrap().items.is_empty());
+        assert_eq!(game.drop_item("player1", "rusty_key"), Err(InventoryError::NotCarried));
+    }
+
+    #[test]
+    fn test_inventories_are_limited() {
+        let mut game = storeroom();
+        game.rules.inventory_capacity = 1;
+        game.add_player("player1".to_string()).unwrap();
+        game.pick_up("player1", None).unwrap();
+        assert_eq!(game.pick_up("player1", None), Err(InventoryError::InventoryFull { capacity: 1 }));
+        let inventory = game.inventory("player1").unwrap();
+        assert_eq!((inventory.capacity, inventory.items[0].name.as_str()), (1, "Rusty key"));
+
+        game.players.get_mut("player1").unwrap().health = 0;
+        game.kill("player1", None);
+        assert_eq!(game.drop_item("player1", "rusty_key"), Err(InventoryError::Dead));
+    }
+
+    #[test]
+    fn test_item_actions_are_queued_and_saved() {
+        let mut game = storeroom();
+        game.add_player("player1".to_string()).unwrap();
+        game.queue_input("player1", PlayerInput::Item(ItemAction::PickUp(None)), None).unwrap();
+        game.tick();
+        assert_eq!(game.get_character("player1").unwrap().inventory, vec!["rusty_key".to_string()]);
+        match game.snapshot() {
+            ServerMessage::Snapshot { items, .. } => assert_eq!(items, game.ground_items()),
+            _ => panic!("expected a snapshot"),
+        }
+
+        let restored = GameState::restore(game.to_save(), GameRules::default()).unwrap();
+        assert_eq!(restored.items_at(0, 0), ["health_potion".to_string()]);
+        assert_eq!(restored.get_character("player1").unwrap().inventory, vec!["rusty_key".to_string()]);
+    }
+
+    #[test]
+    fn test_equipment_changes_combat_stats() {
+        let mut game = arena();
+        game.add_player("player1".to_string()).unwrap();
+        let character = game.players.get_mut("player1").unwrap();
+        character.inventory = vec!["short_sword".to_string(), "lucky_charm".to_string(), "rusty_key".to_string()];
+
+        assert_eq!(game.equip("player1", "rusty_key"), Err(InventoryError::NotEquippable));
+        assert_eq!(game.equip("player1", "leather_armor"), Err(InventoryError::NotCarried));
+        game.equip("player1", "short_sword").unwrap();
+        game.equip("player1", "lucky_charm").unwrap();
+        let character = game.get_character("player1").unwrap();
+        assert_eq!(character.inventory, vec!["rusty_key".to_string()]);
+        assert_eq!(character.equipment[&EquipmentSlot::Weapon], "short_sword");
+        let stats = game.effective_stats(character);
+        assert_eq!((stats.attack, stats.max_health), (14, 120));
+        assert_eq!(game.attack("player1", "npc:dummy").unwrap().damage, 12);
+
+        // Taking the charm off caps health at the lower maximum.
+        game.players.get_mut("player1").unwrap().health = 115;
+        game.unequip("player1", "lucky_charm").unwrap();
+        assert_eq!(game.get_character("player1").unwrap().health, 100);
+        assert_eq!(game.unequip("player1", "lucky_charm"), Err(InventoryError::NotEquipped));
+
+        game.rules.inventory_capacity = 2;
+        assert_eq!(game.unequip("player1", "short_sword"), Err(InventoryError::InventoryFull { capacity: 2 }));
+        game.tick();
+        let changes = match game.deltas_since(0).unwrap().pop() {
+            Some(ServerMessage::Delta { changes, .. }) => changes,
+            other => panic!("expected a delta, got {:?}", other.map(|_| ())),
+        };
+        let equipment = game.get_character("player1").unwrap().equipment.clone();
+        assert!(changes.contains(&StateChange::EquipmentChanged { player_id: "player1".to_string(), equipment }));
+    }
+
+    #[test]
+    fn test_equipping_swaps_out_the_worn_item() {
+        let mut game = arena();
+        game.rules.items = ItemCatalog::parse(r#"{"items": [
+            {"id": "dagger", "name": "Dagger", "slot": "weapon", "modifiers": {"attack": 1}},
+            {"id": "axe", "name": "Axe", "slot": "weapon", "modifiers": {"attack": 6, "speed": -50}}
+        ]}"#).unwrap();
+        game.add_player("player1".to_string()).unwrap();
+        game.players.get_mut("player1").unwrap().inventory = vec!["dagger".to_string(), "axe".to_string()];
+        game.equip("player1", "dagger").unwrap();
+        game.equip("player1", "axe").unwrap();
+        let character = game.get_character("player1").unwrap();
+        assert_eq!(character.inventory, vec!["dagger".to_string()]);
+        assert_eq!(game.effective_stats(character).speed, 50);
+
+        game.rules.move_cooldown = Duration::from_secs(10);
+        game.move_character("player1", Direction::Left).unwrap();
+        match game.move_character("player1", Direction::Right) {
+            Err(MoveError::OnCooldown { remaining }) => assert!(remaining > Duration::from_secs(10)),
+            other => panic!("expected a cooldown, got {:?}", other),
+        }
+    }
+
+    struct BrokenStorage;
+
+    impl Storage for BrokenStorage {
+        fn touch_account(&self, _: &str, _: u64) -> Result<Account, StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn load_account(&self, _: &str) -> Result<Option<Account>, StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn create_login(&self, _: &str, _: &PasswordHash) -> Result<bool, StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn load_login(&self, _: &str) -> Result<Option<PasswordHash>, StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn load_character(&self, _: &str, _: &str) -> Result<Option<Character>, StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+
+        fn save_characters(&self, _: &str, _: &[(&str, &Character)]) -> Result<(), StorageError> {
+            Err(rusqlite::Error::InvalidQuery.into())
+        }
+    }
+
+    #[test]
+    fn test_players_return_to_their_saved_character() {
+        let storage = Arc::new(MemoryStorage::new());
+        let map = Map::new(3, 1, vec![vec![Tile::Floor; 3]]);
+        let mut game = GameState::new(map.clone());
+        game.set_storage(storage.clone(), "default");
+        game.add_player("player1".to_string()).unwrap();
+        game.move_character("player1", Direction::Right).unwrap();
+        game.players.get_mut("player1").unwrap().health = 40;
+        game.remove_player("player1");
+        assert!(storage.load_account("player1").unwrap().is_some());
+
+        let mut game = GameState::new(map.clone());
+        game.set_storage(storage.clone(), "default");
+        assert_eq!(game.add_player("player1".to_string()).unwrap(), &Character::new(1, 0, 40));
+
+        // A taken spot sends the player to a spawn point, keeping the rest.
+        storage.save_characters("default", &[("player2", &Character::new(1, 0, 100))]).unwrap();
+        let mut game = GameState::new(map);
+        game.set_storage(storage, "default");
+        game.add_player("player2".to_string()).unwrap();
+        let character = game.add_player("player1".to_string()).unwrap();
+        assert_ne!((character.x, character.y), (1, 0));
+        assert_eq!(character.health, 40);
+    }
+
+    #[test]
+    fn test_storage_failures_do_not_stop_the_game() {
+        let mut game = GameState::new(Map::new(2, 1, vec![vec![Tile::Floor; 2]]));
+        game.set_storage(Arc::new(BrokenStorage), "default");
+        assert_eq!(game.add_player("player1".to_string()).unwrap(), &Character::new(0, 0, 100));
+        game.connect_player("player1");
+        game.disconnect_player("player1");
+        game.save_all();
+        assert!(game.remove_player("player1").is_some());
+    }
+
+    #[test]
+    fn test_create_default_map() {
+        let map = create_default_map();
+        assert_eq!(map.width, 10);
+        assert_eq!(map.height, 10);
+        assert_eq!(map.tiles.len(), 10);
+        assert_eq!(map.tiles[0].len(), 10);
+
+        // Check some known wall positions
+        assert_eq!(map.tiles[0][3], Tile::Wall);
+        assert_eq!(map.tiles[1][1], Tile::Wall);
+        assert_eq!(map.tiles[0][0], Tile::Floor);
+        assert_eq!(GameState::new(map).items_at(4, 2), ["health_potion".to_string()]);
+    }
+}
@@ -0,0 +1,286 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crate::stats::Stats;
+
+// The built-in items. `GAME_ITEMS` points at a file of the same shape.
+const DEFAULT_ITEMS: &str = include_str!("../data/items.json");
+
+// Map objects of this kind put an item on the ground, named by its id.
+pub const ITEM_KIND: &str = "item";
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemEffect {
+    // Restores health, up to the character's maximum.
+    Heal(i32),
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum EquipmentSlot {
+    Weapon,
+    Armor,
+    Trinket,
+}
+
+// What an equipped item adds to the wearer's stats; negative values take away.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct Modifiers {
+    pub max_health: i32,
+    pub attack: i32,
+    pub defense: i32,
+    pub speed: i32,
+}
+
+impl Modifiers {
+    pub fn is_empty(&self) -> bool {
+        *self == Modifiers::default()
+    }
+
+    pub fn apply(&self, stats: &mut Stats) {
+        stats.max_health += self.max_health;
+        stats.attack += self.attack;
+        stats.defense += self.defense;
+        stats.speed += self.speed;
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ItemDef {
+    pub id: String,
+    pub name: String,
+    // What using the item does; items without one cannot be used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effect: Option<ItemEffect>,
+    // Where the item is worn; items without one cannot be equipped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<EquipmentSlot>,
+    #[serde(default, skip_serializing_if = "Modifiers::is_empty")]
+    pub modifiers: Modifiers,
+}
+
+#[derive(Deserialize)]
+struct CatalogFile {
+    items: Vec<ItemDef>,
+}
+
+// Every item the game knows, by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ItemCatalog {
+    items: BTreeMap<String, ItemDef>,
+}
+
+impl Default for ItemCatalog {
+    fn default() -> Self {
+        ItemCatalog::parse(DEFAULT_ITEMS).expect("the built-in items are valid")
+    }
+}
+
+#[derive(Debug)]
+pub enum ItemError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemError::Io(e) => write!(f, "failed to read items: {}", e),
+            ItemError::Format(e) => write!(f, "items are not valid JSON: {}", e),
+            ItemError::Invalid(message) => write!(f, "invalid items: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ItemError {}
+
+impl From<std::io::Error> for ItemError {
+    fn from(e: std::io::Error) -> Self {
+        ItemError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ItemError {
+    fn from(e: serde_json::Error) -> Self {
+        ItemError::Format(e)
+    }
+}
+
+impl ItemCatalog {
+    pub fn parse(text: &str) -> Result<Self, ItemError> {
+        let file: CatalogFile = serde_json::from_str(text)?;
+        let mut items = BTreeMap::new();
+        for item in file.items {
+            if item.id.is_empty() {
+                return Err(ItemError::Invalid(format!("'{}' has no id", item.name)));
+            }
+            if let Some(ItemEffect::Heal(amount)) = item.effect
+                && amount <= 0
+            {
+                return Err(ItemError::Invalid(format!("{} heals {} points", item.id, amount)));
+            }
+            if item.slot.is_none() && !item.modifiers.is_empty() {
+                return Err(ItemError::Invalid(format!("{} has modifiers but cannot be equipped", item.id)));
+            }
+            if let Some(duplicate) = items.insert(item.id.clone(), item) {
+                return Err(ItemError::Invalid(format!("{} is defined twice", duplicate.id)));
+            }
+        }
+        Ok(Self { items })
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ItemDef> {
+        self.items.get(id)
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &ItemDef> {
+        self.items.values()
+    }
+
+    // Items that were removed from the catalog are still shown, by id.
+    pub fn describe(&self, id: &str) -> ItemDef {
+        self.get(id).cloned().unwrap_or_else(|| ItemDef {
+            id: id.to_string(),
+            name: id.to_string(),
+            effect: None,
+            slot: None,
+            modifiers: Modifiers::default(),
+        })
+    }
+
+    // The stats with the modifiers of everything equipped added. Speed and
+    // maximum health never drop below one.
+    pub fn equipped_stats(&self, stats: &Stats, equipment: &BTreeMap<EquipmentSlot, String>) -> Stats {
+        let mut stats = stats.clone();
+        for item in equipment.values().filter_map(|id| self.get(id)) {
+            item.modifiers.apply(&mut stats);
+        }
+        stats.max_health = stats.max_health.max(1);
+        stats.speed = stats.speed.max(1);
+        stats
+    }
+}
+
+pub fn load_item_file(path: impl AsRef<Path>) -> Result<ItemCatalog, ItemError> {
+    ItemCatalog::parse(&fs::read_to_string(path)?)
+}
+
+// The items lying on one tile, in the order they were dropped there.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct GroundItems {
+    pub x: i32,
+    pub y: i32,
+    pub items: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ItemAction {
+    // Picks up the named item, or the top one, from the character's tile.
+    PickUp(Option<String>),
+    Drop(String),
+    Use(String),
+    // Moves the item from the inventory into its slot, swapping out what
+    // was there.
+    Equip(String),
+    // Moves an equipped item back into the inventory.
+    Unequip(String),
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InventoryView {
+    pub capacity: usize,
+    pub items: Vec<ItemDef>,
+    pub equipment: BTreeMap<EquipmentSlot, ItemDef>,
+    // The character's stats with the equipment's modifiers.
+    pub stats: Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InventoryError {
+    UnknownPlayer,
+    UnknownItem,
+    Dead,
+    NothingHere,
+    InventoryFull { capacity: usize },
+    NotCarried,
+    NotUsable,
+    FullHealth,
+    NotEquippable,
+    NotEquipped,
+}
+
+impl InventoryError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            InventoryError::UnknownPlayer => "unknown_player",
+            InventoryError::UnknownItem => "unknown_item",
+            InventoryError::Dead => "dead",
+            InventoryError::NothingHere => "nothing_here",
+            InventoryError::InventoryFull { .. } => "inventory_full",
+            InventoryError::NotCarried => "not_carried",
+            InventoryError::NotUsable => "not_usable",
+            InventoryError::FullHealth => "full_health",
+            InventoryError::NotEquippable => "not_equippable",
+            InventoryError::NotEquipped => "not_equipped",
+        }
+    }
+}
+
+impl fmt::Display for InventoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InventoryError::UnknownPlayer => write!(f, "Player not found"),
+            InventoryError::UnknownItem => write!(f, "No such item"),
+            InventoryError::Dead => write!(f, "Dead characters cannot handle items"),
+            InventoryError::NothingHere => write!(f, "There is no such item here"),
+            InventoryError::InventoryFull { capacity } => write!(f, "The inventory holds at most {} items", capacity),
+            InventoryError::NotCarried => write!(f, "The item is not in the inventory"),
+            InventoryError::NotUsable => write!(f, "The item cannot be used"),
+            InventoryError::FullHealth => write!(f, "Health is already full"),
+            InventoryError::NotEquippable => write!(f, "The item cannot be equipped"),
+            InventoryError::NotEquipped => write!(f, "The item is not equipped"),
+        }
+    }
+}
+
+impl std::error::Error for InventoryError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_item_catalogs_are_validated() {
+        let catalog = ItemCatalog::default();
+        assert_eq!(catalog.get("health_potion").unwrap().effect, Some(ItemEffect::Heal(30)));
+        assert_eq!(catalog.describe("gone").name, "gone");
+
+        let twice = r#"{"items": [{"id": "key", "name": "Key"}, {"id": "key", "name": "Other key"}]}"#;
+        assert!(matches!(ItemCatalog::parse(twice), Err(ItemError::Invalid(_))));
+        let harmful = r#"{"items": [{"id": "poison", "name": "Poison", "effect": {"heal": -5}}]}"#;
+        assert!(matches!(ItemCatalog::parse(harmful), Err(ItemError::Invalid(_))));
+        assert!(matches!(ItemCatalog::parse(r#"{"items": [{"id": "key"}]}"#), Err(ItemError::Format(_))));
+        let unwearable = r#"{"items": [{"id": "rock", "name": "Rock", "modifiers": {"attack": 1}}]}"#;
+        assert!(matches!(ItemCatalog::parse(unwearable), Err(ItemError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_equipment_modifies_stats() {
+        let catalog = ItemCatalog::default();
+        let mut equipment = BTreeMap::new();
+        equipment.insert(EquipmentSlot::Weapon, "short_sword".to_string());
+        equipment.insert(EquipmentSlot::Armor, "leather_armor".to_string());
+        equipment.insert(EquipmentSlot::Trinket, "forgotten".to_string());
+        let stats = catalog.equipped_stats(&Stats::default(), &equipment);
+        assert_eq!((stats.attack, stats.defense, stats.speed, stats.max_health), (14, 5, 90, 100));
+
+        let slow = Stats { speed: 5, ..Stats::default() };
+        assert_eq!(catalog.equipped_stats(&slow, &equipment).speed, 1);
+    }
+}
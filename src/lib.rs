@@ -1,2 +1,23 @@
+pub mod accounts;
+pub mod auth;
+pub mod combat;
+pub mod config;
+pub mod direction;
+pub mod dungeon;
 pub mod game;
-pub mod web;
\ No newline at end of file
+pub mod items;
+pub mod lobby;
+pub mod logging;
+pub mod map_loader;
+pub mod messages;
+pub mod protocol;
+pub mod rng;
+pub mod rooms;
+pub mod save;
+pub mod socket;
+pub mod spawn;
+pub mod stats;
+pub mod storage;
+pub mod tile;
+pub mod tiled;
+pub mod web;
@@ -0,0 +1,575 @@
+use actix::prelude::*;
+use actix_web_actors::ws;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::rooms::{CloseRoom, CreateRoom, ListRooms, MapChoice, RoomRegistry, find_map};
+
+pub const MAX_PARTY_SIZE: usize = 8;
+const MAX_MODE_LENGTH: usize = 32;
+// How long players who left the queue can still ask how it ended for them.
+const RESULT_TTL: Duration = Duration::from_secs(5 * 60);
+// How long a match room may stay empty after it starts, for its players to
+// connect, before it counts as finished.
+const MATCH_GRACE: Duration = Duration::from_secs(60);
+// How often finished match rooms are closed, besides before every match.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+// What a player is looking for. Players are only grouped with others who
+// asked for exactly the same thing.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MatchPreferences {
+    // Only a label that keeps queues apart and names the room; every mode
+    // plays by the server's rules.
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    // A map from the maps directory; the server's default map if absent.
+    #[serde(default)]
+    pub map: Option<String>,
+    #[serde(default = "default_party_size")]
+    pub party_size: usize,
+}
+
+fn default_mode() -> String {
+    "free_for_all".to_string()
+}
+
+fn default_party_size() -> usize {
+    2
+}
+
+impl Default for MatchPreferences {
+    fn default() -> Self {
+        Self { mode: default_mode(), map: None, party_size: default_party_size() }
+    }
+}
+
+impl MatchPreferences {
+    pub fn validate(&self) -> Result<(), LobbyError> {
+        if !(1..=MAX_PARTY_SIZE).contains(&self.party_size) {
+            return Err(LobbyError::InvalidPreferences(format!(
+                "party_size must be between 1 and {}",
+                MAX_PARTY_SIZE
+            )));
+        }
+        let valid_mode = !self.mode.is_empty()
+            && self.mode.len() <= MAX_MODE_LENGTH
+            && self.mode.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_mode {
+            return Err(LobbyError::InvalidPreferences(format!("'{}' is not a valid mode", self.mode)));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LobbyError {
+    InvalidPreferences(String),
+    UnknownMap(String),
+}
+
+impl LobbyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            LobbyError::InvalidPreferences(_) => "invalid_preferences",
+            LobbyError::UnknownMap(_) => "invalid_map_name",
+        }
+    }
+}
+
+impl fmt::Display for LobbyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LobbyError::InvalidPreferences(message) => write!(f, "{}", message),
+            LobbyError::UnknownMap(name) => write!(f, "'{}' is not a map in the maps directory", name),
+        }
+    }
+}
+
+impl std::error::Error for LobbyError {}
+
+// Sent to players in the lobby, over `/lobby/ws` or as the answer to
+// `GET /lobby/queue`.
+#[derive(Message, Serialize, Debug, Clone, PartialEq)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LobbyEvent {
+    Queued { preferences: MatchPreferences, waiting: usize },
+    // Connect to `/ws?room=<room_id>` to play.
+    MatchFound { room_id: String, players: Vec<String> },
+    Error { code: String, message: String },
+}
+
+struct Ticket {
+    player_id: String,
+    preferences: MatchPreferences,
+    notify: Option<Recipient<LobbyEvent>>,
+}
+
+// Queues players and starts a room for every full party of players with
+// the same preferences.
+pub struct Matchmaker {
+    rooms: Addr<RoomRegistry>,
+    // Where the maps players ask for are looked up; the same as the rooms'.
+    maps_dir: PathBuf,
+    queue: Vec<Ticket>,
+    // The last thing each player was told and when, for clients that poll.
+    last_event: HashMap<String, (LobbyEvent, Instant)>,
+    // The rooms started for matches and when, so that they are closed once
+    // everybody has left; otherwise they would use up the room limit.
+    matches: HashMap<String, Instant>,
+    match_grace: Duration,
+}
+
+impl Matchmaker {
+    pub fn new(rooms: Addr<RoomRegistry>, maps_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            rooms,
+            maps_dir: maps_dir.into(),
+            queue: Vec::new(),
+            last_event: HashMap::new(),
+            matches: HashMap::new(),
+            match_grace: MATCH_GRACE,
+        }
+    }
+
+    pub fn with_match_grace(mut self, grace: Duration) -> Self {
+        self.match_grace = grace;
+        self
+    }
+
+    // Forgets the results of players who are no longer queued once they are
+    // old enough that nobody is polling for them.
+    fn prune_results(&mut self, now: Instant) {
+        let queue = &self.queue;
+        self.last_event.retain(|player_id, (_, at)| {
+            queue.iter().any(|ticket| ticket.player_id == *player_id) || now.duration_since(*at) < RESULT_TTL
+        });
+    }
+
+    fn notify(&mut self, player_id: &str, notify: Option<&Recipient<LobbyEvent>>, event: LobbyEvent) {
+        if let Some(recipient) = notify {
+            recipient.do_send(event.clone());
+        }
+        self.last_event.insert(player_id.to_string(), (event, Instant::now()));
+    }
+
+    fn notify_waiting(&mut self, preferences: &MatchPreferences) {
+        let waiting = self.queue.iter().filter(|ticket| ticket.preferences == *preferences).count();
+        let tickets: Vec<(String, Option<Recipient<LobbyEvent>>)> = self.queue.iter()
+            .filter(|ticket| ticket.preferences == *preferences)
+            .map(|ticket| (ticket.player_id.clone(), ticket.notify.clone()))
+            .collect();
+        for (player_id, notify) in tickets {
+            let event = LobbyEvent::Queued { preferences: preferences.clone(), waiting };
+            self.notify(&player_id, notify.as_ref(), event);
+        }
+    }
+
+    // Takes the oldest full party for `preferences` off the queue, if any.
+    fn take_party(&mut self, preferences: &MatchPreferences) -> Option<Vec<Ticket>> {
+        let waiting = self.queue.iter().filter(|ticket| ticket.preferences == *preferences).count();
+        if waiting < preferences.party_size {
+            return None;
+        }
+        let mut party = Vec::with_capacity(preferences.party_size);
+        let mut index = 0;
+        while party.len() < preferences.party_size {
+            if self.queue[index].preferences == *preferences {
+                party.push(self.queue.remove(index));
+            } else {
+                index += 1;
+            }
+        }
+        Some(party)
+    }
+
+    // Closes the match rooms that are past their grace period and empty.
+    // Resolves to the rooms that are gone, whether closed here or elsewhere.
+    fn reap_matches(&self) -> impl Future<Output = Vec<String>> + 'static {
+        let now = Instant::now();
+        let expired: Vec<String> = self.matches.iter()
+            .filter(|(_, started)| now.duration_since(**started) >= self.match_grace)
+            .map(|(room_id, _)| room_id.clone())
+            .collect();
+        let rooms = self.rooms.clone();
+        async move {
+            if expired.is_empty() {
+                return Vec::new();
+            }
+            let Ok(listed) = rooms.send(ListRooms).await else {
+                return Vec::new();
+            };
+            let mut gone = Vec::new();
+            for room_id in expired {
+                match listed.iter().find(|room| room.id == room_id) {
+                    Some(room) if room.players > 0 => continue,
+                    Some(_) => {
+                        let close = CloseRoom { id: room_id.clone(), player_id: "lobby".to_string(), admin: true };
+                        if rooms.send(close).await.is_err() {
+                            continue;
+                        }
+                    }
+                    None => (),
+                }
+                gone.push(room_id);
+            }
+            gone
+        }
+    }
+
+    fn forget_matches(&mut self, gone: Vec<String>) {
+        for room_id in gone {
+            self.matches.remove(&room_id);
+        }
+    }
+
+    fn start_match(&mut self, party: Vec<Ticket>, ctx: &mut Context<Self>) {
+        let preferences = party[0].preferences.clone();
+        let map = preferences.map.clone().map_or(MapChoice::Default, MapChoice::File);
+        let name = Some(format!("{} match", preferences.mode));
+        let reap = self.reap_matches();
+        let rooms = self.rooms.clone();
+        let create = async move {
+            let gone = reap.await;
+            (gone, rooms.send(CreateRoom { name, map, owner: None }).await)
+        };
+        // Waiting keeps the queue consistent while the room is created.
+        ctx.wait(create.into_actor(self).map(move |(gone, result), lobby, _| {
+            lobby.forget_matches(gone);
+            let players: Vec<String> = party.iter().map(|ticket| ticket.player_id.clone()).collect();
+            let event = match result {
+                Ok(Ok(room)) => {
+                    lobby.matches.insert(room.id.clone(), Instant::now());
+                    LobbyEvent::MatchFound { room_id: room.id, players }
+                }
+                Ok(Err(e)) => LobbyEvent::Error { code: e.code().to_string(), message: e.to_string() },
+                Err(_) => LobbyEvent::Error {
+                    code: "internal".to_string(),
+                    message: "The room could not be created".to_string(),
+                },
+            };
+            for ticket in &party {
+                lobby.notify(&ticket.player_id, ticket.notify.as_ref(), event.clone());
+            }
+        }));
+    }
+}
+
+impl Actor for Matchmaker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(REAP_INTERVAL, |lobby, ctx| {
+            ctx.spawn(lobby.reap_matches().into_actor(lobby).map(|gone, lobby, _| lobby.forget_matches(gone)));
+        });
+    }
+}
+
+// Queues the player, replacing an earlier ticket of theirs.
+#[derive(Message)]
+#[rtype(result = "Result<LobbyEvent, LobbyError>")]
+pub struct Enqueue {
+    pub player_id: String,
+    pub preferences: MatchPreferences,
+    pub notify: Option<Recipient<LobbyEvent>>,
+}
+
+// Leaves the queue. Answers whether the player was queued.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Dequeue {
+    pub player_id: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<LobbyEvent>")]
+pub struct PlayerStatus {
+    pub player_id: String,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QueueSummary {
+    pub preferences: MatchPreferences,
+    pub waiting: usize,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<QueueSummary>")]
+pub struct ListQueues;
+
+impl Handler<Enqueue> for Matchmaker {
+    type Result = Result<LobbyEvent, LobbyError>;
+
+    fn handle(&mut self, msg: Enqueue, ctx: &mut Self::Context) -> Self::Result {
+        msg.preferences.validate()?;
+        if let Some(map) = &msg.preferences.map {
+            find_map(&self.maps_dir, map).map_err(|_| LobbyError::UnknownMap(map.clone()))?;
+        }
+        self.prune_results(Instant::now());
+        if let Some(index) = self.queue.iter().position(|ticket| ticket.player_id == msg.player_id) {
+            let old = self.queue.remove(index);
+            self.notify_waiting(&old.preferences);
+        }
+        let preferences = msg.preferences.clone();
+        self.queue.push(Ticket { player_id: msg.player_id.clone(), preferences: msg.preferences, notify: msg.notify });
+        self.notify_waiting(&preferences);
+        let queued = self.last_event[&msg.player_id].0.clone();
+
+        if let Some(party) = self.take_party(&preferences) {
+            self.start_match(party, ctx);
+        }
+        Ok(queued)
+    }
+}
+
+impl Handler<Dequeue> for Matchmaker {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Dequeue, _: &mut Self::Context) -> bool {
+        let Some(index) = self.queue.iter().position(|ticket| ticket.player_id == msg.player_id) else {
+            return false;
+        };
+        let ticket = self.queue.remove(index);
+        self.last_event.remove(&msg.player_id);
+        self.notify_waiting(&ticket.preferences);
+        true
+    }
+}
+
+impl Handler<PlayerStatus> for Matchmaker {
+    type Result = Option<LobbyEvent>;
+
+    fn handle(&mut self, msg: PlayerStatus, _: &mut Self::Context) -> Self::Result {
+        self.last_event.get(&msg.player_id).map(|(event, _)| event.clone())
+    }
+}
+
+impl Handler<ListQueues> for Matchmaker {
+    type Result = Vec<QueueSummary>;
+
+    fn handle(&mut self, _: ListQueues, _: &mut Self::Context) -> Self::Result {
+        let mut summaries: Vec<QueueSummary> = Vec::new();
+        for ticket in &self.queue {
+            match summaries.iter_mut().find(|summary| summary.preferences == ticket.preferences) {
+                Some(summary) => summary.waiting += 1,
+                None => summaries.push(QueueSummary { preferences: ticket.preferences.clone(), waiting: 1 }),
+            }
+        }
+        summaries
+    }
+}
+
+// Messages a client sends over `/lobby/ws`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LobbyRequest {
+    Queue(MatchPreferences),
+    Leave,
+}
+
+// A lobby connection of an authenticated player. Closing it before a match
+// is found leaves the queue.
+pub struct LobbySocket {
+    lobby: Addr<Matchmaker>,
+    player_id: String,
+    queued: bool,
+}
+
+impl LobbySocket {
+    pub fn new(lobby: Addr<Matchmaker>, player_id: String) -> Self {
+        Self { lobby, player_id, queued: false }
+    }
+
+    fn send(&self, event: &LobbyEvent, ctx: &mut <Self as Actor>::Context) {
+        if let Ok(data) = serde_json::to_string(event) {
+            ctx.text(data);
+        }
+    }
+
+    fn handle_request(&mut self, request: LobbyRequest, ctx: &mut <Self as Actor>::Context) {
+        match request {
+            LobbyRequest::Queue(preferences) => {
+                self.queued = true;
+                let enqueue = Enqueue {
+                    player_id: self.player_id.clone(),
+                    preferences,
+                    notify: Some(ctx.address().recipient()),
+                };
+                ctx.spawn(self.lobby.send(enqueue).into_actor(self).map(|result, socket, ctx| {
+                    if let Ok(Err(e)) = result {
+                        socket.send(&LobbyEvent::Error { code: e.code().to_string(), message: e.to_string() }, ctx);
+                    }
+                }));
+            }
+            LobbyRequest::Leave => self.leave(),
+        }
+    }
+
+    fn leave(&mut self) {
+        if std::mem::take(&mut self.queued) {
+            self.lobby.do_send(Dequeue { player_id: self.player_id.clone() });
+        }
+    }
+}
+
+impl Actor for LobbySocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.leave();
+    }
+}
+
+impl Handler<LobbyEvent> for LobbySocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: LobbyEvent, ctx: &mut Self::Context) {
+        self.send(&msg, ctx);
+        if let LobbyEvent::MatchFound { .. } = msg {
+            // The player is out of the queue and moves on to the room.
+            self.queued = false;
+            ctx.close(Some(ws::CloseCode::Normal.into()));
+            ctx.stop();
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LobbySocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<LobbyRequest>(&text) {
+                Ok(request) => self.handle_request(request, ctx),
+                Err(e) => self.send(&LobbyEvent::Error { code: "invalid_message".to_string(), message: e.to_string() }, ctx),
+            },
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{GameRules, create_default_map};
+    use crate::messages::AddPlayer;
+    use crate::rooms::{GetRoom, MAX_ROOMS};
+
+    fn lobby() -> (Addr<RoomRegistry>, Addr<Matchmaker>) {
+        let rooms = RoomRegistry::new(create_default_map(), GameRules::default(), "maps").start();
+        (rooms.clone(), Matchmaker::new(rooms, "maps").start())
+    }
+
+    fn enqueue(player_id: &str, preferences: MatchPreferences) -> Enqueue {
+        Enqueue { player_id: player_id.to_string(), preferences, notify: None }
+    }
+
+    #[test]
+    fn test_preferences_are_validated() {
+        assert!(MatchPreferences::default().validate().is_ok());
+        assert!(MatchPreferences { party_size: 0, ..MatchPreferences::default() }.validate().is_err());
+        assert!(MatchPreferences { party_size: MAX_PARTY_SIZE + 1, ..MatchPreferences::default() }.validate().is_err());
+        assert!(MatchPreferences { mode: "capture the flag".to_string(), ..MatchPreferences::default() }.validate().is_err());
+
+        let request: LobbyRequest = serde_json::from_str(r#"{"type": "queue", "party_size": 4}"#).unwrap();
+        assert_eq!(request, LobbyRequest::Queue(MatchPreferences { party_size: 4, ..MatchPreferences::default() }));
+    }
+
+    #[actix_rt::test]
+    async fn test_full_party_gets_a_room() {
+        let (rooms, lobby) = lobby();
+        let duo = MatchPreferences { map: Some("arena".to_string()), ..MatchPreferences::default() };
+        let trio = MatchPreferences { party_size: 3, ..MatchPreferences::default() };
+
+        let queued = lobby.send(enqueue("a", duo.clone())).await.unwrap().unwrap();
+        assert_eq!(queued, LobbyEvent::Queued { preferences: duo.clone(), waiting: 1 });
+        lobby.send(enqueue("b", trio.clone())).await.unwrap().unwrap();
+        lobby.send(enqueue("c", duo.clone())).await.unwrap().unwrap();
+
+        let status = lobby.send(PlayerStatus { player_id: "a".to_string() }).await.unwrap();
+        let Some(LobbyEvent::MatchFound { room_id, players }) = status else {
+            panic!("expected a match, got {:?}", status);
+        };
+        assert_eq!(players, ["a", "c"]);
+        assert_eq!(
+            lobby.send(PlayerStatus { player_id: "c".to_string() }).await.unwrap(),
+            Some(LobbyEvent::MatchFound { room_id: room_id.clone(), players: players.clone() })
+        );
+        assert!(rooms.send(GetRoom { id: room_id }).await.unwrap().is_ok());
+        assert_eq!(rooms.send(ListRooms).await.unwrap()[0].width, 14);
+
+        assert_eq!(
+            lobby.send(PlayerStatus { player_id: "b".to_string() }).await.unwrap(),
+            Some(LobbyEvent::Queued { preferences: trio.clone(), waiting: 1 })
+        );
+        assert_eq!(lobby.send(ListQueues).await.unwrap(), [QueueSummary { preferences: trio, waiting: 1 }]);
+    }
+
+    #[actix_rt::test]
+    async fn test_leaving_and_requeueing() {
+        let (_, lobby) = lobby();
+        let missing = MatchPreferences { map: Some("missing".to_string()), party_size: 1, ..MatchPreferences::default() };
+
+        lobby.send(enqueue("a", MatchPreferences::default())).await.unwrap().unwrap();
+        assert!(lobby.send(Dequeue { player_id: "a".to_string() }).await.unwrap());
+        assert!(!lobby.send(Dequeue { player_id: "a".to_string() }).await.unwrap());
+        assert!(lobby.send(ListQueues).await.unwrap().is_empty());
+
+        // Maps are checked before anyone waits for them.
+        let err = lobby.send(enqueue("a", missing)).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), "invalid_map_name");
+        assert!(lobby.send(ListQueues).await.unwrap().is_empty());
+
+        let invalid = MatchPreferences { party_size: 0, ..MatchPreferences::default() };
+        assert!(lobby.send(enqueue("b", invalid)).await.unwrap().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_finished_matches_are_closed() {
+        let rooms = RoomRegistry::new(create_default_map(), GameRules::default(), "maps").start();
+        let lobby = Matchmaker::new(rooms.clone(), "maps").with_match_grace(Duration::ZERO).start();
+        let solo = MatchPreferences { party_size: 1, ..MatchPreferences::default() };
+
+        let mut last_room = String::new();
+        for i in 0..=MAX_ROOMS {
+            let player_id = format!("p{}", i);
+            lobby.send(enqueue(&player_id, solo.clone())).await.unwrap().unwrap();
+            let status = lobby.send(PlayerStatus { player_id }).await.unwrap();
+            let Some(LobbyEvent::MatchFound { room_id, .. }) = status else {
+                panic!("match {} failed: {:?}", i, status);
+            };
+            last_room = room_id;
+        }
+        assert_eq!(rooms.send(ListRooms).await.unwrap().len(), 1);
+
+        // Rooms with players in them stay open.
+        let game = rooms.send(GetRoom { id: last_room.clone() }).await.unwrap().unwrap();
+        game.send(AddPlayer { player_id: "p0".to_string() }).await.unwrap().unwrap();
+        lobby.send(enqueue("q", solo)).await.unwrap().unwrap();
+        let open: Vec<String> = rooms.send(ListRooms).await.unwrap().into_iter().map(|room| room.id).collect();
+        assert_eq!(open.len(), 2);
+        assert!(open.contains(&last_room));
+    }
+
+    #[actix_rt::test]
+    async fn test_old_results_are_forgotten() {
+        let (rooms, _) = lobby();
+        let mut lobby = Matchmaker::new(rooms, "maps");
+        let found = LobbyEvent::MatchFound { room_id: "room".to_string(), players: vec!["a".to_string()] };
+        lobby.notify("a", None, found);
+        lobby.queue.push(Ticket { player_id: "b".to_string(), preferences: MatchPreferences::default(), notify: None });
+        lobby.notify_waiting(&MatchPreferences::default());
+
+        let now = Instant::now();
+        lobby.prune_results(now);
+        assert_eq!(lobby.last_event.len(), 2);
+        lobby.prune_results(now + RESULT_TTL);
+        assert_eq!(lobby.last_event.keys().collect::<Vec<_>>(), ["b"]);
+    }
+}
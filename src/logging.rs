@@ -0,0 +1,28 @@
+use log::{Level, Log, Metadata, Record};
+
+// Writes every log record to stderr as `[LEVEL target] message`. The server
+// and the libraries it uses all log through the `log` macros.
+struct StderrLogger {
+    level: Level,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Installs the logger; later calls keep the first one.
+pub fn init(level: Level) {
+    if log::set_boxed_logger(Box::new(StderrLogger { level })).is_ok() {
+        log::set_max_level(level.to_level_filter());
+    }
+}
@@ -1,29 +1,130 @@
-mod game;
-mod web;
-
+use actix::Actor;
 use actix_web::{App, HttpServer};
-use actix_web::web::{Data, get, post};
+use actix_web::web::{Data, delete, get, post};
+use hello_cargo::auth::TokenSigner;
+use hello_cargo::config::{MapSource, ServerConfig};
+use hello_cargo::dungeon::generate;
+use hello_cargo::game::{GameState, create_default_map};
+use hello_cargo::items::load_item_file;
+use hello_cargo::lobby::Matchmaker;
+use hello_cargo::logging;
+use hello_cargo::map_loader::load_map_file;
+use hello_cargo::rooms::{DEFAULT_ROOM, RoomRegistry, SaveWorld};
+use hello_cargo::save::{read_save, write_save};
+use hello_cargo::stats::load_level_file;
+use hello_cargo::storage::{MemoryStorage, SqliteStorage, Storage};
+use hello_cargo::web;
+use log::{info, warn};
 use std::sync::Arc;
-use crate::game::{GameState, create_default_map};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let map = create_default_map();
-    let game_state = Arc::new(std::sync::Mutex::new(GameState::new(map)));
+    logging::init(log::Level::Info);
+    let mut config = ServerConfig::from_env().map_err(std::io::Error::other)?;
+    if let Some(path) = &config.levels {
+        config.rules.levels = load_level_file(path).map_err(|e| {
+            std::io::Error::other(format!("{}: {}", path.display(), e))
+        })?;
+    }
+    if let Some(path) = &config.items {
+        config.rules.items = load_item_file(path).map_err(|e| {
+            std::io::Error::other(format!("{}: {}", path.display(), e))
+        })?;
+    }
+    let mut map = match &config.map {
+        MapSource::Default => create_default_map(),
+        MapSource::File(path) => load_map_file(path).map_err(|e| {
+            std::io::Error::other(format!("{}: {}", path.display(), e))
+        })?,
+        MapSource::Generated(dungeon) => {
+            info!("Generating {:?} map {}x{} with seed {}", dungeon.generator, dungeon.width, dungeon.height, dungeon.seed);
+            generate(dungeon).map_err(std::io::Error::other)?
+        }
+    };
+    if let Some(movement) = config.movement {
+        map.movement = movement;
+    }
+    if config.auth.secret.is_none() {
+        warn!("GAME_AUTH_SECRET is not set; sessions will not survive a restart");
+    }
+    let signer = Data::new(TokenSigner::from_config(&config.auth));
+    let maps_dir = "maps";
+    let mut rooms = RoomRegistry::new(map.clone(), config.rules.clone(), maps_dir);
+    // Without a database, accounts last until the server stops.
+    let accounts: Arc<dyn Storage> = match &config.database {
+        Some(path) => {
+            let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::open(path).map_err(|e| {
+                std::io::Error::other(format!("{}: {}", path.display(), e))
+            })?);
+            rooms.set_storage(storage.clone());
+            storage
+        }
+        None => Arc::new(MemoryStorage::new()),
+    };
+    let accounts = Data::from(accounts);
+    let mut has_default_room = false;
+    if let Some(path) = config.world_file.as_ref().filter(|path| path.exists()) {
+        let save = read_save(path).map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?;
+        info!("Restoring {} rooms saved at {} from {}", save.rooms.len(), save.saved_at, path.display());
+        has_default_room = save.rooms.iter().any(|room| room.id == DEFAULT_ROOM);
+        rooms.restore(save).map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?;
+    }
+    if !has_default_room {
+        rooms.add_room(DEFAULT_ROOM, "Default", GameState::with_rules(map, config.rules));
+    }
 
-    let app_data = Data::new(game_state);
+    let rooms = rooms.start();
+    let lobby_data = Data::new(Matchmaker::new(rooms.clone(), maps_dir).start());
+    let app_data = Data::new(rooms.clone());
+    let world_file = Data::new(web::WorldFile::new(config.world_file.clone()));
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
-            .route("/", get().to(crate::web::hello))
-            .route("/game", get().to(crate::web::game_page))
-            .route("/character", get().to(crate::web::get_character))
-            .route("/map", get().to(crate::web::get_map))
-            .route("/move", post().to(crate::web::move_character))
-            .route("/ws", get().to(crate::web::websocket))
+            .app_data(lobby_data.clone())
+            .app_data(signer.clone())
+            .app_data(accounts.clone())
+            .app_data(world_file.clone())
+            .route("/", get().to(web::hello))
+            .route("/game", get().to(web::game_page))
+            .route("/auth/guest", post().to(web::guest_login))
+            .route("/auth/register", post().to(web::register_account))
+            .route("/auth/login", post().to(web::account_login))
+            .route("/auth/refresh", post().to(web::refresh_session))
+            .route("/character", get().to(web::get_character))
+            .route("/map", get().to(web::get_map))
+            .route("/move", post().to(web::move_character))
+            .route("/attack", post().to(web::attack))
+            .route("/shoot", post().to(web::shoot))
+            .route("/items", get().to(web::list_items))
+            .route("/inventory", get().to(web::get_inventory))
+            .route("/inventory/pick_up", post().to(web::pick_up_item))
+            .route("/inventory/drop", post().to(web::drop_item))
+            .route("/inventory/use", post().to(web::use_item))
+            .route("/inventory/equip", post().to(web::equip_item))
+            .route("/inventory/unequip", post().to(web::unequip_item))
+            .route("/ws", get().to(web::websocket))
+            .route("/protocol/schema", get().to(web::protocol_schema))
+            .route("/stats", get().to(web::get_stats))
+            .route("/world/save", post().to(web::save_world))
+            .route("/rooms", get().to(web::list_rooms))
+            .route("/rooms", post().to(web::create_room))
+            .route("/rooms/{id}", delete().to(web::close_room))
+            .route("/rooms/{id}/join", post().to(web::join_room))
+            .route("/lobby", get().to(web::list_queues))
+            .route("/lobby/queue", get().to(web::queue_status))
+            .route("/lobby/queue", post().to(web::join_queue))
+            .route("/lobby/queue", delete().to(web::leave_queue))
+            .route("/lobby/ws", get().to(web::lobby_websocket))
     })
     .bind("127.0.0.1:8080")?
     .run()
-    .await
+    .await?;
+
+    if let Some(path) = &config.world_file {
+        let save = rooms.send(SaveWorld).await.map_err(std::io::Error::other)?;
+        write_save(path, &save).map_err(|e| std::io::Error::other(format!("{}: {}", path.display(), e)))?;
+        info!("Saved {} rooms to {}", save.rooms.len(), path.display());
+    }
+    Ok(())
 }
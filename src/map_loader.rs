@@ -0,0 +1,279 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use crate::direction::MovementMode;
+use crate::game::Map;
+use crate::tile::Tile;
+use crate::tiled::{TiledError, parse_tiled_json, parse_tmx};
+
+// Maps are plain text grids, optionally preceded by a legend header:
+//
+//     // comments start with two slashes
+//     ~ = water
+//     movement = eight_way
+//     ---
+//     #..~~
+//     #S..#
+//
+// Single-character entries extend or override the default legend below;
+// `movement` selects four- or eight-way movement. Without a `---` separator
+// the whole file is read as the grid.
+const HEADER_SEPARATOR: &str = "---";
+
+pub fn default_legend() -> HashMap<char, Tile> {
+    HashMap::from([
+        ('#', Tile::Wall),
+        ('.', Tile::Floor),
+        ('S', Tile::Spawn),
+        ('~', Tile::Water),
+        ('+', Tile::Door),
+        ('^', Tile::Lava),
+    ])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapParseError {
+    InvalidLegend { line: usize, entry: String },
+    UnknownTile { line: usize, name: String },
+    InvalidSetting { line: usize, message: String },
+    UnknownSymbol { line: usize, column: usize, symbol: char },
+    RaggedRow { line: usize, expected: usize, found: usize },
+    Empty,
+}
+
+impl fmt::Display for MapParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapParseError::InvalidLegend { line, entry } => {
+                write!(f, "line {}: invalid legend entry '{}', expected '<symbol> = <tile>'", line, entry)
+            }
+            MapParseError::UnknownTile { line, name } => {
+                write!(f, "line {}: unknown tile kind '{}'", line, name)
+            }
+            MapParseError::InvalidSetting { line, message } => write!(f, "line {}: {}", line, message),
+            MapParseError::UnknownSymbol { line, column, symbol } => {
+                write!(f, "line {}, column {}: symbol '{}' is not in the legend", line, column, symbol)
+            }
+            MapParseError::RaggedRow { line, expected, found } => {
+                write!(f, "line {}: row is {} tiles wide, expected {}", line, found, expected)
+            }
+            MapParseError::Empty => write!(f, "map has no rows"),
+        }
+    }
+}
+
+impl std::error::Error for MapParseError {}
+
+#[derive(Debug)]
+pub enum MapLoadError {
+    Io(std::io::Error),
+    Parse(MapParseError),
+    Tiled(TiledError),
+}
+
+impl fmt::Display for MapLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapLoadError::Io(e) => write!(f, "failed to read map: {}", e),
+            MapLoadError::Parse(e) => write!(f, "failed to parse map: {}", e),
+            MapLoadError::Tiled(e) => write!(f, "failed to import map: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MapLoadError {}
+
+impl From<std::io::Error> for MapLoadError {
+    fn from(e: std::io::Error) -> Self {
+        MapLoadError::Io(e)
+    }
+}
+
+impl From<MapParseError> for MapLoadError {
+    fn from(e: MapParseError) -> Self {
+        MapLoadError::Parse(e)
+    }
+}
+
+impl From<TiledError> for MapLoadError {
+    fn from(e: TiledError) -> Self {
+        MapLoadError::Tiled(e)
+    }
+}
+
+// Picks the format from the file extension: Tiled JSON (.tmj/.json), Tiled
+// XML (.tmx), or the ASCII format for anything else.
+pub fn load_map_file(path: impl AsRef<Path>) -> Result<Map, MapLoadError> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(path)?;
+    let extension = path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("tmj" | "json") => Ok(parse_tiled_json(&text)?),
+        Some("tmx") => Ok(parse_tmx(&text)?),
+        _ => Ok(parse_ascii_map(&text)?),
+    }
+}
+
+pub fn parse_ascii_map(text: &str) -> Result<Map, MapParseError> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut header = Header { legend: default_legend(), movement: MovementMode::default() };
+
+    let grid_start = match lines.iter().position(|line| line.trim() == HEADER_SEPARATOR) {
+        Some(separator) => {
+            for (index, line) in lines[..separator].iter().enumerate() {
+                parse_header_line(line, index + 1, &mut header)?;
+            }
+            separator + 1
+        }
+        None => 0,
+    };
+    let legend = header.legend;
+
+    let mut tiles: Vec<Vec<Tile>> = Vec::new();
+    let mut width = 0;
+    let mut blank_line = None;
+
+    for (index, line) in lines.iter().enumerate().skip(grid_start) {
+        let line_number = index + 1;
+        let row = line.trim_end();
+        if row.is_empty() {
+            if !tiles.is_empty() && blank_line.is_none() {
+                blank_line = Some(line_number);
+            }
+            continue;
+        }
+        if let Some(line) = blank_line {
+            // A blank line between rows would silently shift the map down.
+            return Err(MapParseError::RaggedRow { line, expected: width, found: 0 });
+        }
+
+        let parsed = row.chars().enumerate().map(|(column, symbol)| {
+            legend.get(&symbol).copied().ok_or(MapParseError::UnknownSymbol {
+                line: line_number,
+                column: column + 1,
+                symbol,
+            })
+        }).collect::<Result<Vec<Tile>, _>>()?;
+
+        if tiles.is_empty() {
+            width = parsed.len();
+        } else if parsed.len() != width {
+            return Err(MapParseError::RaggedRow { line: line_number, expected: width, found: parsed.len() });
+        }
+        tiles.push(parsed);
+    }
+
+    if tiles.is_empty() {
+        return Err(MapParseError::Empty);
+    }
+
+    let height = tiles.len();
+    let mut map = Map::new(width, height, tiles);
+    map.movement = header.movement;
+    Ok(map)
+}
+
+struct Header {
+    legend: HashMap<char, Tile>,
+    movement: MovementMode,
+}
+
+fn parse_header_line(line: &str, line_number: usize, header: &mut Header) -> Result<(), MapParseError> {
+    let entry = line.trim();
+    if entry.is_empty() || entry.starts_with("//") {
+        return Ok(());
+    }
+
+    let invalid = || MapParseError::InvalidLegend { line: line_number, entry: entry.to_string() };
+    let (key, value) = entry.split_once('=').ok_or_else(invalid)?;
+    let (key, value) = (key.trim(), value.trim());
+    if key == "movement" {
+        header.movement = value.parse()
+            .map_err(|message| MapParseError::InvalidSetting { line: line_number, message })?;
+        return Ok(());
+    }
+
+    let mut symbol_chars = key.chars();
+    let symbol = match (symbol_chars.next(), symbol_chars.next()) {
+        (Some(symbol), None) => symbol,
+        _ => return Err(invalid()),
+    };
+
+    let tile = value.parse::<Tile>().map_err(|_| MapParseError::UnknownTile {
+        line: line_number,
+        name: value.to_string(),
+    })?;
+    header.legend.insert(symbol, tile);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::create_default_map;
+
+    #[test]
+    fn test_parse_ascii_map_with_default_legend() {
+        let map = parse_ascii_map("#.S\n~+^\n").unwrap();
+        assert_eq!(map.width, 3);
+        assert_eq!(map.height, 2);
+        assert_eq!(map.tiles[0], vec![Tile::Wall, Tile::Floor, Tile::Spawn]);
+        assert_eq!(map.tiles[1], vec![Tile::Water, Tile::Door, Tile::Lava]);
+    }
+
+    #[test]
+    fn test_parse_ascii_map_with_legend_header() {
+        let text = "// custom legend\nw = water\n# = door\n\n---\nw#\n..\n";
+        let map = parse_ascii_map(text).unwrap();
+        assert_eq!(map.tiles[0], vec![Tile::Water, Tile::Door]);
+        assert_eq!(map.tiles[1], vec![Tile::Floor, Tile::Floor]);
+    }
+
+    #[test]
+    fn test_parse_ascii_map_movement_setting() {
+        assert_eq!(parse_ascii_map("..\n").unwrap().movement, MovementMode::FourWay);
+        assert_eq!(parse_ascii_map("movement = eight_way\n---\n..\n").unwrap().movement, MovementMode::EightWay);
+        assert_eq!(
+            parse_ascii_map("movement = hex\n---\n..\n").unwrap_err(),
+            MapParseError::InvalidSetting {
+                line: 1,
+                message: "unknown movement mode 'hex', expected four_way or eight_way".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_ascii_map_reports_unknown_symbol_position() {
+        let err = parse_ascii_map("---\n...\n.x.\n").unwrap_err();
+        assert_eq!(err, MapParseError::UnknownSymbol { line: 3, column: 2, symbol: 'x' });
+        assert_eq!(err.to_string(), "line 3, column 2: symbol 'x' is not in the legend");
+    }
+
+    #[test]
+    fn test_parse_ascii_map_rejects_ragged_rows() {
+        let err = parse_ascii_map("...\n..\n").unwrap_err();
+        assert_eq!(err, MapParseError::RaggedRow { line: 2, expected: 3, found: 2 });
+    }
+
+    #[test]
+    fn test_parse_ascii_map_rejects_bad_legend() {
+        assert_eq!(
+            parse_ascii_map("x = granite\n---\nx\n").unwrap_err(),
+            MapParseError::UnknownTile { line: 1, name: "granite".to_string() }
+        );
+        assert_eq!(
+            parse_ascii_map("xy = wall\n---\nx\n").unwrap_err(),
+            MapParseError::InvalidLegend { line: 1, entry: "xy = wall".to_string() }
+        );
+        assert_eq!(parse_ascii_map("---\n\n").unwrap_err(), MapParseError::Empty);
+    }
+
+    #[test]
+    fn test_bundled_default_map_matches_builtin() {
+        let map = parse_ascii_map(include_str!("../maps/default.txt")).unwrap();
+        let builtin = create_default_map();
+        assert_eq!(map.width, builtin.width);
+        assert_eq!(map.height, builtin.height);
+        assert_eq!(map.tiles, builtin.tiles);
+    }
+}
@@ -0,0 +1,294 @@
+use actix::prelude::*;
+use std::time::Duration;
+use crate::combat::{AttackOutcome, CombatError, ShotOutcome};
+use crate::direction::Direction;
+use crate::game::{Character, GameState, MoveError, MoveOutcome, PlayerInput};
+use crate::items::{InventoryError, InventoryView, ItemAction};
+use crate::protocol::{GameEvent, ProtocolError, ServerMessage};
+use crate::spawn::SpawnError;
+
+// The world runs as an actor that owns the GameState. HTTP handlers and
+// sockets talk to it by address with the messages below, and it advances
+// itself on a fixed-rate tick. With storage it also saves its characters
+// periodically and when it stops.
+impl Actor for GameState {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let interval = Duration::from_secs(1) / self.rules.tick_rate.max(1);
+        ctx.run_interval(interval, |game, _| game.tick());
+        if self.has_storage() && !self.rules.save_interval.is_zero() {
+            ctx.run_interval(self.rules.save_interval, |game, _| game.save_all());
+        }
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.save_all();
+    }
+}
+
+// Adds the player if they are new and counts a session for them. Answers
+// with a snapshot of the world.
+#[derive(Message)]
+#[rtype(result = "Result<ServerMessage, SpawnError>")]
+pub struct Join {
+    pub player_id: String,
+}
+
+// Adds the player without opening a session, e.g. when joining over REST.
+#[derive(Message)]
+#[rtype(result = "Result<Character, SpawnError>")]
+pub struct AddPlayer {
+    pub player_id: String,
+}
+
+// Moves a character right away, for request/response callers like `/move`.
+// These REST actions skip the tick's input queue, so they may land ahead of
+// socket inputs waiting for the next tick. Cooldowns hold for both alike and
+// the changes still go out with the next tick's delta.
+#[derive(Message)]
+#[rtype(result = "Result<(Character, MoveOutcome), MoveError>")]
+pub struct Move {
+    pub player_id: String,
+    pub direction: Direction,
+}
+
+// Attacks right away, for request/response callers like `/attack`.
+#[derive(Message)]
+#[rtype(result = "Result<AttackOutcome, CombatError>")]
+pub struct Attack {
+    pub player_id: String,
+    pub target: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<ShotOutcome, CombatError>")]
+pub struct Shoot {
+    pub player_id: String,
+    pub target: String,
+}
+
+// Picks up, drops or uses an item right away and answers the inventory.
+#[derive(Message)]
+#[rtype(result = "Result<InventoryView, InventoryError>")]
+pub struct InventoryAction {
+    pub player_id: String,
+    pub action: ItemAction,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ProtocolError>")]
+pub struct QueueInput {
+    pub player_id: String,
+    pub input: PlayerInput,
+    pub reply: Option<(Recipient<ServerMessage>, Option<u64>)>,
+}
+
+// Read-only access to the world, e.g.
+// `game.send(Query::new(|game| game.map.clone()))`.
+pub struct Query<R>(Box<dyn FnOnce(&GameState) -> R + Send>);
+
+impl<R> Query<R> {
+    pub fn new(query: impl FnOnce(&GameState) -> R + Send + 'static) -> Self {
+        Self(Box::new(query))
+    }
+}
+
+impl<R: Send + 'static> Message for Query<R> {
+    type Result = R;
+}
+
+// Registers a socket for deltas and events.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub client: Recipient<ServerMessage>,
+}
+
+// Sent when a socket closes; starts the reconnect grace period of its player.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub client: Recipient<ServerMessage>,
+    pub player_id: Option<String>,
+    // The server closed the socket because the client went silent.
+    pub reaped: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Broadcast(pub GameEvent);
+
+// Ends the game: connected sockets get an error and are closed, then the
+// actor stops.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub reason: String,
+}
+
+impl Handler<Join> for GameState {
+    type Result = Result<ServerMessage, SpawnError>;
+
+    fn handle(&mut self, msg: Join, _: &mut Self::Context) -> Self::Result {
+        self.add_player(msg.player_id.clone())?;
+        self.connect_player(&msg.player_id);
+        Ok(self.snapshot())
+    }
+}
+
+impl Handler<AddPlayer> for GameState {
+    type Result = Result<Character, SpawnError>;
+
+    fn handle(&mut self, msg: AddPlayer, _: &mut Self::Context) -> Self::Result {
+        self.add_player(msg.player_id).cloned()
+    }
+}
+
+impl Handler<Move> for GameState {
+    type Result = Result<(Character, MoveOutcome), MoveError>;
+
+    fn handle(&mut self, msg: Move, _: &mut Self::Context) -> Self::Result {
+        let outcome = self.move_character(&msg.player_id, msg.direction)?;
+        let character = self.get_character(&msg.player_id).cloned().ok_or(MoveError::UnknownPlayer)?;
+        Ok((character, outcome))
+    }
+}
+
+impl Handler<Attack> for GameState {
+    type Result = Result<AttackOutcome, CombatError>;
+
+    fn handle(&mut self, msg: Attack, _: &mut Self::Context) -> Self::Result {
+        self.attack(&msg.player_id, &msg.target)
+    }
+}
+
+impl Handler<Shoot> for GameState {
+    type Result = Result<ShotOutcome, CombatError>;
+
+    fn handle(&mut self, msg: Shoot, _: &mut Self::Context) -> Self::Result {
+        self.shoot(&msg.player_id, &msg.target)
+    }
+}
+
+impl Handler<InventoryAction> for GameState {
+    type Result = Result<InventoryView, InventoryError>;
+
+    fn handle(&mut self, msg: InventoryAction, _: &mut Self::Context) -> Self::Result {
+        self.apply_item_action(&msg.player_id, msg.action)?;
+        self.inventory(&msg.player_id).ok_or(InventoryError::UnknownPlayer)
+    }
+}
+
+impl Handler<QueueInput> for GameState {
+    type Result = Result<(), ProtocolError>;
+
+    fn handle(&mut self, msg: QueueInput, _: &mut Self::Context) -> Self::Result {
+        self.queue_input(&msg.player_id, msg.input, msg.reply)
+    }
+}
+
+impl<R: Send + 'static> Handler<Query<R>> for GameState {
+    type Result = MessageResult<Query<R>>;
+
+    fn handle(&mut self, msg: Query<R>, _: &mut Self::Context) -> Self::Result {
+        MessageResult((msg.0)(self))
+    }
+}
+
+impl Handler<Subscribe> for GameState {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) {
+        self.add_client(msg.client);
+    }
+}
+
+impl Handler<Unsubscribe> for GameState {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) {
+        self.remove_client(&msg.client);
+        if msg.reaped {
+            self.record_reaped_connection();
+        }
+        if let Some(player_id) = msg.player_id {
+            self.disconnect_player(&player_id);
+        }
+    }
+}
+
+impl Handler<Broadcast> for GameState {
+    type Result = ();
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Self::Context) {
+        self.broadcast(msg.0);
+    }
+}
+
+impl Handler<Shutdown> for GameState {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Self::Context) {
+        let error = ServerMessage::Error(ProtocolError::new("room_closed", msg.reason));
+        for client in &self.clients {
+            client.do_send(error.clone());
+        }
+        ctx.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::{GameRules, Map};
+    use crate::tile::Tile;
+
+    #[actix_rt::test]
+    async fn test_game_actor_applies_queued_inputs_on_tick() {
+        let game = GameState::new(Map::new(2, 1, vec![vec![Tile::Floor; 2]])).start();
+        let snapshot = game.send(Join { player_id: "player1".to_string() }).await.unwrap().unwrap();
+        assert!(matches!(snapshot, ServerMessage::Snapshot { players, .. } if players.contains_key("player1")));
+
+        let input = PlayerInput::Move(Direction::Right);
+        game.send(QueueInput { player_id: "player1".to_string(), input, reply: None }).await.unwrap().unwrap();
+        actix_rt::time::sleep(Duration::from_millis(150)).await;
+
+        let (ticks, x) = game.send(Query::new(|game| (game.ticks, game.get_character("player1").unwrap().x)))
+            .await
+            .unwrap();
+        assert!(ticks > 0);
+        assert_eq!(x, 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_rest_actions_skip_the_input_queue() {
+        let rules = GameRules { tick_rate: 1, move_cooldown: Duration::from_secs(60), ..GameRules::default() };
+        let game = GameState::with_rules(Map::new(3, 1, vec![vec![Tile::Floor; 3]]), rules).start();
+        game.send(Join { player_id: "player1".to_string() }).await.unwrap().unwrap();
+        let version = game.send(Query::new(|game| game.version)).await.unwrap();
+
+        let input = PlayerInput::Move(Direction::Right);
+        game.send(QueueInput { player_id: "player1".to_string(), input, reply: None }).await.unwrap().unwrap();
+        let moved = Move { player_id: "player1".to_string(), direction: Direction::Right };
+        assert_eq!(game.send(moved).await.unwrap().unwrap().0.x, 1);
+        // Applied before any tick, and not yet sent to anyone.
+        assert_eq!(game.send(Query::new(|game| (game.ticks, game.version))).await.unwrap(), (0, version));
+
+        let again = Move { player_id: "player1".to_string(), direction: Direction::Right };
+        assert!(matches!(game.send(again).await.unwrap(), Err(MoveError::OnCooldown { .. })));
+    }
+
+    #[actix_rt::test]
+    async fn test_game_actor_moves() {
+        let game = GameState::new(Map::new(2, 1, vec![vec![Tile::Floor; 2]])).start();
+        game.send(Join { player_id: "player1".to_string() }).await.unwrap().unwrap();
+
+        let moved = Move { player_id: "player1".to_string(), direction: Direction::Right };
+        assert_eq!(game.send(moved).await.unwrap().unwrap().0.x, 1);
+        let blocked = Move { player_id: "player1".to_string(), direction: Direction::Right };
+        assert_eq!(game.send(blocked).await.unwrap(), Err(MoveError::OutOfBounds));
+        let unknown = Move { player_id: "ghost".to_string(), direction: Direction::Left };
+        assert_eq!(game.send(unknown).await.unwrap(), Err(MoveError::UnknownPlayer));
+    }
+}
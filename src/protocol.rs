@@ -0,0 +1,246 @@
+use actix::{MailboxError, Message};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use crate::combat::CombatError;
+use crate::direction::Direction;
+use crate::game::{Character, Map, MoveError};
+use crate::items::{EquipmentSlot, GroundItems, InventoryError};
+use crate::spawn::SpawnError;
+use crate::stats::Stats;
+use crate::tile::Tile;
+
+// Wire format of the `/ws` endpoint. Every message is a JSON object tagged
+// with `type`. A client opens with `hello`, naming the protocol version it
+// speaks; the server answers with `welcome` and a `snapshot`, or with an
+// `error` and a close frame when the version is not supported. The player
+// is the one named by the session token the socket was opened with.
+//
+// After the snapshot the client only receives `delta` messages. Each carries
+// the state version it produces; a client that sees a gap sends `resync`
+// with the last version it applied and gets the missing deltas or, if they
+// are too old, a fresh snapshot.
+//
+// Bump PROTOCOL_VERSION for any incompatible change and keep
+// MIN_PROTOCOL_VERSION at the oldest version the server still understands.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+pub fn is_supported_version(version: u32) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+// Commands may carry a client-chosen `seq` that is echoed back in the `ack`.
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ClientEnvelope {
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello { protocol_version: u32 },
+    Move { direction: Direction },
+    Ping,
+    Resync {
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    Chat { text: String },
+    Action {
+        name: String,
+        #[serde(default)]
+        target: Option<String>,
+        // The item to pick up, drop or use.
+        #[serde(default)]
+        item: Option<String>,
+    },
+}
+
+#[derive(Message, Serialize, JsonSchema, Debug, Clone)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Welcome { protocol_version: u32, player_id: String },
+    Snapshot { version: u64, players: HashMap<String, Character>, map: Map, items: Vec<GroundItems> },
+    Delta { version: u64, changes: Vec<StateChange> },
+    Event(GameEvent),
+    Error(ProtocolError),
+    Ack {
+        seq: Option<u64>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<ProtocolError>,
+    },
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum StateChange {
+    PlayerJoined { player_id: String, character: Character },
+    PlayerMoved { player_id: String, x: i32, y: i32, health: i32 },
+    PlayerLeft { player_id: String },
+    TileChanged { x: i32, y: i32, tile: Tile },
+    HealthChanged { player_id: String, health: i32 },
+    StatsChanged { player_id: String, stats: Stats },
+    // Everything now lying on the tile, possibly nothing.
+    ItemsChanged { x: i32, y: i32, items: Vec<String> },
+    InventoryChanged { player_id: String, inventory: Vec<String> },
+    // Everything the player now has equipped, by slot.
+    EquipmentChanged { player_id: String, equipment: BTreeMap<EquipmentSlot, String> },
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum GameEvent {
+    Chat { from: String, text: String },
+    // The player's connection dropped; their character is kept for a while.
+    PlayerLeft { player_id: String },
+    PlayerReturned { player_id: String },
+    Damaged { attacker: String, target: String, damage: i32, health: i32 },
+    LevelUp { player_id: String, level: u32 },
+    // A shot was stopped by the tile at (x, y).
+    Missed { attacker: String, x: i32, y: i32 },
+    // `by` is the killer, if another character was to blame.
+    Died {
+        player_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        by: Option<String>,
+    },
+}
+
+#[derive(Serialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct ProtocolError {
+    pub code: String,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        Self { code: code.to_string(), message: message.into() }
+    }
+
+    pub fn incompatible_version(version: Option<u32>) -> Self {
+        let requested = version.map_or_else(|| "an unversioned protocol".to_string(), |v| format!("version {}", v));
+        Self::new(
+            "incompatible_protocol",
+            format!(
+                "Client speaks {}, server supports versions {} to {}",
+                requested, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        )
+    }
+}
+
+impl From<MoveError> for ProtocolError {
+    fn from(e: MoveError) -> Self {
+        Self::new(e.code(), e.to_string())
+    }
+}
+
+impl From<CombatError> for ProtocolError {
+    fn from(e: CombatError) -> Self {
+        Self::new(e.code(), e.to_string())
+    }
+}
+
+impl From<InventoryError> for ProtocolError {
+    fn from(e: InventoryError) -> Self {
+        Self::new(e.code(), e.to_string())
+    }
+}
+
+impl From<MailboxError> for ProtocolError {
+    fn from(_: MailboxError) -> Self {
+        Self::new("internal", "The game is not available")
+    }
+}
+
+impl From<SpawnError> for ProtocolError {
+    fn from(e: SpawnError) -> Self {
+        Self::new("no_free_spawn_point", e.to_string())
+    }
+}
+
+// JSON Schemas for both directions, served at `/protocol/schema` so clients
+// can validate their messages.
+pub fn json_schema() -> serde_json::Value {
+    serde_json::json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "min_protocol_version": MIN_PROTOCOL_VERSION,
+        "client": schemars::schema_for!(ClientEnvelope),
+        "server": schemars::schema_for!(ServerMessage),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_envelope_parsing() {
+        let envelope: ClientEnvelope = serde_json::from_str(r#"{"type": "move", "seq": 7, "direction": "left"}"#).unwrap();
+        assert_eq!(envelope.seq, Some(7));
+        assert_eq!(envelope.message, ClientMessage::Move { direction: Direction::Left });
+
+        let envelope: ClientEnvelope = serde_json::from_str(r#"{"type": "hello", "protocol_version": 3}"#).unwrap();
+        assert_eq!(envelope.message, ClientMessage::Hello { protocol_version: 3 });
+
+        let envelope: ClientEnvelope = serde_json::from_str(r#"{"type": "resync", "since": 12}"#).unwrap();
+        assert_eq!(envelope.message, ClientMessage::Resync { since: Some(12) });
+
+        assert!(serde_json::from_str::<ClientEnvelope>(r#"{"type": "teleport"}"#).is_err());
+        assert!(serde_json::from_str::<ClientEnvelope>(r#"{"playerId": "legacy"}"#).is_err());
+    }
+
+    #[test]
+    fn test_server_message_serialization() {
+        let ack = ServerMessage::Ack { seq: Some(3), ok: true, error: None };
+        assert_eq!(serde_json::to_value(&ack).unwrap(), serde_json::json!({"type": "ack", "seq": 3, "ok": true}));
+
+        let chat = ServerMessage::Event(GameEvent::Chat { from: "p1".to_string(), text: "hi".to_string() });
+        assert_eq!(
+            serde_json::to_value(&chat).unwrap(),
+            serde_json::json!({"type": "event", "event": "chat", "from": "p1", "text": "hi"})
+        );
+
+        let delta = ServerMessage::Delta {
+            version: 4,
+            changes: vec![StateChange::PlayerMoved { player_id: "p1".to_string(), x: 1, y: 2, health: 100 }],
+        };
+        assert_eq!(
+            serde_json::to_value(&delta).unwrap(),
+            serde_json::json!({
+                "type": "delta",
+                "version": 4,
+                "changes": [{"change": "player_moved", "player_id": "p1", "x": 1, "y": 2, "health": 100}],
+            })
+        );
+
+        let error = ServerMessage::Error(MoveError::OutOfBounds.into());
+        assert_eq!(serde_json::to_value(&error).unwrap()["code"], "out_of_bounds");
+    }
+
+    #[test]
+    fn test_supported_versions() {
+        assert!(is_supported_version(PROTOCOL_VERSION));
+        assert!(!is_supported_version(0));
+        assert!(!is_supported_version(PROTOCOL_VERSION + 1));
+        assert!(ProtocolError::incompatible_version(None).message.contains("unversioned"));
+    }
+
+    #[test]
+    fn test_json_schema_covers_all_messages() {
+        let schema = json_schema().to_string();
+        let names = [
+            "hello", "move", "ping", "resync", "chat", "action",
+            "welcome", "snapshot", "delta", "event", "error", "ack",
+        ];
+        for name in names {
+            assert!(schema.contains(&format!("\"{}\"", name)), "schema is missing '{}'", name);
+        }
+    }
+}
@@ -0,0 +1,66 @@
+// Small deterministic PRNG (SplitMix64). Map generation must give identical
+// output for a seed across platforms and dependency upgrades, so we keep the
+// algorithm in-tree instead of relying on an external crate's stream.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn from_entropy() -> Self {
+        Self::new(uuid::Uuid::new_v4().as_u64_pair().0)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform integer in `low..high`; `high` must be greater than `low`.
+    pub fn range(&mut self, low: i32, high: i32) -> i32 {
+        assert!(high > low, "empty range {}..{}", low, high);
+        let span = (high as i64 - low as i64) as u64;
+        (low as i64 + (self.next_u64() % span) as i64) as i32
+    }
+
+    pub fn index(&mut self, len: usize) -> usize {
+        assert!(len > 0, "cannot pick from an empty collection");
+        (self.next_u64() % len as u64) as usize
+    }
+
+    // True with the given probability in percent.
+    pub fn chance(&mut self, percent: u32) -> bool {
+        self.next_u64() % 100 < percent as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_is_deterministic() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let first: Vec<u64> = (0..5).map(|_| a.next_u64()).collect();
+        let second: Vec<u64> = (0..5).map(|_| b.next_u64()).collect();
+        assert_eq!(first, second);
+        assert_ne!(Rng::new(43).next_u64(), first[0]);
+    }
+
+    #[test]
+    fn test_rng_range_stays_in_bounds() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let value = rng.range(-3, 4);
+            assert!((-3..4).contains(&value));
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use actix::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::auth::unix_now;
+use crate::dungeon::{DungeonConfig, GenerateError, Generator, generate};
+use crate::game::{GameRules, GameState, Map};
+use crate::map_loader::{MapLoadError, load_map_file};
+use crate::messages::{Query, Shutdown};
+use crate::rng::Rng;
+use crate::save::{RoomSave, SAVE_FORMAT_VERSION, SaveError, WorldSave};
+use crate::storage::Storage;
+
+// The room every server starts with; `/ws` and `/move` use it when no
+// `room` is given.
+pub const DEFAULT_ROOM: &str = "default";
+// Generated rooms are capped so a single request cannot allocate a huge map.
+pub const MAX_GENERATED_SIZE: usize = 200;
+// Every room runs its own actor, so requests may only create so many.
+pub const MAX_ROOMS: usize = 64;
+
+// Where the map of a new room comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapChoice {
+    // The map the server was started with.
+    Default,
+    // A file in the server's maps directory, e.g. `arena.txt`.
+    File(String),
+    Generated { generator: Generator, width: usize, height: usize, seed: Option<u64> },
+}
+
+#[derive(Debug)]
+pub enum RoomError {
+    NotFound,
+    InvalidRequest(String),
+    InvalidMapName(String),
+    TooLarge { width: usize, height: usize },
+    Map(MapLoadError),
+    Generate(GenerateError),
+    // The default room serves every request that names no room.
+    DefaultRoom,
+    NotOwner,
+    TooManyRooms { limit: usize },
+}
+
+impl RoomError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            RoomError::NotFound => "room_not_found",
+            RoomError::InvalidRequest(_) => "invalid_request",
+            RoomError::InvalidMapName(_) => "invalid_map_name",
+            RoomError::TooLarge { .. } => "map_too_large",
+            RoomError::Map(_) => "invalid_map",
+            RoomError::Generate(_) => "generation_failed",
+            RoomError::DefaultRoom => "default_room",
+            RoomError::NotOwner => "not_room_owner",
+            RoomError::TooManyRooms { .. } => "too_many_rooms",
+        }
+    }
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::NotFound => write!(f, "Room not found"),
+            RoomError::InvalidRequest(message) => write!(f, "{}", message),
+            RoomError::InvalidMapName(name) => write!(f, "'{}' is not a map in the maps directory", name),
+            RoomError::TooLarge { width, height } => write!(
+                f,
+                "{}x{} is larger than the {}x{} limit",
+                width, height, MAX_GENERATED_SIZE, MAX_GENERATED_SIZE
+            ),
+            RoomError::Map(e) => write!(f, "Map could not be loaded: {}", e),
+            RoomError::Generate(e) => write!(f, "Map could not be generated: {}", e),
+            RoomError::DefaultRoom => write!(f, "The default room cannot be closed"),
+            RoomError::NotOwner => write!(f, "Only the player who created the room may close it"),
+            RoomError::TooManyRooms { limit } => write!(f, "There are already {} rooms, close one first", limit),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+impl From<MapLoadError> for RoomError {
+    fn from(e: MapLoadError) -> Self {
+        RoomError::Map(e)
+    }
+}
+
+impl From<GenerateError> for RoomError {
+    fn from(e: GenerateError) -> Self {
+        RoomError::Generate(e)
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub id: String,
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub players: usize,
+    // The player who created the room; rooms of the server have none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+struct Room {
+    info: RoomInfo,
+    game: Addr<GameState>,
+}
+
+// Owns every running game. Each room is its own GameState actor with its
+// own map, players and clients.
+pub struct RoomRegistry {
+    rooms: BTreeMap<String, Room>,
+    default_map: Map,
+    rules: GameRules,
+    maps_dir: PathBuf,
+    storage: Option<Arc<dyn Storage>>,
+}
+
+impl RoomRegistry {
+    pub fn new(default_map: Map, rules: GameRules, maps_dir: impl Into<PathBuf>) -> Self {
+        Self { rooms: BTreeMap::new(), default_map, rules, maps_dir: maps_dir.into(), storage: None }
+    }
+
+    // Rooms added from now on keep their characters in `storage`.
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = Some(storage);
+    }
+
+    // Starts `game` as the room `id`, replacing any room of that id.
+    pub fn add_room(&mut self, id: &str, name: &str, game: GameState) -> RoomInfo {
+        self.add_owned_room(id, name, None, game)
+    }
+
+    fn add_owned_room(&mut self, id: &str, name: &str, owner: Option<String>, mut game: GameState) -> RoomInfo {
+        if let Some(storage) = &self.storage
+            && !game.has_storage()
+        {
+            game.set_storage(storage.clone(), id);
+        }
+        let info = RoomInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            width: game.map.width,
+            height: game.map.height,
+            players: game.player_count(),
+            owner,
+        };
+        if let Some(old) = self.rooms.insert(id.to_string(), Room { info: info.clone(), game: game.start() }) {
+            old.game.do_send(Shutdown { reason: "The room was replaced".to_string() });
+        }
+        info
+    }
+
+    // Brings back the rooms of a save, replacing rooms of the same ids.
+    pub fn restore(&mut self, save: WorldSave) -> Result<(), SaveError> {
+        for room in save.rooms {
+            let game = GameState::restore(room.game, self.rules.clone())?;
+            self.add_owned_room(&room.id, &room.name, room.owner, game);
+        }
+        Ok(())
+    }
+
+    fn load_map(&self, choice: &MapChoice) -> Result<Map, RoomError> {
+        match choice {
+            MapChoice::Default => Ok(self.default_map.clone()),
+            MapChoice::File(name) => Ok(load_map_file(find_map(&self.maps_dir, name)?)?),
+            MapChoice::Generated { generator, width, height, seed } => {
+                if *width > MAX_GENERATED_SIZE || *height > MAX_GENERATED_SIZE {
+                    return Err(RoomError::TooLarge { width: *width, height: *height });
+                }
+                let seed = seed.unwrap_or_else(|| Rng::from_entropy().next_u64());
+                Ok(generate(&DungeonConfig::new(*generator, *width, *height, seed))?)
+            }
+        }
+    }
+}
+
+// The file of the map `name` in `maps_dir`, e.g. `arena` for `arena.txt`.
+pub fn find_map(maps_dir: &Path, name: &str) -> Result<PathBuf, RoomError> {
+    // Only plain file names, so requests cannot reach outside the maps directory.
+    let valid = !name.is_empty()
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+        && !name.starts_with('.');
+    if !valid {
+        return Err(RoomError::InvalidMapName(name.to_string()));
+    }
+    let mut path = maps_dir.join(name);
+    if path.extension().is_none() {
+        path.set_extension("txt");
+    }
+    if !path.is_file() {
+        return Err(RoomError::InvalidMapName(name.to_string()));
+    }
+    Ok(path)
+}
+
+impl Actor for RoomRegistry {
+    type Context = Context<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<RoomInfo, RoomError>")]
+pub struct CreateRoom {
+    pub name: Option<String>,
+    pub map: MapChoice,
+    // Who may close the room besides administrators.
+    pub owner: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<RoomInfo>")]
+pub struct ListRooms;
+
+#[derive(Message)]
+#[rtype(result = "Result<Addr<GameState>, RoomError>")]
+pub struct GetRoom {
+    pub id: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct CloseRoom {
+    pub id: String,
+    pub player_id: String,
+    // Administrators may close rooms they do not own.
+    pub admin: bool,
+}
+
+// Collects every room into a save, e.g. before shutting down.
+#[derive(Message)]
+#[rtype(result = "WorldSave")]
+pub struct SaveWorld;
+
+impl Handler<CreateRoom> for RoomRegistry {
+    type Result = Result<RoomInfo, RoomError>;
+
+    fn handle(&mut self, msg: CreateRoom, _: &mut Self::Context) -> Self::Result {
+        if self.rooms.len() >= MAX_ROOMS {
+            return Err(RoomError::TooManyRooms { limit: MAX_ROOMS });
+        }
+        let map = self.load_map(&msg.map)?;
+        let id = loop {
+            let id = uuid::Uuid::new_v4().simple().to_string()[..8].to_string();
+            if !self.rooms.contains_key(&id) {
+                break id;
+            }
+        };
+        let name = msg.name.unwrap_or_else(|| format!("Room {}", id));
+        Ok(self.add_owned_room(&id, &name, msg.owner, GameState::with_rules(map, self.rules.clone())))
+    }
+}
+
+impl Handler<ListRooms> for RoomRegistry {
+    type Result = ResponseFuture<Vec<RoomInfo>>;
+
+    fn handle(&mut self, _: ListRooms, _: &mut Self::Context) -> Self::Result {
+        let rooms: Vec<(RoomInfo, Addr<GameState>)> = self.rooms.values()
+            .map(|room| (room.info.clone(), room.game.clone()))
+            .collect();
+        Box::pin(async move {
+            let mut infos = Vec::with_capacity(rooms.len());
+            for (mut info, game) in rooms {
+                info.players = game.send(Query::new(|game| game.player_count())).await.unwrap_or(0);
+                infos.push(info);
+            }
+            infos
+        })
+    }
+}
+
+impl Handler<SaveWorld> for RoomRegistry {
+    type Result = ResponseFuture<WorldSave>;
+
+    fn handle(&mut self, _: SaveWorld, _: &mut Self::Context) -> Self::Result {
+        let rooms: Vec<(RoomInfo, Addr<GameState>)> = self.rooms.values()
+            .map(|room| (room.info.clone(), room.game.clone()))
+            .collect();
+        Box::pin(async move {
+            let mut saves = Vec::with_capacity(rooms.len());
+            for (info, game) in rooms {
+                // A room that stopped in the meantime has nothing left to save.
+                if let Ok(game) = game.send(Query::new(GameState::to_save)).await {
+                    saves.push(RoomSave { id: info.id, name: info.name, owner: info.owner, game });
+                }
+            }
+            WorldSave { format_version: SAVE_FORMAT_VERSION, saved_at: unix_now(), rooms: saves }
+        })
+    }
+}
+
+impl Handler<GetRoom> for RoomRegistry {
+    type Result = Result<Addr<GameState>, RoomError>;
+
+    fn handle(&mut self, msg: GetRoom, _: &mut Self::Context) -> Self::Result {
+        self.rooms.get(&msg.id).map(|room| room.game.clone()).ok_or(RoomError::NotFound)
+    }
+}
+
+impl Handler<CloseRoom> for RoomRegistry {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: CloseRoom, _: &mut Self::Context) -> Self::Result {
+        if msg.id == DEFAULT_ROOM {
+            return Err(RoomError::DefaultRoom);
+        }
+        let room = self.rooms.get(&msg.id).ok_or(RoomError::NotFound)?;
+        if !msg.admin && room.info.owner.as_deref() != Some(msg.player_id.as_str()) {
+            return Err(RoomError::NotOwner);
+        }
+        let room = self.rooms.remove(&msg.id).ok_or(RoomError::NotFound)?;
+        room.game.do_send(Shutdown { reason: "The room was closed".to_string() });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::create_default_map;
+
+    fn registry() -> RoomRegistry {
+        RoomRegistry::new(create_default_map(), GameRules::default(), "maps")
+    }
+
+    #[actix_rt::test]
+    async fn test_create_list_and_close_rooms() {
+        let mut rooms = registry();
+        rooms.add_room(DEFAULT_ROOM, "Default", GameState::new(create_default_map()));
+        let rooms = rooms.start();
+
+        let arena = rooms.send(create("Arena", MapChoice::File("arena".to_string()))).await.unwrap().unwrap();
+        assert_eq!(arena.name, "Arena");
+        let generated = MapChoice::Generated { generator: Generator::Caves, width: 30, height: 20, seed: Some(3) };
+        let cave = rooms.send(CreateRoom { name: None, map: generated, owner: None }).await.unwrap().unwrap();
+        assert_eq!((cave.width, cave.height), (30, 20));
+
+        let game = rooms.send(GetRoom { id: arena.id.clone() }).await.unwrap().unwrap();
+        game.send(crate::messages::Join { player_id: "player1".to_string() }).await.unwrap().unwrap();
+
+        let listed = rooms.send(ListRooms).await.unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(listed.iter().find(|room| room.id == arena.id).unwrap().players, 1);
+
+        rooms.send(close(&arena.id, "owner", false)).await.unwrap().unwrap();
+        assert!(matches!(rooms.send(GetRoom { id: arena.id.clone() }).await.unwrap(), Err(RoomError::NotFound)));
+        assert!(matches!(rooms.send(close(&arena.id, "owner", false)).await.unwrap(), Err(RoomError::NotFound)));
+        assert_eq!(rooms.send(ListRooms).await.unwrap().len(), 2);
+    }
+
+    fn create(name: &str, map: MapChoice) -> CreateRoom {
+        CreateRoom { name: Some(name.to_string()), map, owner: Some("owner".to_string()) }
+    }
+
+    fn close(id: &str, player_id: &str, admin: bool) -> CloseRoom {
+        CloseRoom { id: id.to_string(), player_id: player_id.to_string(), admin }
+    }
+
+    #[actix_rt::test]
+    async fn test_rooms_are_closed_by_their_owner_only() {
+        let mut rooms = registry();
+        rooms.add_room(DEFAULT_ROOM, "Default", GameState::new(create_default_map()));
+        let rooms = rooms.start();
+        let first = rooms.send(create("First", MapChoice::Default)).await.unwrap().unwrap();
+        let second = rooms.send(create("Second", MapChoice::Default)).await.unwrap().unwrap();
+
+        assert!(matches!(rooms.send(close(DEFAULT_ROOM, "owner", true)).await.unwrap(), Err(RoomError::DefaultRoom)));
+        assert!(matches!(rooms.send(close(&first.id, "stranger", false)).await.unwrap(), Err(RoomError::NotOwner)));
+        rooms.send(close(&first.id, "stranger", true)).await.unwrap().unwrap();
+        rooms.send(close(&second.id, "owner", false)).await.unwrap().unwrap();
+        assert_eq!(rooms.send(ListRooms).await.unwrap().len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_rooms_are_limited() {
+        let rooms = registry().start();
+        for _ in 0..MAX_ROOMS {
+            rooms.send(create("Room", MapChoice::Default)).await.unwrap().unwrap();
+        }
+        let err = rooms.send(create("One too many", MapChoice::Default)).await.unwrap().unwrap_err();
+        assert_eq!(err.code(), "too_many_rooms");
+    }
+
+    #[test]
+    fn test_map_choices_are_validated() {
+        let rooms = registry();
+        for name in ["../Cargo.toml", "/etc/passwd", ".hidden", "missing", ""] {
+            let err = rooms.load_map(&MapChoice::File(name.to_string())).unwrap_err();
+            assert_eq!(err.code(), "invalid_map_name", "{}", name);
+        }
+        assert_eq!(rooms.load_map(&MapChoice::File("arena.txt".to_string())).unwrap().width, 14);
+
+        let huge = MapChoice::Generated { generator: Generator::Bsp, width: 1000, height: 10, seed: None };
+        assert_eq!(rooms.load_map(&huge).unwrap_err().code(), "map_too_large");
+        let tiny = MapChoice::Generated { generator: Generator::Bsp, width: 2, height: 2, seed: None };
+        assert_eq!(rooms.load_map(&tiny).unwrap_err().code(), "generation_failed");
+    }
+
+    #[actix_rt::test]
+    async fn test_rooms_survive_a_save_and_restore() {
+        let mut rooms = registry();
+        rooms.add_room(DEFAULT_ROOM, "Default", GameState::new(create_default_map()));
+        let rooms = rooms.start();
+        let arena = rooms.send(create("Arena", MapChoice::File("arena".to_string()))).await.unwrap().unwrap();
+        let game = rooms.send(GetRoom { id: arena.id.clone() }).await.unwrap().unwrap();
+        game.send(crate::messages::Join { player_id: "player1".to_string() }).await.unwrap().unwrap();
+
+        let save = rooms.send(SaveWorld).await.unwrap();
+        assert_eq!(save.rooms.len(), 2);
+
+        let mut restored = registry();
+        restored.restore(save).unwrap();
+        let restored = restored.start();
+        let listed = restored.send(ListRooms).await.unwrap();
+        assert_eq!(listed.iter().find(|room| room.id == arena.id).unwrap(), &RoomInfo { players: 1, ..arena });
+    }
+}
@@ -0,0 +1,224 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use crate::game::{Character, Map};
+use crate::items::{GroundItems, ITEM_KIND};
+use crate::stats::Stats;
+
+// Bump SAVE_FORMAT_VERSION for any change to the saved layout and add a
+// migration from the previous format to MIGRATIONS, so that saves written
+// by older servers stay loadable.
+pub const SAVE_FORMAT_VERSION: u32 = 3;
+
+// MIGRATIONS[n] upgrades a save of format n + 1 to format n + 2.
+type Migration = fn(&mut Value);
+const MIGRATIONS: [Migration; SAVE_FORMAT_VERSION as usize - 1] = [add_stats, add_ground_items];
+
+// Format 2 gave characters stats. Older characters start out at level 1,
+// with room for the health they were saved with.
+fn add_stats(save: &mut Value) {
+    let rooms = save.get_mut("rooms").and_then(Value::as_array_mut).into_iter().flatten();
+    for room in rooms {
+        let players = room.get_mut("game")
+            .and_then(|game| game.get_mut("players"))
+            .and_then(Value::as_object_mut);
+        for character in players.into_iter().flat_map(|players| players.values_mut()) {
+            let Some(character) = character.as_object_mut() else {
+                continue;
+            };
+            let health = character.get("health").and_then(Value::as_i64).unwrap_or(0);
+            let mut stats = Stats::default();
+            stats.max_health = stats.max_health.max(i32::try_from(health).unwrap_or(i32::MAX));
+            character.insert("stats".to_string(), serde_json::to_value(stats).expect("stats serialize"));
+        }
+    }
+}
+
+// Format 3 saved the items lying on the map. Older saves get the items the
+// map placed.
+fn add_ground_items(save: &mut Value) {
+    let rooms = save.get_mut("rooms").and_then(Value::as_array_mut).into_iter().flatten();
+    for room in rooms {
+        let Some(game) = room.get_mut("game").and_then(Value::as_object_mut) else {
+            continue;
+        };
+        let objects = game.get("map").and_then(|map| map.get("objects")).and_then(Value::as_array);
+        let items: Vec<Value> = objects.into_iter().flatten()
+            .filter(|object| object.get("kind").and_then(Value::as_str) == Some(ITEM_KIND))
+            .map(|object| serde_json::json!({"x": object["x"], "y": object["y"], "items": [object["name"]]}))
+            .collect();
+        game.insert("items".to_string(), Value::Array(items));
+    }
+}
+
+// Everything needed to bring the world back after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorldSave {
+    pub format_version: u32,
+    // Seconds since the Unix epoch.
+    pub saved_at: u64,
+    pub rooms: Vec<RoomSave>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSave {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub game: GameSave,
+}
+
+// The persistent part of a GameState. Clients, sessions and queued inputs
+// do not survive a restart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameSave {
+    pub map: Map,
+    pub players: BTreeMap<String, Character>,
+    pub items: Vec<GroundItems>,
+    pub ticks: u64,
+    pub version: u64,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    MissingVersion,
+    UnsupportedVersion(u32),
+    Invalid(String),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::Format(e) => write!(f, "Save is not valid JSON: {}", e),
+            SaveError::MissingVersion => write!(f, "Save has no format_version"),
+            SaveError::UnsupportedVersion(version) => write!(
+                f,
+                "Save format {} is not supported; this server reads formats 1 to {}",
+                version, SAVE_FORMAT_VERSION
+            ),
+            SaveError::Invalid(message) => write!(f, "Save is inconsistent: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(e: serde_json::Error) -> Self {
+        SaveError::Format(e)
+    }
+}
+
+// Reads a save of any supported format, migrating it to the current one.
+pub fn parse_save(data: &str) -> Result<WorldSave, SaveError> {
+    let mut value: Value = serde_json::from_str(data)?;
+    let version = value.get("format_version").and_then(Value::as_u64).ok_or(SaveError::MissingVersion)?;
+    let version = u32::try_from(version).unwrap_or(u32::MAX);
+    if version == 0 || version > SAVE_FORMAT_VERSION {
+        return Err(SaveError::UnsupportedVersion(version));
+    }
+    for migrate in &MIGRATIONS[version as usize - 1..] {
+        migrate(&mut value);
+    }
+    value["format_version"] = SAVE_FORMAT_VERSION.into();
+    Ok(serde_json::from_value(value)?)
+}
+
+pub fn read_save(path: &Path) -> Result<WorldSave, SaveError> {
+    parse_save(&fs::read_to_string(path)?)
+}
+
+// Writes next to the target first so a crash cannot leave half a save.
+pub fn write_save(path: &Path, save: &WorldSave) -> Result<(), SaveError> {
+    let partial = path.with_extension("partial");
+    fs::write(&partial, serde_json::to_string_pretty(save)?)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::Tile;
+
+    fn world() -> WorldSave {
+        let mut players = BTreeMap::new();
+        players.insert("player1".to_string(), Character::new(1, 0, 80));
+        WorldSave {
+            format_version: SAVE_FORMAT_VERSION,
+            saved_at: 1000,
+            rooms: vec![RoomSave {
+                id: "default".to_string(),
+                name: "Default".to_string(),
+                owner: None,
+                game: GameSave {
+                    map: Map::new(2, 1, vec![vec![Tile::Floor; 2]]),
+                    players,
+                    items: vec![GroundItems { x: 0, y: 0, items: vec!["health_potion".to_string()] }],
+                    ticks: 42,
+                    version: 7,
+                },
+            }],
+        }
+    }
+
+    #[test]
+    fn test_saves_round_trip_through_files() {
+        let path = std::env::temp_dir().join(format!("world-{}.json", uuid::Uuid::new_v4()));
+        write_save(&path, &world()).unwrap();
+        let save = read_save(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(save.format_version, SAVE_FORMAT_VERSION);
+        let game = &save.rooms[0].game;
+        assert_eq!((game.ticks, game.version, game.map.width), (42, 7, 2));
+        assert_eq!(game.players["player1"], Character::new(1, 0, 80));
+        assert_eq!(game.items[0].items, vec!["health_potion".to_string()]);
+    }
+
+    #[test]
+    fn test_save_versions_are_checked() {
+        let mut value = serde_json::to_value(world()).unwrap();
+        value["format_version"] = (SAVE_FORMAT_VERSION + 1).into();
+        let err = parse_save(&value.to_string()).unwrap_err();
+        assert!(matches!(err, SaveError::UnsupportedVersion(version) if version == SAVE_FORMAT_VERSION + 1));
+
+        value.as_object_mut().unwrap().remove("format_version");
+
+        assert!(matches!(parse_save(&value.to_string()), Err(SaveError::MissingVersion)));
+        assert!(matches!(parse_save("{"), Err(SaveError::Format(_))));
+    }
+
+    #[test]
+    fn test_old_saves_are_migrated() {
+        let mut value = serde_json::to_value(world()).unwrap();
+        value["format_version"] = 1.into();
+        let players = value["rooms"][0]["game"]["players"].as_object_mut().unwrap();
+        players.get_mut("player1").unwrap().as_object_mut().unwrap().remove("stats");
+        players.insert("player2".to_string(), serde_json::json!({"x": 0, "y": 0, "health": 150}));
+        let game = &mut value["rooms"][0]["game"];
+        game.as_object_mut().unwrap().remove("items");
+        game["map"]["objects"] = serde_json::json!([{"kind": "item", "name": "rusty_key", "x": 1, "y": 0}]);
+
+        let save = parse_save(&value.to_string()).unwrap();
+        assert_eq!(save.format_version, SAVE_FORMAT_VERSION);
+        let players = &save.rooms[0].game.players;
+        assert_eq!(players["player1"], Character::new(1, 0, 80));
+        assert_eq!((players["player2"].health, players["player2"].stats.max_health), (150, 150));
+        assert_eq!(players["player2"].stats.level, 1);
+        assert_eq!(save.rooms[0].game.items, vec![GroundItems { x: 1, y: 0, items: vec!["rusty_key".to_string()] }]);
+    }
+}
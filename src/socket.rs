@@ -0,0 +1,373 @@
+use actix::prelude::*;
+use actix_web_actors::ws;
+use std::time::{Duration, Instant};
+use crate::game::{GameState, PlayerInput};
+use crate::items::ItemAction;
+use crate::messages::{Broadcast, Join, Query, QueueInput, Subscribe, Unsubscribe};
+use crate::protocol::{
+    ClientEnvelope, ClientMessage, GameEvent, PROTOCOL_VERSION, ProtocolError, ServerMessage, is_supported_version,
+};
+
+pub const MAX_CHAT_LENGTH: usize = 500;
+
+// What to send back for a client message: replies ahead of an immediate ack,
+// or nothing yet because the ack follows once a tick applied the input.
+#[derive(Debug)]
+pub enum Response {
+    Now(Vec<ServerMessage>),
+    Queued,
+}
+
+// What a socket knows about its player, kept apart from the websocket
+// context so that commands can be driven without a connection.
+pub struct Session {
+    pub game: Addr<GameState>,
+    // The player the socket's session token was issued to.
+    pub authenticated: String,
+    // Set once the handshake joined the player to the game.
+    pub player_id: Option<String>,
+    // Where queued inputs send their ack once a tick applied them.
+    pub reply_to: Option<Recipient<ServerMessage>>,
+}
+
+impl Session {
+    pub fn new(game: Addr<GameState>, authenticated: String) -> Self {
+        Self { game, authenticated, player_id: None, reply_to: None }
+    }
+
+    fn joined_player(&self) -> Result<String, ProtocolError> {
+        self.player_id.clone().ok_or_else(|| ProtocolError::new("handshake_required", "Send hello first"))
+    }
+
+    // Applies one client message, or queues it for the next tick.
+    pub async fn handle(&mut self, seq: Option<u64>, message: ClientMessage) -> Result<Response, ProtocolError> {
+        match message {
+            ClientMessage::Hello { protocol_version } => {
+                if !is_supported_version(protocol_version) {
+                    return Err(ProtocolError::incompatible_version(Some(protocol_version)));
+                }
+                let player_id = self.authenticated.clone();
+                let snapshot = match &self.player_id {
+                    Some(_) => self.game.send(Query::new(GameState::snapshot)).await?,
+                    None => self.game.send(Join { player_id: player_id.clone() }).await??,
+                };
+                self.player_id = Some(player_id.clone());
+                Ok(Response::Now(vec![ServerMessage::Welcome { protocol_version: PROTOCOL_VERSION, player_id }, snapshot]))
+            }
+            ClientMessage::Move { direction } => {
+                let player_id = self.joined_player()?;
+                let reply = self.reply_to.clone().map(|recipient| (recipient, seq));
+                self.game.send(QueueInput { player_id, input: PlayerInput::Move(direction), reply }).await??;
+                Ok(Response::Queued)
+            }
+            ClientMessage::Ping => Ok(Response::Now(Vec::new())),
+            ClientMessage::Resync { since } => {
+                self.joined_player()?;
+                let replies = self.game.send(Query::new(move |game| {
+                    since.and_then(|since| game.deltas_since(since)).unwrap_or_else(|| vec![game.snapshot()])
+                })).await?;
+                Ok(Response::Now(replies))
+            }
+            ClientMessage::Chat { text } => {
+                let player_id = self.joined_player()?;
+                let text = text.trim();
+                if text.is_empty() {
+                    return Err(ProtocolError::new("empty_message", "Chat messages cannot be empty"));
+                }
+                if text.chars().count() > MAX_CHAT_LENGTH {
+                    return Err(ProtocolError::new(
+                        "message_too_long",
+                        format!("Chat messages are limited to {} characters", MAX_CHAT_LENGTH),
+                    ));
+                }
+                self.game.do_send(Broadcast(GameEvent::Chat { from: player_id, text: text.to_string() }));
+                Ok(Response::Now(Vec::new()))
+            }
+            ClientMessage::Action { name, target, item } => {
+                let player_id = self.joined_player()?;
+                let input = match name.as_str() {
+                    "attack" => PlayerInput::Attack { target: require_target(target)? },
+                    "shoot" => PlayerInput::Shoot { target: require_target(target)? },
+                    "pick_up" => PlayerInput::Item(ItemAction::PickUp(item)),
+                    "drop" => PlayerInput::Item(ItemAction::Drop(require_item(item)?)),
+                    "use" => PlayerInput::Item(ItemAction::Use(require_item(item)?)),
+                    "equip" => PlayerInput::Item(ItemAction::Equip(require_item(item)?)),
+                    "unequip" => PlayerInput::Item(ItemAction::Unequip(require_item(item)?)),
+                    _ => return Err(ProtocolError::new("unknown_action", format!("Unknown action '{}'", name))),
+                };
+                let reply = self.reply_to.clone().map(|recipient| (recipient, seq));
+                self.game.send(QueueInput { player_id, input, reply }).await??;
+                Ok(Response::Queued)
+            }
+        }
+    }
+}
+
+fn require_target(target: Option<String>) -> Result<String, ProtocolError> {
+    target.ok_or_else(|| ProtocolError::new("missing_target", "Attacks need a target"))
+}
+
+fn require_item(item: Option<String>) -> Result<String, ProtocolError> {
+    item.ok_or_else(|| ProtocolError::new("missing_item", "This action needs an item"))
+}
+
+pub struct GameWebSocket {
+    game: Addr<GameState>,
+    authenticated: String,
+    player_id: Option<String>,
+    // When anything, including a pong, last arrived from the client.
+    last_heartbeat: Instant,
+    reaped: bool,
+}
+
+impl GameWebSocket {
+    pub fn new(game: Addr<GameState>, authenticated: String) -> Self {
+        Self { game, authenticated, player_id: None, last_heartbeat: Instant::now(), reaped: false }
+    }
+
+    pub fn is_timed_out(&self, now: Instant, timeout: Duration) -> bool {
+        now.duration_since(self.last_heartbeat) > timeout
+    }
+
+    fn heartbeat(&self, interval: Duration, timeout: Duration, ctx: &mut <Self as Actor>::Context) {
+        ctx.run_interval(interval, move |socket, ctx| {
+            if socket.is_timed_out(Instant::now(), timeout) {
+                // Stopping runs the regular disconnect path in `stopped`.
+                socket.reaped = true;
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+
+    fn handle_envelope(&mut self, envelope: ClientEnvelope, ctx: &mut <Self as Actor>::Context) {
+        let seq = envelope.seq;
+        let mut session = Session {
+            game: self.game.clone(),
+            authenticated: self.authenticated.clone(),
+            player_id: self.player_id.clone(),
+            reply_to: Some(ctx.address().recipient()),
+        };
+        // Waiting keeps the client's messages in order.
+        ctx.wait(
+            async move {
+                let result = session.handle(seq, envelope.message).await;
+                (session.player_id, result)
+            }
+            .into_actor(self)
+            .map(move |(player_id, result), socket, ctx| {
+                socket.player_id = player_id;
+                match result {
+                    Ok(Response::Queued) => {}
+                    Ok(Response::Now(replies)) => {
+                        for reply in &replies {
+                            socket.send(reply, ctx);
+                        }
+                        socket.send(&ServerMessage::Ack { seq, ok: true, error: None }, ctx);
+                    }
+                    Err(error) if error.code == "incompatible_protocol" => socket.reject(error, ctx),
+                    Err(error) => socket.send(&ServerMessage::Ack { seq, ok: false, error: Some(error) }, ctx),
+                }
+            }),
+        );
+    }
+
+    fn send(&self, message: &ServerMessage, ctx: &mut <Self as Actor>::Context) {
+        if let Ok(data) = serde_json::to_string(message) {
+            ctx.text(data);
+        }
+    }
+
+    // Clients that fail the handshake, or whose game ends, get an error
+    // message and a close frame instead of a half-working session.
+    fn reject(&self, error: ProtocolError, ctx: &mut <Self as Actor>::Context) {
+        let description = error.message.clone();
+        self.send(&ServerMessage::Error(error), ctx);
+        ctx.close(Some(ws::CloseReason { code: ws::CloseCode::Policy, description: Some(description) }));
+        ctx.stop();
+    }
+}
+
+impl Actor for GameWebSocket {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.game.do_send(Subscribe { client: ctx.address().recipient() });
+        let timing = Query::new(|game: &GameState| (game.rules.heartbeat_interval, game.rules.client_timeout));
+        ctx.wait(self.game.send(timing).into_actor(self).map(|timing, socket, ctx| match timing {
+            Ok((interval, timeout)) => socket.heartbeat(interval, timeout, ctx),
+            Err(_) => ctx.stop(),
+        }));
+    }
+
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        self.game.do_send(Unsubscribe {
+            client: ctx.address().recipient(),
+            player_id: self.player_id.clone(),
+            reaped: self.reaped,
+        });
+    }
+}
+
+impl Handler<ServerMessage> for GameWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) {
+        match msg {
+            // The game ended, e.g. because its room was closed.
+            ServerMessage::Error(error) => self.reject(error, ctx),
+            // Broadcasts are only meaningful after the handshake.
+            msg if self.player_id.is_some() => self.send(&msg, ctx),
+            _ => {}
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GameWebSocket {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if msg.is_ok() {
+            self.last_heartbeat = Instant::now();
+        }
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Text(text)) => match serde_json::from_str::<ClientEnvelope>(&text) {
+                Ok(envelope) => self.handle_envelope(envelope, ctx),
+                Err(_) if self.player_id.is_none() => {
+                    let version = serde_json::from_str::<serde_json::Value>(&text).ok()
+                        .and_then(|value| value.get("protocol_version")?.as_u64())
+                        .map(|version| version as u32);
+                    self.reject(ProtocolError::incompatible_version(version), ctx);
+                }
+                Err(e) => {
+                    let error = ProtocolError::new("invalid_message", e.to_string());
+                    self.send(&ServerMessage::Ack { seq: None, ok: false, error: Some(error) }, ctx);
+                }
+            },
+            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direction::Direction;
+    use crate::game::{MAX_QUEUED_INPUTS, Map};
+    use crate::tile::Tile;
+
+    fn test_session(map: Map) -> Session {
+        Session::new(GameState::new(map).start(), "player1".to_string())
+    }
+
+    fn hello() -> ClientMessage {
+        ClientMessage::Hello { protocol_version: PROTOCOL_VERSION }
+    }
+
+    fn replies(response: Response) -> Vec<ServerMessage> {
+        match response {
+            Response::Now(replies) => replies,
+            Response::Queued => panic!("expected an immediate response"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_hello_handshake() {
+        let mut session = test_session(Map::new(2, 1, vec![vec![Tile::Floor, Tile::Floor]]));
+
+        assert!(replies(session.handle(None, ClientMessage::Ping).await.unwrap()).is_empty());
+        let err = session.handle(None, ClientMessage::Move { direction: Direction::Right }).await.unwrap_err();
+        assert_eq!(err.code, "handshake_required");
+
+        let err = session.handle(None, ClientMessage::Hello { protocol_version: PROTOCOL_VERSION + 1 }).await.unwrap_err();
+        assert_eq!(err.code, "incompatible_protocol");
+        assert!(session.player_id.is_none());
+
+        let joined = replies(session.handle(None, hello()).await.unwrap());
+        assert!(matches!(&joined[0], ServerMessage::Welcome { player_id, .. } if player_id == "player1"));
+        assert!(matches!(&joined[1], ServerMessage::Snapshot { players, .. } if players.contains_key("player1")));
+
+        // A repeated hello only resends the snapshot.
+        let again = replies(session.handle(None, hello()).await.unwrap());
+        assert!(matches!(&again[1], ServerMessage::Snapshot { players, .. } if players.len() == 1));
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_moves_wait_for_the_tick() {
+        let mut session = test_session(Map::new(2, 1, vec![vec![Tile::Floor, Tile::Floor]]));
+        session.handle(None, hello()).await.unwrap();
+
+        let response = session.handle(None, ClientMessage::Move { direction: Direction::Right }).await.unwrap();
+        assert!(matches!(response, Response::Queued));
+        actix_rt::time::sleep(Duration::from_millis(150)).await;
+        let x = session.game.send(Query::new(|game| game.get_character("player1").unwrap().x)).await.unwrap();
+        assert_eq!(x, 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_input_queue_is_bounded() {
+        let mut session = test_session(Map::new(2, 1, vec![vec![Tile::Floor, Tile::Floor]]));
+        session.handle(None, hello()).await.unwrap();
+
+        let mut results = Vec::new();
+        for _ in 0..MAX_QUEUED_INPUTS * 2 {
+            results.push(session.handle(None, ClientMessage::Move { direction: Direction::Left }).await);
+        }
+        assert!(results.iter().any(|result| matches!(result, Err(e) if e.code == "input_queue_full")));
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_resync() {
+        let mut session = test_session(Map::new(2, 1, vec![vec![Tile::Floor, Tile::Floor]]));
+        session.handle(None, hello()).await.unwrap();
+        actix_rt::time::sleep(Duration::from_millis(150)).await;
+
+        let resync = session.handle(None, ClientMessage::Resync { since: Some(0) }).await.unwrap();
+        assert!(matches!(replies(resync)[..], [ServerMessage::Delta { version: 1, .. }]));
+
+        let resync = session.handle(None, ClientMessage::Resync { since: None }).await.unwrap();
+        assert!(matches!(replies(resync)[..], [ServerMessage::Snapshot { version: 1, .. }]));
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_chat_and_action_validation() {
+        let mut session = test_session(Map::new(1, 1, vec![vec![Tile::Floor]]));
+        session.handle(None, hello()).await.unwrap();
+
+        assert!(session.handle(None, ClientMessage::Chat { text: "hello".to_string() }).await.is_ok());
+        let err = session.handle(None, ClientMessage::Chat { text: "   ".to_string() }).await.unwrap_err();
+        assert_eq!(err.code, "empty_message");
+        let long = ClientMessage::Chat { text: "x".repeat(MAX_CHAT_LENGTH + 1) };
+        assert_eq!(session.handle(None, long).await.unwrap_err().code, "message_too_long");
+
+        let action = ClientMessage::Action { name: "dance".to_string(), target: None, item: None };
+        let err = session.handle(None, action).await.unwrap_err();
+        assert_eq!(err.code, "unknown_action");
+        let attack = ClientMessage::Action { name: "attack".to_string(), target: None, item: None };
+        assert_eq!(session.handle(None, attack).await.unwrap_err().code, "missing_target");
+        let attack = ClientMessage::Action { name: "attack".to_string(), target: Some("ghost".to_string()), item: None };
+        assert!(matches!(session.handle(None, attack).await.unwrap(), Response::Queued));
+        let shot = ClientMessage::Action { name: "shoot".to_string(), target: None, item: None };
+        assert_eq!(session.handle(None, shot).await.unwrap_err().code, "missing_target");
+        let drop = ClientMessage::Action { name: "drop".to_string(), target: None, item: None };
+        assert_eq!(session.handle(None, drop).await.unwrap_err().code, "missing_item");
+        let equip = ClientMessage::Action { name: "equip".to_string(), target: None, item: None };
+        assert_eq!(session.handle(None, equip).await.unwrap_err().code, "missing_item");
+        let pick_up = ClientMessage::Action { name: "pick_up".to_string(), target: None, item: None };
+        assert!(matches!(session.handle(None, pick_up).await.unwrap(), Response::Queued));
+    }
+
+    #[actix_rt::test]
+    async fn test_websocket_idle_timeout() {
+        let game = GameState::new(Map::new(1, 1, vec![vec![Tile::Floor]])).start();
+        let socket = GameWebSocket::new(game, "player1".to_string());
+        let timeout = Duration::from_secs(15);
+        assert!(!socket.is_timed_out(Instant::now(), timeout));
+        assert!(socket.is_timed_out(Instant::now() + Duration::from_secs(16), timeout));
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::game::Map;
+use crate::rng::Rng;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpawnPolicy {
+    #[default]
+    RoundRobin,
+    Random,
+    FarthestFromEnemies,
+}
+
+impl FromStr for SpawnPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "round_robin" | "round-robin" => Ok(SpawnPolicy::RoundRobin),
+            "random" => Ok(SpawnPolicy::Random),
+            "farthest" | "farthest_from_enemies" => Ok(SpawnPolicy::FarthestFromEnemies),
+            _ => Err(format!("unknown spawn policy '{}', expected round_robin, random or farthest", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpawnError {
+    NoFreeSpawnPoint,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::NoFreeSpawnPoint => write!(f, "every spawn point is blocked or occupied"),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+// Chooses spawn tiles for new characters. Maps without any spawn tiles fall
+// back to every walkable tile in row-major order.
+#[derive(Debug, Clone)]
+pub struct SpawnSelector {
+    pub policy: SpawnPolicy,
+    rng: Rng,
+    cursor: usize,
+}
+
+impl SpawnSelector {
+    pub fn new(policy: SpawnPolicy, rng: Rng) -> Self {
+        Self { policy, rng, cursor: 0 }
+    }
+
+    pub fn choose(&mut self, map: &Map, occupied: &[(i32, i32)]) -> Result<(i32, i32), SpawnError> {
+        let mut candidates = map.spawn_points();
+        if candidates.is_empty() {
+            candidates = map.walkable_positions();
+        }
+        let is_free = |position: &(i32, i32)| map.is_walkable(position.0, position.1) && !occupied.contains(position);
+
+        match self.policy {
+            SpawnPolicy::RoundRobin => {
+                let count = candidates.len();
+                let offset = (0..count)
+                    .find(|offset| is_free(&candidates[(self.cursor + offset) % count]))
+                    .ok_or(SpawnError::NoFreeSpawnPoint)?;
+                let index = (self.cursor + offset) % count;
+                self.cursor = index + 1;
+                Ok(candidates[index])
+            }
+            SpawnPolicy::Random => {
+                let free: Vec<(i32, i32)> = candidates.into_iter().filter(is_free).collect();
+                if free.is_empty() {
+                    return Err(SpawnError::NoFreeSpawnPoint);
+                }
+                Ok(free[self.rng.index(free.len())])
+            }
+            SpawnPolicy::FarthestFromEnemies => candidates.into_iter()
+                .filter(is_free)
+                .map(|(x, y)| {
+                    let nearest = occupied.iter()
+                        .map(|&(ox, oy)| (ox - x).abs() + (oy - y).abs())
+                        .min()
+                        .unwrap_or(i32::MAX);
+                    ((x, y), nearest)
+                })
+                .fold(None, |best: Option<((i32, i32), i32)>, candidate| match best {
+                    Some(best) if best.1 >= candidate.1 => Some(best),
+                    _ => Some(candidate),
+                })
+                .map(|(position, _)| position)
+                .ok_or(SpawnError::NoFreeSpawnPoint),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::Tile;
+
+    fn arena() -> Map {
+        // Spawns in the four corners of a 5x5 room.
+        let mut tiles = vec![vec![Tile::Floor; 5]; 5];
+        for (x, y) in [(0, 0), (4, 0), (0, 4), (4, 4)] {
+            tiles[y][x] = Tile::Spawn;
+        }
+        Map::new(5, 5, tiles)
+    }
+
+    #[test]
+    fn test_round_robin_skips_occupied_spawns() {
+        let map = arena();
+        let mut selector = SpawnSelector::new(SpawnPolicy::RoundRobin, Rng::new(1));
+        assert_eq!(selector.choose(&map, &[]), Ok((0, 0)));
+        assert_eq!(selector.choose(&map, &[(4, 0)]), Ok((0, 4)));
+        assert_eq!(selector.choose(&map, &[]), Ok((4, 4)));
+        assert_eq!(selector.choose(&map, &[]), Ok((0, 0)));
+    }
+
+    #[test]
+    fn test_random_only_picks_free_spawns() {
+        let map = arena();
+        let mut selector = SpawnSelector::new(SpawnPolicy::Random, Rng::new(1));
+        for _ in 0..20 {
+            assert_eq!(selector.choose(&map, &[(0, 0), (4, 0), (0, 4)]), Ok((4, 4)));
+        }
+    }
+
+    #[test]
+    fn test_farthest_from_enemies() {
+        let map = arena();
+        let mut selector = SpawnSelector::new(SpawnPolicy::FarthestFromEnemies, Rng::new(1));
+        assert_eq!(selector.choose(&map, &[(0, 0)]), Ok((4, 4)));
+        assert_eq!(selector.choose(&map, &[(4, 4), (3, 0)]), Ok((0, 4)));
+    }
+
+    #[test]
+    fn test_no_free_spawn_point_is_reported() {
+        let map = arena();
+        let occupied = [(0, 0), (4, 0), (0, 4), (4, 4)];
+        for policy in [SpawnPolicy::RoundRobin, SpawnPolicy::Random, SpawnPolicy::FarthestFromEnemies] {
+            let mut selector = SpawnSelector::new(policy, Rng::new(1));
+            assert_eq!(selector.choose(&map, &occupied), Err(SpawnError::NoFreeSpawnPoint));
+        }
+    }
+
+    #[test]
+    fn test_maps_without_spawns_use_walkable_tiles() {
+        let map = Map::new(2, 2, vec![vec![Tile::Wall, Tile::Floor], vec![Tile::Water, Tile::Floor]]);
+        let mut selector = SpawnSelector::new(SpawnPolicy::RoundRobin, Rng::new(1));
+        assert_eq!(selector.choose(&map, &[]), Ok((1, 0)));
+        assert_eq!(selector.choose(&map, &[(1, 0)]), Ok((1, 1)));
+        assert_eq!(selector.choose(&map, &[(1, 0), (1, 1)]), Err(SpawnError::NoFreeSpawnPoint));
+    }
+}
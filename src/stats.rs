@@ -0,0 +1,164 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// The built-in level curve. `GAME_LEVELS` points at a file of the same shape.
+const DEFAULT_LEVELS: &str = include_str!("../data/levels.json");
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub max_health: i32,
+    pub attack: i32,
+    pub defense: i32,
+    // In percent of the normal speed; moves wait `move_cooldown * 100 / speed`.
+    pub speed: i32,
+    pub level: u32,
+    // Gained in total, not since the last level.
+    pub experience: u64,
+}
+
+// A fresh level 1 character. Characters saved before stats existed get
+// these as well.
+impl Default for Stats {
+    fn default() -> Self {
+        Self { max_health: 100, attack: 10, defense: 2, speed: 100, level: 1, experience: 0 }
+    }
+}
+
+// What reaching a level adds to the stats, once the character has gained
+// `experience` in total.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Level {
+    pub experience: u64,
+    #[serde(default)]
+    pub max_health: i32,
+    #[serde(default)]
+    pub attack: i32,
+    #[serde(default)]
+    pub defense: i32,
+    #[serde(default)]
+    pub speed: i32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LevelCurve {
+    // Experience for a kill, times the level of the one killed.
+    pub kill_experience: u64,
+    // The levels after the first: `levels[0]` is level 2, and so on.
+    pub levels: Vec<Level>,
+}
+
+impl Default for LevelCurve {
+    fn default() -> Self {
+        LevelCurve::parse(DEFAULT_LEVELS).expect("the built-in level curve is valid")
+    }
+}
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io(std::io::Error),
+    Format(serde_json::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Io(e) => write!(f, "failed to read levels: {}", e),
+            LevelError::Format(e) => write!(f, "levels are not valid JSON: {}", e),
+            LevelError::Invalid(message) => write!(f, "invalid levels: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LevelError {}
+
+impl From<std::io::Error> for LevelError {
+    fn from(e: std::io::Error) -> Self {
+        LevelError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LevelError {
+    fn from(e: serde_json::Error) -> Self {
+        LevelError::Format(e)
+    }
+}
+
+impl LevelCurve {
+    pub fn parse(text: &str) -> Result<Self, LevelError> {
+        let curve: LevelCurve = serde_json::from_str(text)?;
+        let mut previous = 0;
+        for (index, level) in curve.levels.iter().enumerate() {
+            let number = index + 2;
+            if level.experience <= previous {
+                return Err(LevelError::Invalid(format!("level {} needs more experience than level {}", number, number - 1)));
+            }
+            if level.max_health < 0 || level.attack < 0 || level.defense < 0 || level.speed < 0 {
+                return Err(LevelError::Invalid(format!("level {} lowers a stat", number)));
+            }
+            previous = level.experience;
+        }
+        Ok(curve)
+    }
+
+    pub fn max_level(&self) -> u32 {
+        self.levels.len() as u32 + 1
+    }
+
+    // Raises the stats for every level their experience has reached and
+    // answers how many levels that was.
+    pub fn level_up(&self, stats: &mut Stats) -> u32 {
+        let mut gained = 0;
+        while let Some(next) = self.levels.get((stats.level as usize).saturating_sub(1)) {
+            if stats.experience < next.experience {
+                break;
+            }
+            stats.level += 1;
+            stats.max_health += next.max_health;
+            stats.attack += next.attack;
+            stats.defense += next.defense;
+            stats.speed += next.speed;
+            gained += 1;
+        }
+        gained
+    }
+}
+
+pub fn load_level_file(path: impl AsRef<Path>) -> Result<LevelCurve, LevelError> {
+    LevelCurve::parse(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_up_follows_the_curve() {
+        let curve = LevelCurve::parse(r#"{"kill_experience": 10, "levels": [
+            {"experience": 50, "max_health": 10, "attack": 2},
+            {"experience": 150, "defense": 1, "speed": 10}
+        ]}"#).unwrap();
+        assert_eq!(curve.max_level(), 3);
+
+        let mut stats = Stats { experience: 49, ..Stats::default() };
+        assert_eq!(curve.level_up(&mut stats), 0);
+        stats.experience = 200;
+        assert_eq!(curve.level_up(&mut stats), 2);
+        assert_eq!((stats.level, stats.max_health, stats.attack, stats.defense, stats.speed), (3, 110, 12, 3, 110));
+        stats.experience = 10_000;
+        assert_eq!(curve.level_up(&mut stats), 0);
+    }
+
+    #[test]
+    fn test_level_curves_are_validated() {
+        assert_eq!(LevelCurve::default().max_level(), 6);
+        let unordered = r#"{"kill_experience": 10, "levels": [{"experience": 50}, {"experience": 50}]}"#;
+        assert!(matches!(LevelCurve::parse(unordered), Err(LevelError::Invalid(_))));
+        let lowering = r#"{"kill_experience": 10, "levels": [{"experience": 50, "attack": -1}]}"#;
+        assert!(matches!(LevelCurve::parse(lowering), Err(LevelError::Invalid(_))));
+        assert!(matches!(LevelCurve::parse("{}"), Err(LevelError::Format(_))));
+    }
+}
@@ -0,0 +1,275 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::Mutex;
+use crate::accounts::PasswordHash;
+use crate::game::Character;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    pub player_id: String,
+    // Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Database(rusqlite::Error),
+    // A stored record could not be read back, e.g. after a format change.
+    Corrupt(serde_json::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Database(e) => write!(f, "Database error: {}", e),
+            StorageError::Corrupt(e) => write!(f, "Stored data could not be read: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Corrupt(e)
+    }
+}
+
+// Where accounts and characters outlive the server. Characters are kept
+// per room, as positions only make sense on the map they were saved on.
+pub trait Storage: Send + Sync {
+    // Creates the account on first sight and updates when it was last seen.
+    fn touch_account(&self, player_id: &str, now: u64) -> Result<Account, StorageError>;
+    fn load_account(&self, player_id: &str) -> Result<Option<Account>, StorageError>;
+    // Stores the password of a named account. Answers false, and changes
+    // nothing, when the name already has one.
+    fn create_login(&self, player_id: &str, password: &PasswordHash) -> Result<bool, StorageError>;
+    fn load_login(&self, player_id: &str) -> Result<Option<PasswordHash>, StorageError>;
+    fn load_character(&self, room: &str, player_id: &str) -> Result<Option<Character>, StorageError>;
+    // Saves all the given characters of a room at once.
+    fn save_characters(&self, room: &str, characters: &[(&str, &Character)]) -> Result<(), StorageError>;
+}
+
+// Keeps everything in memory, for tests and servers that need no persistence.
+#[derive(Default)]
+pub struct MemoryStorage {
+    accounts: Mutex<HashMap<String, Account>>,
+    logins: Mutex<HashMap<String, PasswordHash>>,
+    characters: Mutex<HashMap<(String, String), Character>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn touch_account(&self, player_id: &str, now: u64) -> Result<Account, StorageError> {
+        let mut accounts = self.accounts.lock().unwrap();
+        let account = accounts.entry(player_id.to_string()).or_insert_with(|| Account {
+            player_id: player_id.to_string(),
+            created_at: now,
+            last_seen: now,
+        });
+        account.last_seen = now;
+        Ok(account.clone())
+    }
+
+    fn load_account(&self, player_id: &str) -> Result<Option<Account>, StorageError> {
+        Ok(self.accounts.lock().unwrap().get(player_id).cloned())
+    }
+
+    fn create_login(&self, player_id: &str, password: &PasswordHash) -> Result<bool, StorageError> {
+        let mut logins = self.logins.lock().unwrap();
+        if logins.contains_key(player_id) {
+            return Ok(false);
+        }
+        logins.insert(player_id.to_string(), password.clone());
+        Ok(true)
+    }
+
+    fn load_login(&self, player_id: &str) -> Result<Option<PasswordHash>, StorageError> {
+        Ok(self.logins.lock().unwrap().get(player_id).cloned())
+    }
+
+    fn load_character(&self, room: &str, player_id: &str) -> Result<Option<Character>, StorageError> {
+        Ok(self.characters.lock().unwrap().get(&(room.to_string(), player_id.to_string())).cloned())
+    }
+
+    fn save_characters(&self, room: &str, characters: &[(&str, &Character)]) -> Result<(), StorageError> {
+        let mut stored = self.characters.lock().unwrap();
+        for (player_id, character) in characters {
+            stored.insert((room.to_string(), player_id.to_string()), (*character).clone());
+        }
+        Ok(())
+    }
+}
+
+// Characters are stored as JSON so that new fields need no schema change.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS accounts (
+        player_id TEXT PRIMARY KEY,
+        created_at INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS logins (
+        player_id TEXT PRIMARY KEY,
+        password TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS characters (
+        room TEXT NOT NULL,
+        player_id TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (room, player_id)
+    );
+";
+
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, StorageError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, StorageError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn touch_account(&self, player_id: &str, now: u64) -> Result<Account, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO accounts (player_id, created_at, last_seen) VALUES (?1, ?2, ?2)
+             ON CONFLICT (player_id) DO UPDATE SET last_seen = excluded.last_seen",
+            params![player_id, now],
+        )?;
+        let created_at = connection.query_row(
+            "SELECT created_at FROM accounts WHERE player_id = ?1",
+            params![player_id],
+            |row| row.get(0),
+        )?;
+        Ok(Account { player_id: player_id.to_string(), created_at, last_seen: now })
+    }
+
+    fn load_account(&self, player_id: &str) -> Result<Option<Account>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let account = connection.query_row(
+            "SELECT created_at, last_seen FROM accounts WHERE player_id = ?1",
+            params![player_id],
+            |row| Ok(Account { player_id: player_id.to_string(), created_at: row.get(0)?, last_seen: row.get(1)? }),
+        ).optional()?;
+        Ok(account)
+    }
+
+    fn create_login(&self, player_id: &str, password: &PasswordHash) -> Result<bool, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let inserted = connection.execute(
+            "INSERT INTO logins (player_id, password) VALUES (?1, ?2) ON CONFLICT (player_id) DO NOTHING",
+            params![player_id, password.as_str()],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn load_login(&self, player_id: &str) -> Result<Option<PasswordHash>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let password: Option<String> = connection.query_row(
+            "SELECT password FROM logins WHERE player_id = ?1",
+            params![player_id],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(password.map(PasswordHash::from_stored))
+    }
+
+    fn load_character(&self, room: &str, player_id: &str) -> Result<Option<Character>, StorageError> {
+        let connection = self.connection.lock().unwrap();
+        let data: Option<String> = connection.query_row(
+            "SELECT data FROM characters WHERE room = ?1 AND player_id = ?2",
+            params![room, player_id],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+    }
+
+    fn save_characters(&self, room: &str, characters: &[(&str, &Character)]) -> Result<(), StorageError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                "INSERT INTO characters (room, player_id, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (room, player_id) DO UPDATE SET data = excluded.data",
+            )?;
+            for (player_id, character) in characters {
+                statement.execute(params![room, player_id, serde_json::to_string(character)?])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(storage: &dyn Storage) {
+        assert_eq!(storage.load_account("player1").unwrap(), None);
+        assert_eq!(storage.touch_account("player1", 100).unwrap().created_at, 100);
+        let account = storage.touch_account("player1", 250).unwrap();
+        assert_eq!((account.created_at, account.last_seen), (100, 250));
+        assert_eq!(storage.load_account("player1").unwrap(), Some(account));
+
+        let password = PasswordHash::from_stored("$pbkdf2-sha256$i=1,l=32$c2FsdA$aGFzaA".to_string());
+        assert!(storage.create_login("alice", &password).unwrap());
+        assert!(!storage.create_login("alice", &PasswordHash::from_stored("other".to_string())).unwrap());
+        assert_eq!(storage.load_login("alice").unwrap(), Some(password));
+        assert_eq!(storage.load_login("player1").unwrap(), None);
+
+        let character = Character::new(3, 4, 70);
+        storage.save_characters("default", &[("player1", &character)]).unwrap();
+        assert_eq!(storage.load_character("default", "player1").unwrap(), Some(character));
+        assert_eq!(storage.load_character("arena", "player1").unwrap(), None);
+
+        let moved = Character::new(5, 4, 60);
+        storage.save_characters("default", &[("player1", &moved), ("player2", &moved)]).unwrap();
+        assert_eq!(storage.load_character("default", "player1").unwrap(), Some(moved.clone()));
+        assert_eq!(storage.load_character("default", "player2").unwrap(), Some(moved));
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        round_trip(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        round_trip(&SqliteStorage::open_in_memory().unwrap());
+    }
+
+    #[test]
+    fn test_sqlite_storage_reports_corrupt_records() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        storage.connection.lock().unwrap()
+            .execute("INSERT INTO characters VALUES ('default', 'player1', 'not json')", [])
+            .unwrap();
+        assert!(matches!(storage.load_character("default", "player1"), Err(StorageError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,135 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// A single map cell. Serialized as a lowercase name so the `/map` payload
+// stays readable for clients (`"empty"`, `"wall"`, ...).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Tile {
+    #[serde(rename = "empty", alias = "floor")]
+    Floor,
+    Wall,
+    Water,
+    Door,
+    Lava,
+    Spawn,
+}
+
+// Gameplay properties shared by every tile of a kind.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileProperties {
+    pub walkable: bool,
+    // Multiplies the move cooldown of a character standing on the tile.
+    pub movement_cost: u32,
+    pub opaque: bool,
+    pub damage_per_step: i32,
+}
+
+impl Tile {
+    pub const ALL: [Tile; 6] = [Tile::Floor, Tile::Wall, Tile::Water, Tile::Door, Tile::Lava, Tile::Spawn];
+
+    pub fn properties(self) -> TileProperties {
+        match self {
+            Tile::Floor | Tile::Spawn => TileProperties { walkable: true, movement_cost: 1, opaque: false, damage_per_step: 0 },
+            Tile::Wall => TileProperties { walkable: false, movement_cost: 0, opaque: true, damage_per_step: 0 },
+            Tile::Water => TileProperties { walkable: false, movement_cost: 0, opaque: false, damage_per_step: 0 },
+            Tile::Door => TileProperties { walkable: true, movement_cost: 1, opaque: true, damage_per_step: 0 },
+            Tile::Lava => TileProperties { walkable: true, movement_cost: 2, opaque: false, damage_per_step: 25 },
+        }
+    }
+
+    pub fn is_walkable(self) -> bool {
+        self.properties().walkable
+    }
+
+    pub fn movement_cost(self) -> u32 {
+        self.properties().movement_cost
+    }
+
+    pub fn is_opaque(self) -> bool {
+        self.properties().opaque
+    }
+
+    pub fn damage_per_step(self) -> i32 {
+        self.properties().damage_per_step
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tile::Floor => "empty",
+            Tile::Wall => "wall",
+            Tile::Water => "water",
+            Tile::Door => "door",
+            Tile::Lava => "lava",
+            Tile::Spawn => "spawn",
+        }
+    }
+}
+
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTile(pub String);
+
+impl fmt::Display for UnknownTile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown tile kind '{}'", self.0)
+    }
+}
+
+impl std::error::Error for UnknownTile {}
+
+impl FromStr for Tile {
+    type Err = UnknownTile;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "empty" | "floor" => Ok(Tile::Floor),
+            "wall" => Ok(Tile::Wall),
+            "water" => Ok(Tile::Water),
+            "door" => Ok(Tile::Door),
+            "lava" => Ok(Tile::Lava),
+            "spawn" => Ok(Tile::Spawn),
+            _ => Err(UnknownTile(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tile_properties() {
+        assert!(Tile::Floor.is_walkable());
+        assert!(!Tile::Wall.is_walkable());
+        assert!(!Tile::Water.is_walkable());
+        assert!(Tile::Wall.is_opaque());
+        assert!(!Tile::Water.is_opaque());
+        assert_eq!(Tile::Lava.damage_per_step(), 25);
+        assert_eq!(Tile::Lava.movement_cost(), 2);
+        assert_eq!(Tile::Floor.damage_per_step(), 0);
+    }
+
+    #[test]
+    fn test_tile_serialization_is_backwards_compatible() {
+        assert_eq!(serde_json::to_string(&Tile::Floor).unwrap(), "\"empty\"");
+        assert_eq!(serde_json::to_string(&Tile::Wall).unwrap(), "\"wall\"");
+        assert_eq!(serde_json::from_str::<Tile>("\"floor\"").unwrap(), Tile::Floor);
+        assert!(serde_json::from_str::<Tile>("\"wal\"").is_err());
+    }
+
+    #[test]
+    fn test_tile_from_str_round_trips_names() {
+        for tile in Tile::ALL {
+            assert_eq!(tile.name().parse::<Tile>(), Ok(tile));
+        }
+        assert_eq!("granite".parse::<Tile>(), Err(UnknownTile("granite".to_string())));
+    }
+}
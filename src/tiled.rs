@@ -0,0 +1,667 @@
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use crate::game::{Map, MapObject};
+use crate::tile::Tile;
+
+// Importer for maps authored in the Tiled editor (https://www.mapeditor.org),
+// from either the JSON (.tmj/.json) or the XML (.tmx) format.
+//
+// Tile kinds come from a `kind` property on each tileset tile, falling back
+// to the tile's class. Tile layers are stacked in order, so a non-empty tile
+// on a later layer replaces the one below it. Objects with class `spawn` turn
+// their tile into a spawn point; every other object is kept as a `MapObject`.
+// A `movement` map property selects four- or eight-way movement.
+
+const GID_MASK: u32 = 0x0FFF_FFFF; // strips Tiled's flip/rotation flags
+const KIND_PROPERTY: &str = "kind";
+const SPAWN_KIND: &str = "spawn";
+const MOVEMENT_PROPERTY: &str = "movement";
+
+#[derive(Debug)]
+pub enum TiledError {
+    Json(serde_json::Error),
+    Xml(roxmltree::Error),
+    Unsupported(String),
+    Invalid(String),
+    UnknownGid(u32),
+    MissingTileKind { gid: u32 },
+    UnknownTileKind { gid: u32, kind: String },
+    ObjectOutOfBounds { name: String, x: i32, y: i32 },
+    SpawnNotWalkable { x: i32, y: i32 },
+}
+
+impl fmt::Display for TiledError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TiledError::Json(e) => write!(f, "invalid Tiled JSON: {}", e),
+            TiledError::Xml(e) => write!(f, "invalid TMX: {}", e),
+            TiledError::Unsupported(feature) => write!(f, "unsupported Tiled feature: {}", feature),
+            TiledError::Invalid(message) => write!(f, "invalid Tiled map: {}", message),
+            TiledError::UnknownGid(gid) => write!(f, "tile gid {} does not belong to any tileset", gid),
+            TiledError::MissingTileKind { gid } => {
+                write!(f, "tile gid {} has no '{}' property or class", gid, KIND_PROPERTY)
+            }
+            TiledError::UnknownTileKind { gid, kind } => {
+                write!(f, "tile gid {} has unknown kind '{}'", gid, kind)
+            }
+            TiledError::ObjectOutOfBounds { name, x, y } => {
+                write!(f, "object '{}' at tile ({}, {}) is outside the map", name, x, y)
+            }
+            TiledError::SpawnNotWalkable { x, y } => {
+                write!(f, "spawn point at ({}, {}) is not on a walkable tile", x, y)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TiledError {}
+
+impl From<serde_json::Error> for TiledError {
+    fn from(e: serde_json::Error) -> Self {
+        TiledError::Json(e)
+    }
+}
+
+impl From<roxmltree::Error> for TiledError {
+    fn from(e: roxmltree::Error) -> Self {
+        TiledError::Xml(e)
+    }
+}
+
+// Format-independent view of a Tiled map that both parsers produce.
+struct TiledMap {
+    width: usize,
+    height: usize,
+    tile_width: f64,
+    tile_height: f64,
+    orientation: String,
+    infinite: bool,
+    properties: BTreeMap<String, String>,
+    tilesets: Vec<TiledTileset>,
+    layers: Vec<TiledLayer>,
+}
+
+struct TiledTileset {
+    first_gid: u32,
+    source: Option<String>,
+    kinds: HashMap<u32, String>,
+}
+
+enum TiledLayer {
+    Tiles { name: String, width: usize, height: usize, data: Vec<u32> },
+    Objects { objects: Vec<TiledObject> },
+}
+
+struct TiledObject {
+    name: String,
+    kind: String,
+    x: f64,
+    y: f64,
+    properties: BTreeMap<String, String>,
+}
+
+pub fn parse_tiled_json(text: &str) -> Result<Map, TiledError> {
+    let raw: json::Map = serde_json::from_str(text)?;
+    build_map(raw.into_tiled()?)
+}
+
+pub fn parse_tmx(text: &str) -> Result<Map, TiledError> {
+    let document = roxmltree::Document::parse(text)?;
+    build_map(tmx::read_map(document.root_element())?)
+}
+
+fn build_map(source: TiledMap) -> Result<Map, TiledError> {
+    if source.infinite {
+        return Err(TiledError::Unsupported("infinite maps".to_string()));
+    }
+    if !matches!(source.orientation.as_str(), "orthogonal" | "isometric") {
+        return Err(TiledError::Unsupported(format!("{} orientation", source.orientation)));
+    }
+    if source.width == 0 || source.height == 0 {
+        return Err(TiledError::Invalid("map has zero width or height".to_string()));
+    }
+    if let Some(source) = source.tilesets.iter().find_map(|tileset| tileset.source.as_ref()) {
+        return Err(TiledError::Unsupported(format!("external tileset '{}', embed it in the map", source)));
+    }
+
+    let mut tiles = vec![vec![Tile::Floor; source.width]; source.height];
+    let mut objects = Vec::new();
+    let mut spawns = Vec::new();
+    let mut has_tile_layer = false;
+
+    for layer in &source.layers {
+        match layer {
+            TiledLayer::Tiles { name, width, height, data } => {
+                if *width != source.width || *height != source.height || data.len() != width * height {
+                    return Err(TiledError::Invalid(format!(
+                        "tile layer '{}' does not cover the {}x{} map", name, source.width, source.height
+                    )));
+                }
+                has_tile_layer = true;
+                for (index, &raw_gid) in data.iter().enumerate() {
+                    let gid = raw_gid & GID_MASK;
+                    if gid != 0 {
+                        tiles[index / source.width][index % source.width] = resolve_gid(&source.tilesets, gid)?;
+                    }
+                }
+            }
+            TiledLayer::Objects { objects: layer_objects } => {
+                // Isometric maps measure object positions along the tile
+                // axes, in tile heights for both of them.
+                let (cell_width, cell_height) = match source.orientation.as_str() {
+                    "isometric" => (source.tile_height, source.tile_height),
+                    _ => (source.tile_width, source.tile_height),
+                };
+                for object in layer_objects {
+                    let x = (object.x / cell_width).floor() as i32;
+                    let y = (object.y / cell_height).floor() as i32;
+                    if x < 0 || y < 0 || x as usize >= source.width || y as usize >= source.height {
+                        return Err(TiledError::ObjectOutOfBounds { name: object.name.clone(), x, y });
+                    }
+                    let kind = object.kind.to_ascii_lowercase();
+                    if kind == SPAWN_KIND {
+                        spawns.push((x, y));
+                    } else {
+                        objects.push(MapObject {
+                            kind,
+                            name: object.name.clone(),
+                            x,
+                            y,
+                            properties: object.properties.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    if !has_tile_layer {
+        return Err(TiledError::Invalid("map has no tile layers".to_string()));
+    }
+
+    // Spawn objects are applied last so they are checked against the final terrain.
+    for (x, y) in spawns {
+        let tile = &mut tiles[y as usize][x as usize];
+        if !tile.is_walkable() {
+            return Err(TiledError::SpawnNotWalkable { x, y });
+        }
+        *tile = Tile::Spawn;
+    }
+
+    let mut map = Map::new(source.width, source.height, tiles);
+    map.objects = objects;
+    if let Some(movement) = source.properties.get(MOVEMENT_PROPERTY) {
+        map.movement = movement.parse().map_err(TiledError::Invalid)?;
+    }
+    Ok(map)
+}
+
+fn resolve_gid(tilesets: &[TiledTileset], gid: u32) -> Result<Tile, TiledError> {
+    let tileset = tilesets.iter()
+        .filter(|tileset| tileset.first_gid <= gid)
+        .max_by_key(|tileset| tileset.first_gid)
+        .ok_or(TiledError::UnknownGid(gid))?;
+    let kind = tileset.kinds.get(&(gid - tileset.first_gid))
+        .ok_or(TiledError::MissingTileKind { gid })?;
+    kind.parse().map_err(|_| TiledError::UnknownTileKind { gid, kind: kind.clone() })
+}
+
+mod json {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct Map {
+        width: usize,
+        height: usize,
+        tilewidth: f64,
+        tileheight: f64,
+        #[serde(default = "default_orientation")]
+        orientation: String,
+        #[serde(default)]
+        infinite: bool,
+        #[serde(default)]
+        layers: Vec<Layer>,
+        #[serde(default)]
+        tilesets: Vec<Tileset>,
+        #[serde(default)]
+        properties: Vec<Property>,
+    }
+
+    fn default_orientation() -> String {
+        "orthogonal".to_string()
+    }
+
+    #[derive(Deserialize)]
+    #[serde(tag = "type", rename_all = "lowercase")]
+    enum Layer {
+        Tilelayer {
+            #[serde(default)]
+            name: String,
+            width: usize,
+            height: usize,
+            #[serde(default)]
+            data: Option<serde_json::Value>,
+            #[serde(default)]
+            encoding: Option<String>,
+            #[serde(default)]
+            compression: Option<String>,
+        },
+        Objectgroup {
+            #[serde(default)]
+            objects: Vec<Object>,
+        },
+        Imagelayer {},
+        Group {
+            #[serde(default)]
+            name: String,
+        },
+    }
+
+    #[derive(Deserialize)]
+    struct Tileset {
+        firstgid: u32,
+        #[serde(default)]
+        source: Option<String>,
+        #[serde(default)]
+        tiles: Vec<TilesetTile>,
+    }
+
+    #[derive(Deserialize)]
+    struct TilesetTile {
+        id: u32,
+        #[serde(default, rename = "type")]
+        kind: String,
+        #[serde(default)]
+        class: String,
+        #[serde(default)]
+        properties: Vec<Property>,
+    }
+
+    #[derive(Deserialize)]
+    struct Object {
+        #[serde(default)]
+        name: String,
+        #[serde(default, rename = "type")]
+        kind: String,
+        #[serde(default)]
+        class: String,
+        x: f64,
+        y: f64,
+        #[serde(default)]
+        height: f64,
+        #[serde(default)]
+        gid: Option<u32>,
+        #[serde(default)]
+        properties: Vec<Property>,
+    }
+
+    #[derive(Deserialize)]
+    struct Property {
+        name: String,
+        value: serde_json::Value,
+    }
+
+    fn property_map(properties: Vec<Property>) -> BTreeMap<String, String> {
+        properties.into_iter().map(|property| {
+            let value = match property.value {
+                serde_json::Value::String(value) => value,
+                other => other.to_string(),
+            };
+            (property.name, value)
+        }).collect()
+    }
+
+    fn class_or_type(kind: String, class: String) -> String {
+        if class.is_empty() { kind } else { class }
+    }
+
+    impl Map {
+        pub fn into_tiled(self) -> Result<TiledMap, TiledError> {
+            let layers = self.layers.into_iter().filter_map(|layer| match layer {
+                Layer::Tilelayer { name, width, height, data, encoding, compression } => {
+                    Some(tile_layer(name, width, height, data, encoding, compression))
+                }
+                Layer::Objectgroup { objects } => Some(Ok(TiledLayer::Objects {
+                    objects: objects.into_iter().map(|object| {
+                        // Tile objects are anchored at their bottom-left corner.
+                        let y = if object.gid.is_some() { object.y - object.height } else { object.y };
+                        TiledObject {
+                            name: object.name,
+                            kind: class_or_type(object.kind, object.class),
+                            x: object.x,
+                            y,
+                            properties: property_map(object.properties),
+                        }
+                    }).collect(),
+                })),
+                Layer::Imagelayer {} => None,
+                Layer::Group { name } => Some(Err(TiledError::Unsupported(format!("group layer '{}'", name)))),
+            }).collect::<Result<Vec<_>, _>>()?;
+
+            let tilesets = self.tilesets.into_iter().map(|tileset| TiledTileset {
+                first_gid: tileset.firstgid,
+                source: tileset.source,
+                kinds: tileset.tiles.into_iter().filter_map(|tile| {
+                    let mut properties = property_map(tile.properties);
+                    let kind = properties.remove(KIND_PROPERTY)
+                        .unwrap_or_else(|| class_or_type(tile.kind, tile.class));
+                    (!kind.is_empty()).then_some((tile.id, kind))
+                }).collect(),
+            }).collect();
+
+            Ok(TiledMap {
+                width: self.width,
+                height: self.height,
+                tile_width: self.tilewidth,
+                tile_height: self.tileheight,
+                orientation: self.orientation,
+                infinite: self.infinite,
+                properties: property_map(self.properties),
+                tilesets,
+                layers,
+            })
+        }
+    }
+
+    fn tile_layer(
+        name: String,
+        width: usize,
+        height: usize,
+        data: Option<serde_json::Value>,
+        encoding: Option<String>,
+        compression: Option<String>,
+    ) -> Result<TiledLayer, TiledError> {
+        if let Some(compression) = compression.filter(|c| !c.is_empty()) {
+            return Err(TiledError::Unsupported(format!("{} compression in layer '{}'", compression, name)));
+        }
+        if let Some(encoding) = encoding.filter(|e| e != "csv") {
+            return Err(TiledError::Unsupported(format!("{} encoding in layer '{}'", encoding, name)));
+        }
+        let data = data.ok_or_else(|| TiledError::Invalid(format!("tile layer '{}' has no data", name)))?;
+        let data: Vec<u32> = serde_json::from_value(data)?;
+        Ok(TiledLayer::Tiles { name, width, height, data })
+    }
+}
+
+mod tmx {
+    use super::*;
+    use roxmltree::Node;
+
+    fn attr<T: std::str::FromStr>(node: Node, name: &str) -> Result<T, TiledError> {
+        let value = node.attribute(name).ok_or_else(|| {
+            TiledError::Invalid(format!("<{}> is missing the '{}' attribute", node.tag_name().name(), name))
+        })?;
+        value.parse().map_err(|_| {
+            TiledError::Invalid(format!("<{}> has an invalid '{}' attribute", node.tag_name().name(), name))
+        })
+    }
+
+    fn children<'a, 'input>(node: Node<'a, 'input>, tag: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+        node.children().filter(move |child| child.has_tag_name(tag))
+    }
+
+    fn properties(node: Node) -> BTreeMap<String, String> {
+        children(node, "properties")
+            .flat_map(|properties| children(properties, "property"))
+            .filter_map(|property| {
+                let name = property.attribute("name")?;
+                let value = property.attribute("value").or_else(|| property.text()).unwrap_or_default();
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    fn class_or_type(node: Node) -> String {
+        node.attribute("class").or_else(|| node.attribute("type")).unwrap_or_default().to_string()
+    }
+
+    pub fn read_map(root: Node) -> Result<TiledMap, TiledError> {
+        if !root.has_tag_name("map") {
+            return Err(TiledError::Invalid("root element is not <map>".to_string()));
+        }
+
+        let mut layers = Vec::new();
+        let mut tilesets = Vec::new();
+        for child in root.children().filter(Node::is_element) {
+            match child.tag_name().name() {
+                "tileset" => tilesets.push(read_tileset(child)?),
+                "layer" => layers.push(read_tile_layer(child)?),
+                "objectgroup" => layers.push(read_object_layer(child)?),
+                "group" => {
+                    let name = child.attribute("name").unwrap_or_default();
+                    return Err(TiledError::Unsupported(format!("group layer '{}'", name)));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(TiledMap {
+            width: attr(root, "width")?,
+            height: attr(root, "height")?,
+            tile_width: attr(root, "tilewidth")?,
+            tile_height: attr(root, "tileheight")?,
+            orientation: root.attribute("orientation").unwrap_or("orthogonal").to_string(),
+            infinite: root.attribute("infinite") == Some("1"),
+            properties: properties(root),
+            tilesets,
+            layers,
+        })
+    }
+
+    fn read_tileset(node: Node) -> Result<TiledTileset, TiledError> {
+        let kinds = children(node, "tile").map(|tile| {
+            let id: u32 = attr(tile, "id")?;
+            let kind = properties(tile).remove(KIND_PROPERTY).unwrap_or_else(|| class_or_type(tile));
+            Ok((id, kind))
+        }).filter(|entry| !matches!(entry, Ok((_, kind)) if kind.is_empty()))
+            .collect::<Result<_, TiledError>>()?;
+
+        Ok(TiledTileset {
+            first_gid: attr(node, "firstgid")?,
+            source: node.attribute("source").map(str::to_string),
+            kinds,
+        })
+    }
+
+    fn read_tile_layer(node: Node) -> Result<TiledLayer, TiledError> {
+        let name = node.attribute("name").unwrap_or_default().to_string();
+        let data_node = children(node, "data").next()
+            .ok_or_else(|| TiledError::Invalid(format!("tile layer '{}' has no data", name)))?;
+        if let Some(compression) = data_node.attribute("compression") {
+            return Err(TiledError::Unsupported(format!("{} compression in layer '{}'", compression, name)));
+        }
+        if children(data_node, "chunk").next().is_some() {
+            return Err(TiledError::Unsupported("infinite maps".to_string()));
+        }
+
+        let data = match data_node.attribute("encoding") {
+            Some("csv") => data_node.text().unwrap_or_default()
+                .split(',')
+                .map(|gid| gid.trim().parse().map_err(|_| {
+                    TiledError::Invalid(format!("tile layer '{}' has a non-numeric gid '{}'", name, gid.trim()))
+                }))
+                .collect::<Result<Vec<u32>, _>>()?,
+            Some(encoding) => {
+                return Err(TiledError::Unsupported(format!("{} encoding in layer '{}'", encoding, name)));
+            }
+            None => children(data_node, "tile")
+                .map(|tile| Ok(tile.attribute("gid").map(str::parse).transpose().map_err(|_| {
+                    TiledError::Invalid(format!("tile layer '{}' has an invalid gid", name))
+                })?.unwrap_or(0)))
+                .collect::<Result<Vec<u32>, TiledError>>()?,
+        };
+
+        Ok(TiledLayer::Tiles { width: attr(node, "width")?, height: attr(node, "height")?, name, data })
+    }
+
+    fn read_object_layer(node: Node) -> Result<TiledLayer, TiledError> {
+        let objects = children(node, "object").map(|object| {
+            let x: f64 = attr(object, "x")?;
+            let mut y: f64 = attr(object, "y")?;
+            if object.attribute("gid").is_some() {
+                // Tile objects are anchored at their bottom-left corner.
+                y -= object.attribute("height").and_then(|h| h.parse::<f64>().ok()).unwrap_or(0.0);
+            }
+            Ok(TiledObject {
+                name: object.attribute("name").unwrap_or_default().to_string(),
+                kind: class_or_type(object),
+                x,
+                y,
+                properties: properties(object),
+            })
+        }).collect::<Result<Vec<_>, TiledError>>()?;
+        Ok(TiledLayer::Objects { objects })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::direction::MovementMode;
+
+    const TILESET_JSON: &str = r#"{
+        "firstgid": 1,
+        "tiles": [
+            {"id": 0, "properties": [{"name": "kind", "type": "string", "value": "empty"}]},
+            {"id": 1, "type": "wall"},
+            {"id": 2, "properties": [{"name": "kind", "type": "string", "value": "water"}]}
+        ]
+    }"#;
+
+    fn json_map(layers: &str) -> String {
+        format!(
+            r#"{{"width": 3, "height": 2, "tilewidth": 32, "tileheight": 32, "orientation": "orthogonal",
+                "infinite": false, "tilesets": [{}], "layers": [{}]}}"#,
+            TILESET_JSON, layers
+        )
+    }
+
+    const GROUND_LAYER: &str = r#"{"type": "tilelayer", "name": "ground", "width": 3, "height": 2, "data": [1, 2, 1, 3, 1, 1]}"#;
+
+    #[test]
+    fn test_parse_tiled_json_tile_layer() {
+        let map = parse_tiled_json(&json_map(GROUND_LAYER)).unwrap();
+        assert_eq!(map.width, 3);
+        assert_eq!(map.height, 2);
+        assert_eq!(map.tiles[0], vec![Tile::Floor, Tile::Wall, Tile::Floor]);
+        assert_eq!(map.tiles[1], vec![Tile::Water, Tile::Floor, Tile::Floor]);
+    }
+
+    #[test]
+    fn test_parse_tiled_json_objects() {
+        let layers = format!(
+            r#"{}, {{"type": "objectgroup", "objects": [
+                {{"name": "p1", "type": "spawn", "x": 70, "y": 40, "point": true}},
+                {{"name": "chest", "class": "item", "x": 0, "y": 32, "point": true,
+                  "properties": [{{"name": "item", "type": "string", "value": "potion"}}]}}
+            ]}}"#,
+            GROUND_LAYER
+        );
+        let map = parse_tiled_json(&json_map(&layers)).unwrap();
+        assert_eq!(map.tiles[1][2], Tile::Spawn);
+        assert_eq!(map.objects.len(), 1);
+        assert_eq!(map.objects[0].kind, "item");
+        assert_eq!((map.objects[0].x, map.objects[0].y), (0, 1));
+        assert_eq!(map.objects[0].properties["item"], "potion");
+    }
+
+    #[test]
+    fn test_parse_tiled_json_isometric_objects() {
+        let layers = format!(
+            r#"{}, {{"type": "objectgroup", "objects": [
+                {{"name": "chest", "type": "item", "x": 40, "y": 20, "point": true}}
+            ]}}"#,
+            GROUND_LAYER
+        );
+        let isometric = json_map(&layers)
+            .replace(r#""orientation": "orthogonal""#, r#""orientation": "isometric""#)
+            .replace(r#""tilewidth": 32, "tileheight": 32"#, r#""tilewidth": 32, "tileheight": 16"#);
+        let map = parse_tiled_json(&isometric).unwrap();
+        assert_eq!((map.objects[0].x, map.objects[0].y), (2, 1));
+    }
+
+    #[test]
+    fn test_parse_tiled_json_movement_property() {
+        assert_eq!(parse_tiled_json(&json_map(GROUND_LAYER)).unwrap().movement, MovementMode::FourWay);
+
+        let eight_way = json_map(GROUND_LAYER).replacen(
+            r#""layers""#,
+            r#""properties": [{"name": "movement", "type": "string", "value": "eight_way"}], "layers""#,
+            1,
+        );
+        assert_eq!(parse_tiled_json(&eight_way).unwrap().movement, MovementMode::EightWay);
+    }
+
+    #[test]
+    fn test_parse_tiled_json_rejects_unsupported_features() {
+        let base64 = r#"{"type": "tilelayer", "name": "ground", "width": 3, "height": 2, "encoding": "base64", "data": "AQAAAA=="}"#;
+        assert!(matches!(parse_tiled_json(&json_map(base64)), Err(TiledError::Unsupported(_))));
+
+        let group = r#"{"type": "group", "name": "nested", "layers": []}"#;
+        assert!(matches!(parse_tiled_json(&json_map(group)), Err(TiledError::Unsupported(_))));
+
+        let infinite = json_map(GROUND_LAYER).replace(r#""infinite": false"#, r#""infinite": true"#);
+        assert!(matches!(parse_tiled_json(&infinite), Err(TiledError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_parse_tiled_json_rejects_bad_tiles() {
+        let unknown = r#"{"type": "tilelayer", "name": "ground", "width": 3, "height": 2, "data": [1, 1, 1, 1, 1, 9]}"#;
+        assert!(matches!(parse_tiled_json(&json_map(unknown)), Err(TiledError::MissingTileKind { gid: 9 })));
+
+        let short = r#"{"type": "tilelayer", "name": "ground", "width": 3, "height": 2, "data": [1, 1]}"#;
+        assert!(matches!(parse_tiled_json(&json_map(short)), Err(TiledError::Invalid(_))));
+
+        let blocked_spawn = format!(
+            r#"{}, {{"type": "objectgroup", "objects": [{{"name": "p1", "type": "spawn", "x": 40, "y": 0}}]}}"#,
+            GROUND_LAYER
+        );
+        assert!(matches!(
+            parse_tiled_json(&json_map(&blocked_spawn)),
+            Err(TiledError::SpawnNotWalkable { x: 1, y: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_tmx() {
+        let tmx = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <map version="1.10" orientation="orthogonal" width="3" height="2" tilewidth="32" tileheight="32" infinite="0">
+              <properties><property name="movement" value="eight_way"/></properties>
+              <tileset firstgid="1" name="terrain" tilewidth="32" tileheight="32" tilecount="3">
+                <tile id="0"><properties><property name="kind" value="empty"/></properties></tile>
+                <tile id="1" class="wall"/>
+                <tile id="2" type="lava"/>
+              </tileset>
+              <layer id="1" name="ground" width="3" height="2">
+                <data encoding="csv">
+            1,2,1,
+            3,1,1
+            </data>
+              </layer>
+              <objectgroup id="2" name="objects">
+                <object id="1" name="p1" type="spawn" x="0" y="0"><point/></object>
+                <object id="2" name="sign" type="note" x="64" y="32">
+                  <properties><property name="text" value="hello"/></properties>
+                </object>
+              </objectgroup>
+            </map>"#;
+        let map = parse_tmx(tmx).unwrap();
+        assert_eq!(map.tiles[0], vec![Tile::Spawn, Tile::Wall, Tile::Floor]);
+        assert_eq!(map.tiles[1], vec![Tile::Lava, Tile::Floor, Tile::Floor]);
+        assert_eq!(map.objects[0].kind, "note");
+        assert_eq!(map.objects[0].properties["text"], "hello");
+        assert_eq!(map.movement, MovementMode::EightWay);
+    }
+
+    #[test]
+    fn test_parse_tmx_rejects_external_tileset() {
+        let tmx = r#"<map orientation="orthogonal" width="1" height="1" tilewidth="32" tileheight="32">
+              <tileset firstgid="1" source="terrain.tsx"/>
+              <layer name="ground" width="1" height="1"><data encoding="csv">1</data></layer>
+            </map>"#;
+        let err = parse_tmx(tmx).unwrap_err();
+        assert!(matches!(err, TiledError::Unsupported(_)));
+        assert!(err.to_string().contains("terrain.tsx"));
+    }
+}
@@ -1,10 +1,46 @@
 use actix_web::{web, Result, HttpResponse};
+use actix_web::error::JsonPayloadError;
+use actix_web::http::{StatusCode, header};
 use actix_web_actors::ws;
-use serde::Deserialize;
-use std::sync::{Arc, Mutex};
-use crate::game::{GameState, GameWebSocket};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use actix::{Addr, MailboxError};
+use crate::accounts::{AccountError, Credentials};
+use crate::auth::{Admin, AuthError, AuthenticatedPlayer, TokenSigner, guest_id, unix_now};
+use crate::combat::CombatError;
+use crate::direction::Direction;
+use crate::dungeon::Generator;
+use crate::game::{Character, GameState, MoveError, MoveOutcome};
+use crate::items::{InventoryError, ItemAction};
+use crate::lobby::{Dequeue, Enqueue, ListQueues, LobbyError, LobbySocket, MatchPreferences, Matchmaker, PlayerStatus};
+use crate::messages::{AddPlayer, Attack, InventoryAction, Move, Query, Shoot};
+use crate::rooms::{
+    CloseRoom, CreateRoom, DEFAULT_ROOM, GetRoom, ListRooms, MapChoice, RoomError, RoomRegistry, SaveWorld,
+};
+use crate::save::{SaveError, write_save};
+use crate::socket::GameWebSocket;
+use crate::spawn::SpawnError;
+use crate::storage::Storage;
 
-pub type AppState = Arc<Mutex<GameState>>;
+pub type AppState = Addr<RoomRegistry>;
+pub type LobbyState = Addr<Matchmaker>;
+
+fn unavailable(_: MailboxError) -> actix_web::Error {
+    actix_web::error::ErrorInternalServerError("The game is not available")
+}
+
+// Game endpoints take the room as `?room=<id>` and fall back to the default room.
+#[derive(Deserialize)]
+pub struct RoomQuery {
+    pub room: Option<String>,
+}
+
+async fn room_game(data: &AppState, room: Option<String>) -> Result<Addr<GameState>> {
+    let id = room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+    Ok(data.send(GetRoom { id }).await.map_err(unavailable)??)
+}
 
 pub async fn hello() -> Result<String> {
     Ok("Hello world!".to_string())
@@ -12,50 +48,528 @@ pub async fn hello() -> Result<String> {
 
 pub async fn get_character(
     data: web::Data<AppState>,
-    req: actix_web::HttpRequest,
+    query: web::Query<RoomQuery>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
 ) -> Result<impl actix_web::Responder> {
-    let game_state = data.lock().map_err(|_| actix_web::error::ErrorInternalServerError("Lock failed"))?;
-    let player_id = req.headers()
-        .get("x-player-id")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing x-player-id header"))?;
-
-    if let Some(character) = game_state.get_character(player_id) {
-        Ok(web::Json(character.clone()))
-    } else {
-        Err(actix_web::error::ErrorNotFound("Player not found"))
-    }
+    let game = room_game(&data, query.into_inner().room).await?;
+    let character = game.send(Query::new(move |game| game.get_character(&player_id).cloned()))
+        .await
+        .map_err(unavailable)?;
+    character.map(web::Json).ok_or_else(|| actix_web::error::ErrorNotFound("Player not found"))
 }
 
-pub async fn get_map(data: web::Data<AppState>) -> Result<impl actix_web::Responder> {
-    let game_state = data.lock().map_err(|_| actix_web::error::ErrorInternalServerError("Lock failed"))?;
-    Ok(web::Json(game_state.map.clone()))
+pub async fn get_map(data: web::Data<AppState>, query: web::Query<RoomQuery>) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, query.into_inner().room).await?;
+    let map = game.send(Query::new(|game| game.map.clone())).await.map_err(unavailable)?;
+    Ok(web::Json(map))
 }
 
+// The direction is parsed by hand so that an unknown one is reported like
+// any other rejected move rather than as a malformed body.
 #[derive(Deserialize)]
 pub struct MoveRequest {
     pub direction: String,
 }
 
+// The character after the move, and whether it moved or bumped into
+// someone and stayed put.
+#[derive(Serialize)]
+pub struct MoveResponse {
+    #[serde(flatten)]
+    pub character: Character,
+    pub outcome: MoveOutcome,
+}
+
 pub async fn move_character(
     data: web::Data<AppState>,
-    req: web::Json<MoveRequest>,
-    http_req: actix_web::HttpRequest,
+    query: web::Query<RoomQuery>,
+    req: Result<web::Json<MoveRequest>, actix_web::Error>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let req = req.map_err(|e| match e.as_error::<JsonPayloadError>() {
+        Some(JsonPayloadError::Deserialize(error)) => MoveError::InvalidRequest(error.to_string()).into(),
+        _ => e,
+    })?;
+    let direction: Direction = req.direction.parse().map_err(|_| MoveError::UnknownDirection)?;
+    let game = room_game(&data, query.into_inner().room).await?;
+    let (character, outcome) = game.send(Move { player_id, direction }).await.map_err(unavailable)??;
+    Ok(web::Json(MoveResponse { character, outcome }))
+}
+
+#[derive(Deserialize)]
+pub struct AttackRequest {
+    pub target: String,
+}
+
+pub async fn attack(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<AttackRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, query.into_inner().room).await?;
+    let outcome = game.send(Attack { player_id, target: req.into_inner().target }).await.map_err(unavailable)??;
+    Ok(web::Json(outcome))
+}
+
+pub async fn shoot(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<AttackRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, query.into_inner().room).await?;
+    let outcome = game.send(Shoot { player_id, target: req.into_inner().target }).await.map_err(unavailable)??;
+    Ok(web::Json(outcome))
+}
+
+// The item catalog of the room.
+pub async fn list_items(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, query.into_inner().room).await?;
+    let items = game.send(Query::new(|game| game.rules.items.items().cloned().collect::<Vec<_>>()))
+        .await
+        .map_err(unavailable)?;
+    Ok(web::Json(items))
+}
+
+pub async fn get_inventory(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
 ) -> Result<impl actix_web::Responder> {
-    let mut game_state = data.lock().map_err(|_| actix_web::error::ErrorInternalServerError("Lock failed"))?;
-    let player_id = http_req.headers()
-        .get("x-player-id")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Missing x-player-id header"))?;
+    let game = room_game(&data, query.into_inner().room).await?;
+    let inventory = game.send(Query::new(move |game| game.inventory(&player_id)))
+        .await
+        .map_err(unavailable)?;
+    Ok(web::Json(inventory.ok_or(InventoryError::UnknownPlayer)?))
+}
 
-    if game_state.move_character(player_id, &req.direction) {
-        if let Some(character) = game_state.get_character(player_id) {
-            Ok(web::Json(character.clone()))
-        } else {
-            Err(actix_web::error::ErrorNotFound("Player not found"))
+#[derive(Deserialize)]
+pub struct PickUpRequest {
+    // The top item when left out.
+    #[serde(default)]
+    pub item: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ItemRequest {
+    pub item: String,
+}
+
+async fn inventory_action(
+    data: web::Data<AppState>,
+    room: Option<String>,
+    player_id: String,
+    action: ItemAction,
+) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, room).await?;
+    let inventory = game.send(InventoryAction { player_id, action }).await.map_err(unavailable)??;
+    Ok(web::Json(inventory))
+}
+
+pub async fn pick_up_item(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<PickUpRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let action = ItemAction::PickUp(req.into_inner().item);
+    inventory_action(data, query.into_inner().room, player_id, action).await
+}
+
+pub async fn drop_item(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<ItemRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let action = ItemAction::Drop(req.into_inner().item);
+    inventory_action(data, query.into_inner().room, player_id, action).await
+}
+
+pub async fn use_item(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<ItemRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let action = ItemAction::Use(req.into_inner().item);
+    inventory_action(data, query.into_inner().room, player_id, action).await
+}
+
+pub async fn equip_item(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<ItemRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let action = ItemAction::Equip(req.into_inner().item);
+    inventory_action(data, query.into_inner().room, player_id, action).await
+}
+
+pub async fn unequip_item(
+    data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    req: web::Json<ItemRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let action = ItemAction::Unequip(req.into_inner().item);
+    inventory_action(data, query.into_inner().room, player_id, action).await
+}
+
+#[derive(Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    pub message: String,
+}
+
+impl actix_web::ResponseError for MoveError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MoveError::UnknownPlayer => StatusCode::NOT_FOUND,
+            MoveError::UnknownDirection | MoveError::InvalidRequest(_) | MoveError::DirectionNotAllowed { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            MoveError::OutOfBounds => StatusCode::UNPROCESSABLE_ENTITY,
+            MoveError::BlockedByWall { .. }
+            | MoveError::CornerBlocked
+            | MoveError::BlockedByPlayer { .. } => StatusCode::CONFLICT,
+            MoveError::OnCooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+            MoveError::Dead => StatusCode::FORBIDDEN,
         }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let MoveError::OnCooldown { remaining } = self {
+            response.insert_header((header::RETRY_AFTER, remaining.as_secs_f64().ceil() as u64));
+        }
+        response.json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+impl actix_web::ResponseError for InventoryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            InventoryError::UnknownPlayer | InventoryError::UnknownItem | InventoryError::NothingHere
+            | InventoryError::NotCarried | InventoryError::NotEquipped => StatusCode::NOT_FOUND,
+            InventoryError::Dead => StatusCode::FORBIDDEN,
+            InventoryError::InventoryFull { .. } | InventoryError::FullHealth => StatusCode::CONFLICT,
+            InventoryError::NotUsable | InventoryError::NotEquippable => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+impl actix_web::ResponseError for CombatError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CombatError::UnknownPlayer | CombatError::UnknownTarget => StatusCode::NOT_FOUND,
+            CombatError::SelfTarget => StatusCode::BAD_REQUEST,
+            CombatError::Dead => StatusCode::FORBIDDEN,
+            CombatError::TargetDead => StatusCode::CONFLICT,
+            CombatError::NotAdjacent | CombatError::OutOfRange { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            CombatError::OnCooldown { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let CombatError::OnCooldown { remaining } = self {
+            response.insert_header((header::RETRY_AFTER, remaining.as_secs_f64().ceil() as u64));
+        }
+        response.json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+pub async fn get_stats(data: web::Data<AppState>, query: web::Query<RoomQuery>) -> Result<impl actix_web::Responder> {
+    let game = room_game(&data, query.into_inner().room).await?;
+    let stats = game.send(Query::new(|game| serde_json::json!({
+        "connections": game.connection_stats(),
+        "players": game.player_count(),
+        "ticks": game.ticks,
+        "version": game.version,
+    }))).await.map_err(unavailable)?;
+    Ok(web::Json(stats))
+}
+
+// Starts a session as a new guest player.
+pub async fn guest_login(signer: web::Data<TokenSigner>) -> Result<impl actix_web::Responder> {
+    Ok(HttpResponse::Created().json(signer.issue(&guest_id())))
+}
+
+// Creates a named account and starts a session for it. Unlike guests,
+// accounts sign in as the same player every time.
+pub async fn register_account(
+    signer: web::Data<TokenSigner>,
+    accounts: web::Data<dyn Storage>,
+    req: web::Json<Credentials>,
+) -> Result<impl actix_web::Responder> {
+    let account = web::block(move || crate::accounts::register(accounts.get_ref(), &req, unix_now())).await??;
+    Ok(HttpResponse::Created().json(signer.issue(&account.player_id)))
+}
+
+pub async fn account_login(
+    signer: web::Data<TokenSigner>,
+    accounts: web::Data<dyn Storage>,
+    req: web::Json<Credentials>,
+) -> Result<impl actix_web::Responder> {
+    let account = web::block(move || crate::accounts::login(accounts.get_ref(), &req, unix_now())).await??;
+    Ok(web::Json(signer.issue(&account.player_id)))
+}
+
+impl actix_web::ResponseError for AccountError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AccountError::InvalidName(_) | AccountError::WeakPassword => StatusCode::BAD_REQUEST,
+            AccountError::NameTaken => StatusCode::CONFLICT,
+            AccountError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            AccountError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+// Trades a valid session token for a fresh one for the same player.
+pub async fn refresh_session(
+    signer: web::Data<TokenSigner>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    Ok(web::Json(signer.issue(&player_id)))
+}
+
+impl actix_web::ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if self.status_code() == StatusCode::UNAUTHORIZED {
+            response.insert_header((header::WWW_AUTHENTICATE, "Bearer"));
+        }
+        response.json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+pub async fn protocol_schema() -> Result<impl actix_web::Responder> {
+    Ok(web::Json(crate::protocol::json_schema()))
+}
+
+// Body of `POST /rooms`. `map` names a file in the maps directory;
+// `generator` (with optional `width`, `height` and `seed`) generates one.
+// Without either the room uses the server's default map.
+#[derive(Deserialize)]
+pub struct CreateRoomRequest {
+    pub name: Option<String>,
+    pub map: Option<String>,
+    pub generator: Option<String>,
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub seed: Option<u64>,
+}
+
+impl CreateRoomRequest {
+    fn map_choice(self) -> Result<MapChoice, RoomError> {
+        match (self.map, self.generator) {
+            (Some(_), Some(_)) => Err(RoomError::InvalidRequest("map and generator cannot be combined".to_string())),
+            (Some(map), None) => Ok(MapChoice::File(map)),
+            (None, Some(generator)) => Ok(MapChoice::Generated {
+                generator: generator.parse::<Generator>().map_err(RoomError::InvalidRequest)?,
+                width: self.width.unwrap_or(40),
+                height: self.height.unwrap_or(30),
+                seed: self.seed,
+            }),
+            (None, None) => Ok(MapChoice::Default),
+        }
+    }
+}
+
+pub async fn create_room(
+    data: web::Data<AppState>,
+    req: web::Json<CreateRoomRequest>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let req = req.into_inner();
+    let name = req.name.clone();
+    let map = req.map_choice()?;
+    let room = data.send(CreateRoom { name, map, owner: Some(player_id) }).await.map_err(unavailable)??;
+    Ok(HttpResponse::Created().json(room))
+}
+
+pub async fn list_rooms(data: web::Data<AppState>) -> Result<impl actix_web::Responder> {
+    let rooms = data.send(ListRooms).await.map_err(unavailable)?;
+    Ok(web::Json(rooms))
+}
+
+pub async fn join_room(
+    data: web::Data<AppState>,
+    path: web::Path<String>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let room = path.into_inner();
+    let game = room_game(&data, Some(room.clone())).await?;
+    let character = game.send(AddPlayer { player_id }).await.map_err(unavailable)??;
+    Ok(web::Json(serde_json::json!({ "room": room, "character": character })))
+}
+
+// Rooms are closed by the player who created them or by an administrator.
+pub async fn close_room(
+    data: web::Data<AppState>,
+    signer: web::Data<TokenSigner>,
+    path: web::Path<String>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let admin = signer.is_admin(&player_id);
+    data.send(CloseRoom { id: path.into_inner(), player_id, admin }).await.map_err(unavailable)??;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+impl actix_web::ResponseError for RoomError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RoomError::NotFound => StatusCode::NOT_FOUND,
+            RoomError::InvalidRequest(_) | RoomError::InvalidMapName(_) | RoomError::TooLarge { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            RoomError::Map(_) | RoomError::Generate(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RoomError::DefaultRoom | RoomError::NotOwner => StatusCode::FORBIDDEN,
+            RoomError::TooManyRooms { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+impl actix_web::ResponseError for SpawnError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::CONFLICT
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: "no_free_spawn_point", message: self.to_string() })
+    }
+}
+
+// Queues the player for a match. The answer is the player's queue status;
+// `GET /lobby/queue` or `/lobby/ws` tell them which room to join.
+pub async fn join_queue(
+    lobby: web::Data<LobbyState>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+    preferences: web::Json<MatchPreferences>,
+) -> Result<impl actix_web::Responder> {
+    let preferences = preferences.into_inner();
+    let status = lobby.send(Enqueue { player_id, preferences, notify: None }).await.map_err(unavailable)??;
+    Ok(HttpResponse::Accepted().json(status))
+}
+
+pub async fn queue_status(
+    lobby: web::Data<LobbyState>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    let status = lobby.send(PlayerStatus { player_id }).await.map_err(unavailable)?;
+    status.map(web::Json).ok_or_else(|| actix_web::error::ErrorNotFound("Player is not in the lobby"))
+}
+
+pub async fn leave_queue(
+    lobby: web::Data<LobbyState>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    if lobby.send(Dequeue { player_id }).await.map_err(unavailable)? {
+        Ok(HttpResponse::NoContent().finish())
     } else {
-        Err(actix_web::error::ErrorBadRequest("Invalid move"))
+        Err(actix_web::error::ErrorNotFound("Player is not queued"))
+    }
+}
+
+pub async fn list_queues(lobby: web::Data<LobbyState>) -> Result<impl actix_web::Responder> {
+    let queues = lobby.send(ListQueues).await.map_err(unavailable)?;
+    Ok(web::Json(queues))
+}
+
+impl actix_web::ResponseError for LobbyError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: self.code(), message: self.to_string() })
+    }
+}
+
+// Saves on request happen at most this often; the world is saved on
+// shutdown anyway.
+pub const WORLD_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Where `POST /world/save` writes the world; None when world saves are off.
+pub struct WorldFile {
+    path: Option<PathBuf>,
+    last_saved: Mutex<Option<Instant>>,
+}
+
+impl WorldFile {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self { path, last_saved: Mutex::new(None) }
+    }
+}
+
+// Only administrators may write the world file.
+pub async fn save_world(
+    data: web::Data<AppState>,
+    world_file: web::Data<WorldFile>,
+    _: Admin,
+) -> Result<HttpResponse> {
+    let path = world_file.path.clone().ok_or_else(|| actix_web::error::ErrorNotFound("World saves are disabled"))?;
+    // The slot is taken before saving so that concurrent requests do not
+    // all write, and given back if the save fails.
+    let previous = {
+        let mut last_saved = world_file.last_saved.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if let Some(last) = *last_saved
+            && now.duration_since(last) < WORLD_SAVE_INTERVAL
+        {
+            let remaining = WORLD_SAVE_INTERVAL - now.duration_since(last);
+            return Ok(HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, remaining.as_secs_f64().ceil() as u64))
+                .json(ErrorBody { error: "save_too_soon", message: "The world was saved moments ago".to_string() }));
+        }
+        last_saved.replace(now)
+    };
+    let saved = write_world(&data, path).await;
+    if saved.is_err() {
+        *world_file.last_saved.lock().unwrap_or_else(PoisonError::into_inner) = previous;
+    }
+    Ok(HttpResponse::Ok().json(saved?))
+}
+
+async fn write_world(data: &AppState, path: PathBuf) -> Result<serde_json::Value> {
+    let save = data.send(SaveWorld).await.map_err(unavailable)?;
+    let summary = serde_json::json!({ "saved_at": save.saved_at, "rooms": save.rooms.len() });
+    web::block(move || write_save(&path, &save)).await??;
+    Ok(summary)
+}
+
+impl actix_web::ResponseError for SaveError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorBody { error: "save_failed", message: self.to_string() })
     }
 }
 
@@ -69,8 +583,18 @@ pub async fn websocket(
     req: actix_web::HttpRequest,
     stream: web::Payload,
     data: web::Data<AppState>,
+    query: web::Query<RoomQuery>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
 ) -> Result<impl actix_web::Responder> {
-    let game_state = data.get_ref().clone();
+    let game = room_game(&data, query.into_inner().room).await?;
+    ws::start(GameWebSocket::new(game, player_id), &req, stream)
+}
 
-    ws::start(GameWebSocket { game_state, player_id: None }, &req, stream)
-}
\ No newline at end of file
+pub async fn lobby_websocket(
+    req: actix_web::HttpRequest,
+    stream: web::Payload,
+    lobby: web::Data<LobbyState>,
+    AuthenticatedPlayer(player_id): AuthenticatedPlayer,
+) -> Result<impl actix_web::Responder> {
+    ws::start(LobbySocket::new(lobby.get_ref().clone(), player_id), &req, stream)
+}